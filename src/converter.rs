@@ -7,28 +7,233 @@ mod kakinoki;
 mod ki2;
 mod kif;
 
-pub use self::csa::ToCsa;
-pub use self::ki2::ToKi2;
-pub use self::kif::ToKif;
+pub use self::csa::{CsaOptions, ToCsa};
+pub(crate) use self::kakinoki::write_initial;
+pub use self::ki2::{Ki2Options, ToKi2};
+pub(crate) use self::kif::write_move_line;
+pub use self::kif::{KifOptions, ToKif};
+use crate::error::ConvertError;
 use crate::jkf::JsonKifuFormat;
-use shogi_core::{PartialPosition, Position, ToUsi};
+use shogi_core::{Move, PartialPosition, Position, ToUsi};
+use std::fmt::Write;
 
+/// Converts the main line into USI format.
+///
+/// # Panics
+///
+/// [`ToUsi::to_usi_owned()`], which is provided by `shogi_core`, panics in debug builds
+/// if `self` cannot be converted to [`Position`], such as with an illegal move,
+/// and returns an empty string in release builds.
+/// Use [`JsonKifuFormat::try_to_usi()`] to handle the error.
 impl ToUsi for JsonKifuFormat {
-    fn to_usi<W: std::fmt::Write>(&self, sink: &mut W) -> std::fmt::Result {
-        let pos = Position::try_from(self).expect("failed to convert initial to position");
-        if pos.initial_position() == &PartialPosition::startpos() {
-            sink.write_str("startpos")?;
-        } else {
-            sink.write_str("sfen ")?;
-            pos.initial_position().to_sfen(sink)?;
+    /// Writes the position and the moves of the main line in USI format.
+    ///
+    /// This function returns Err(core::fmt::Error) if it fails to write to `sink`,
+    /// or if `self` cannot be converted to [`Position`]. Use [`JsonKifuFormat::try_to_usi()`] to get the reason.
+    fn to_usi<W: Write>(&self, sink: &mut W) -> std::fmt::Result {
+        let pos = Position::try_from(self).map_err(|_| std::fmt::Error)?;
+        write_usi(pos.initial_position(), pos.moves(), sink)
+    }
+}
+
+fn write_usi<W: Write>(
+    initial: &PartialPosition,
+    moves: &[Move],
+    sink: &mut W,
+) -> std::fmt::Result {
+    if initial == &PartialPosition::startpos() {
+        sink.write_str("startpos")?;
+    } else {
+        sink.write_str("sfen ")?;
+        initial.to_sfen(sink)?;
+    }
+    if !moves.is_empty() {
+        sink.write_str(" moves")?;
+        for mv in moves {
+            sink.write_str(" ")?;
+            mv.to_usi(sink)?;
+        }
+    }
+    Ok(())
+}
+
+impl JsonKifuFormat {
+    /// Returns the position and the moves of the main line in USI format, such as `startpos moves 7g7f 3c3d`
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`ConvertError`] if `self` cannot be converted to [`Position`].
+    pub fn try_to_usi(&self) -> Result<String, ConvertError> {
+        let pos = Position::try_from(self)?;
+        let mut s = String::new();
+        // Writing to `String` never fails
+        let _ = write_usi(pos.initial_position(), pos.moves(), &mut s);
+        Ok(s)
+    }
+
+    /// Returns an iterator over the USI `position` commands for every ply of the line, starting from the initial position.
+    ///
+    /// `forks` is the path to the variation, empty for the main line. See [`JsonKifuFormat::line()`].
+    /// The iterator ends after an item of the error if a move cannot be made, as [`Positions`](crate::jkf::Positions).
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`ConvertError::InvalidForkPath`] if the path doesn't exist.
+    pub fn usi_positions(
+        &self,
+        forks: &[(usize, usize)],
+    ) -> Result<impl Iterator<Item = Result<String, ConvertError>> + '_, ConvertError> {
+        let mut initial = None;
+        let mut moves = Vec::new();
+        Ok(self.line_positions_of_moves(forks)?.map(move |item| {
+            let pos = item?;
+            moves.extend(pos.last_move());
+            let initial = initial.get_or_insert(pos);
+            let mut s = String::from("position ");
+            // Writing to `String` never fails
+            let _ = write_usi(initial, &moves, &mut s);
+            Ok(s)
+        }))
+    }
+
+    /// Returns an iterator over the SFEN strings of the positions for every ply of the line, starting from the initial position.
+    ///
+    /// `forks` is the path to the variation, empty for the main line. See [`JsonKifuFormat::line()`].
+    /// The iterator ends after an item of the error if a move cannot be made, as [`Positions`](crate::jkf::Positions).
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`ConvertError::InvalidForkPath`] if the path doesn't exist.
+    pub fn sfen_positions(
+        &self,
+        forks: &[(usize, usize)],
+    ) -> Result<impl Iterator<Item = Result<String, ConvertError>> + '_, ConvertError> {
+        Ok(self
+            .line_positions_of_moves(forks)?
+            .map(|item| item.map(|pos| pos.to_sfen_owned())))
+    }
+
+    // The initial position and the positions after the moves of the line, without the special moves
+    fn line_positions_of_moves(
+        &self,
+        forks: &[(usize, usize)],
+    ) -> Result<impl Iterator<Item = Result<PartialPosition, ConvertError>> + '_, ConvertError>
+    {
+        Ok(self
+            .line_positions(forks)
+            .ok_or_else(|| ConvertError::InvalidForkPath(forks.to_vec()))?
+            .filter(|item| {
+                item.as_ref()
+                    .map_or(true, |(ply, mf, _)| *ply == 0 || mf.move_.is_some())
+            })
+            .map(|item| item.map(|(_, _, pos)| pos)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::LocatedNormalizeError;
+    use crate::jkf::MoveMoveFormat;
+    use std::fs::File;
+    use std::io::BufReader;
+
+    fn forks() -> JsonKifuFormat {
+        let file = File::open("data/tests/kif/forks.json").expect("failed to open file");
+        serde_json::from_reader(BufReader::new(file)).expect("failed to parse json")
+    }
+
+    #[test]
+    fn usi_positions() -> Result<(), ConvertError> {
+        let jkf = forks();
+        assert_eq!(jkf.to_usi_owned(), jkf.try_to_usi()?);
+
+        let positions = jkf.usi_positions(&[])?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(13, positions.len());
+        assert_eq!("position startpos", positions[0]);
+        assert_eq!("position startpos moves 7g7f 8c8d", positions[2]);
+
+        let positions = jkf
+            .usi_positions(&[(5, 0), (9, 0)])?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            "position startpos moves 7g7f 8c8d 7i6h 4a3b 8h7g 3c3d 3i4h 7a6b 1g1f",
+            positions[9]
+        );
+
+        let sfens = jkf
+            .sfen_positions(&[(5, 0)])?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(13, sfens.len());
+        assert_eq!(PartialPosition::startpos().to_sfen_owned(), sfens[0]);
+        assert!(sfens[12].ends_with(" b - 13"));
+        Ok(())
+    }
+
+    #[test]
+    fn usi_positions_errors() {
+        let mut jkf = forks();
+        assert!(matches!(
+            jkf.usi_positions(&[(3, 0)]),
+            Err(ConvertError::InvalidForkPath(_))
+        ));
+
+        // 3a4b -> 5e4b at the ply 12 of the variation
+        if let Some(MoveMoveFormat {
+            from: Some(from), ..
+        }) = &mut jkf.moves[10].forks.as_mut().unwrap()[0][2].move_
+        {
+            (from.x, from.y) = (5, 5);
         }
-        if !pos.moves().is_empty() {
-            sink.write_str(" moves")?;
-            for mv in pos.moves() {
-                sink.write_str(" ")?;
-                mv.to_usi(sink)?;
+        assert!(jkf
+            .usi_positions(&[])
+            .is_ok_and(|mut positions| positions.all(|item| item.is_ok())));
+        match jkf
+            .sfen_positions(&[(10, 0)])
+            .expect("line should exist")
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Err(ConvertError::Normalize(err)) => {
+                assert_eq!(12, err.ply);
+                assert_eq!(vec![(10, 0)], err.forks);
             }
+            result => panic!("unexpected result: {result:?}"),
         }
-        Ok(())
+        assert!(jkf.try_to_usi().is_ok());
+    }
+
+    #[test]
+    fn to_usi_illegal_move() {
+        let mut jkf = forks();
+        // 7g7f -> 5e7f
+        if let Some(MoveMoveFormat {
+            from: Some(from), ..
+        }) = &mut jkf.moves[1].move_
+        {
+            (from.x, from.y) = (5, 5);
+        }
+        assert_eq!(Err(std::fmt::Error), jkf.to_usi(&mut String::new()));
+        assert!(matches!(
+            jkf.try_to_usi(),
+            Err(ConvertError::Normalize(LocatedNormalizeError {
+                ply: 1,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn to_usi_owned_illegal_move() {
+        let mut jkf = forks();
+        // 7g7f -> 5e7f
+        if let Some(MoveMoveFormat {
+            from: Some(from), ..
+        }) = &mut jkf.moves[1].move_
+        {
+            (from.x, from.y) = (5, 5);
+        }
+        jkf.to_usi_owned();
     }
 }
@@ -1,34 +1,123 @@
 use crate::jkf::*;
-use std::collections::HashMap;
+use indexmap::IndexMap;
 use std::fmt::{Result, Write};
 
+/// Options for writing in CSA format.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CsaOptions {
+    /// Write each variation ([`MoveFormat::forks`]) as an additional game record.
+    ///
+    /// The records are separated by `/` lines, and each variation record starts with a `'変化：N手` comment line.
+    pub forks: bool,
+}
+
 /// A type that is convertible to CSA format.
 pub trait ToCsa {
     /// Write `self` in CSA format.
     ///
     /// This function returns Err(core::fmt::Error)
-    /// if and only if it fails to write to `sink`.
+    /// if it fails to write to `sink`, or if the initial position is
+    /// [`PresetOther`](crate::jkf::Preset::PresetOther) without the board data.
     fn to_csa<W: Write>(&self, sink: &mut W) -> Result;
 
+    /// Write `self` in CSA format with the given options.
+    ///
+    /// This function returns Err(core::fmt::Error) in the same cases as [`ToCsa::to_csa`].
+    ///
+    /// The default implementation ignores the options and calls [`ToCsa::to_csa`].
+    fn to_csa_with_options<W: Write>(&self, _options: &CsaOptions, sink: &mut W) -> Result {
+        self.to_csa(sink)
+    }
+
     /// Returns `self`'s string representation.
+    ///
+    /// Writing never fails for a normalized kifu, since [`JsonKifuFormat::normalize()`] rejects the initial position
+    /// which cannot be written. Otherwise the string written until the failure is returned.
+    /// Use [`ToCsa::try_to_csa_owned`] to detect the failure.
     fn to_csa_owned(&self) -> String {
+        self.to_csa_owned_with_options(&CsaOptions::default())
+    }
+
+    /// Returns `self`'s string representation with the given options.
+    ///
+    /// See [`ToCsa::to_csa_owned`] for the failure.
+    fn to_csa_owned_with_options(&self, options: &CsaOptions) -> String {
         let mut s = String::new();
-        // guaranteed to be Ok(())
-        let result = self.to_csa(&mut s);
-        debug_assert_eq!(result, Ok(()));
+        // The failure is ignored as documented
+        let _ = self.to_csa_with_options(options, &mut s);
         s
     }
+
+    /// Returns `self`'s string representation, or Err(core::fmt::Error) in the same cases as [`ToCsa::to_csa`].
+    fn try_to_csa_owned(&self) -> std::result::Result<String, std::fmt::Error> {
+        self.try_to_csa_owned_with_options(&CsaOptions::default())
+    }
+
+    /// Returns `self`'s string representation with the given options,
+    /// or Err(core::fmt::Error) in the same cases as [`ToCsa::to_csa`].
+    fn try_to_csa_owned_with_options(
+        &self,
+        options: &CsaOptions,
+    ) -> std::result::Result<String, std::fmt::Error> {
+        let mut s = String::new();
+        self.to_csa_with_options(options, &mut s)?;
+        Ok(s)
+    }
 }
 
 impl ToCsa for JsonKifuFormat {
     fn to_csa<W: Write>(&self, sink: &mut W) -> Result {
-        write_header(&self.header, sink)?;
-        write_initial(&self.initial, sink)?;
-        write_moves(&self.moves[1..], sink)?;
+        self.to_csa_with_options(&CsaOptions::default(), sink)
+    }
+
+    fn to_csa_with_options<W: Write>(&self, options: &CsaOptions, sink: &mut W) -> Result {
+        write_record(self, &[], &self.moves[1..], sink)?;
+        if options.forks {
+            write_variations(self, &mut Vec::new(), &self.moves[1..], 1, sink)?;
+        }
         Ok(())
     }
 }
 
+fn write_record<W: Write>(
+    jkf: &JsonKifuFormat,
+    prefix: &[&MoveFormat],
+    moves: &[MoveFormat],
+    sink: &mut W,
+) -> Result {
+    write_header(&jkf.header, sink)?;
+    write_initial(&jkf.initial, sink)?;
+    if let Some(mf) = jkf.moves.first() {
+        write_comments(mf, sink)?;
+    }
+    prefix.iter().try_for_each(|&mf| write_move(mf, sink))?;
+    moves.iter().try_for_each(|mf| write_move(mf, sink))?;
+    Ok(())
+}
+
+// Write the variations in depth-first order, so that every variation comes after the line it branches from
+fn write_variations<'a, W: Write>(
+    jkf: &JsonKifuFormat,
+    prefix: &mut Vec<&'a MoveFormat>,
+    moves: &'a [MoveFormat],
+    index: usize,
+    sink: &mut W,
+) -> Result {
+    for (i, mf) in (index..).zip(moves) {
+        if let Some(forks) = &mf.forks {
+            for fork in forks {
+                sink.write_str("/\n")?;
+                sink.write_fmt(format_args!("'変化：{}手\n", i))?;
+                write_record(jkf, prefix, fork, sink)?;
+                write_variations(jkf, prefix, fork, i, sink)?;
+            }
+        }
+        prefix.push(mf);
+    }
+    prefix.truncate(prefix.len() - moves.len());
+    Ok(())
+}
+
 fn write_color<W: Write>(c: Color, sink: &mut W) -> Result {
     match c {
         Color::Black => sink.write_char('+')?,
@@ -66,29 +155,10 @@ fn write_place<W: Write>(place: &Option<PlaceFormat>, sink: &mut W) -> Result {
     Ok(())
 }
 
-fn write_header<W: Write>(header: &HashMap<String, String>, sink: &mut W) -> Result {
-    sink.write_str("V2.2\n")?;
-    if let Some(s) = header.get("先手").or_else(|| header.get("下手")) {
-        if !s.is_empty() {
-            sink.write_fmt(format_args!("N+{}\n", s))?;
-        }
-    }
-    if let Some(s) = header.get("後手").or_else(|| header.get("上手")) {
-        if !s.is_empty() {
-            sink.write_fmt(format_args!("N-{}\n", s))?;
-        }
-    }
-    if let Some(s) = header.get("棋戦") {
-        sink.write_fmt(format_args!("$EVENT:{}\n", s))?;
-    }
-    if let Some(s) = header.get("場所") {
-        sink.write_fmt(format_args!("$SITE:{}\n", s))?;
-    }
-    // TODO: 開始日時
-    // TODO: 終了日時
-    // TODO: 持ち時間
-    if let Some(s) = header.get("戦型") {
-        sink.write_fmt(format_args!("$OPENING:{}\n", s))?;
+fn write_header<W: Write>(header: &IndexMap<String, String>, sink: &mut W) -> Result {
+    for line in GameInfo::from_header(header).to_csa_header() {
+        sink.write_str(&line)?;
+        sink.write_char('\n')?;
     }
     Ok(())
 }
@@ -133,17 +203,23 @@ fn write_initial_data<W: Write>(data: &StateFormat, sink: &mut W) -> Result {
 fn write_initial_preset<W: Write>(preset: Preset, sink: &mut W) -> Result {
     match preset {
         Preset::PresetHirate => sink.write_str("PI")?,
+        // `OTHER` without the board data cannot be written since the position is unknown
+        Preset::PresetOther => return Err(std::fmt::Error),
         Preset::PresetKY => sink.write_str("PI11KY")?,
         Preset::PresetKYR => sink.write_str("PI91KY")?,
         Preset::PresetKA => sink.write_str("PI22KA")?,
         Preset::PresetHI => sink.write_str("PI82HI")?,
         Preset::PresetHIKY => sink.write_str("PI82HI11KY")?,
         Preset::Preset2 => sink.write_str("PI82HI22KA")?,
+        Preset::Preset3 => sink.write_str("PI82HI22KA11KY")?,
         Preset::Preset4 => sink.write_str("PI82HI22KA91KY11KY")?,
+        Preset::Preset5 => sink.write_str("PI82HI22KA91KY11KY81KE")?,
+        Preset::Preset5L => sink.write_str("PI82HI22KA91KY11KY21KE")?,
         Preset::Preset6 => sink.write_str("PI82HI22KA91KY11KY81KE21KE")?,
+        Preset::Preset7L => sink.write_str("PI82HI22KA91KY11KY81KE21KE31GI")?,
+        Preset::Preset7R => sink.write_str("PI82HI22KA91KY11KY81KE21KE71GI")?,
         Preset::Preset8 => sink.write_str("PI82HI22KA91KY11KY81KE21KE71GI31GI")?,
         Preset::Preset10 => sink.write_str("PI82HI22KA91KY11KY81KE21KE71GI31GI61KI41KI")?,
-        _ => unimplemented!(),
     }
     sink.write_char('\n')?;
     if preset == Preset::PresetHirate {
@@ -168,50 +244,53 @@ fn write_initial<W: Write>(initial: &Option<Initial>, sink: &mut W) -> Result {
     Ok(())
 }
 
-fn write_moves<W: Write>(moves: &[MoveFormat], sink: &mut W) -> Result {
-    for mf in moves {
-        if let Some(mv) = mf.move_ {
-            write_color(mv.color, sink)?;
-            write_place(&mv.from, sink)?;
-            write_place(&Some(mv.to), sink)?;
-            let kind = if mv.promote.unwrap_or_default() {
-                mv.piece.promoted()
-            } else {
-                mv.piece
-            };
-            write_kind(kind, sink)?;
-        } else if let Some(special) = &mf.special {
-            sink.write_char('%')?;
-            match special {
-                MoveSpecial::SpecialToryo => sink.write_str("TORYO")?,
-                MoveSpecial::SpecialChudan => sink.write_str("CHUDAN")?,
-                MoveSpecial::SpecialSennichite => sink.write_str("SENNICHITE")?,
-                MoveSpecial::SpecialTimeUp => sink.write_str("TIME_UP")?,
-                MoveSpecial::SpecialIllegalMove => sink.write_str("ILLEGAL_MOVE")?,
-                MoveSpecial::SpecialIllegalActionBlack => sink.write_str("+ILLEGAL_ACTION")?,
-                MoveSpecial::SpecialIllegalActionWhite => sink.write_str("-ILLEGAL_ACTION")?,
-                MoveSpecial::SpecialJishogi => sink.write_str("JISHOGI")?,
-                MoveSpecial::SpecialKachi => sink.write_str("KACHI")?,
-                MoveSpecial::SpecialHikiwake => sink.write_str("HIKIWAKE")?,
-                MoveSpecial::SpecialMatta => sink.write_str("MATTA")?,
-                MoveSpecial::SpecialTsumi => sink.write_str("TSUMI")?,
-                MoveSpecial::SpecialFuzumi => sink.write_str("FUZUMI")?,
-                MoveSpecial::SpecialError => sink.write_str("ERROR")?,
-            }
+fn write_move<W: Write>(mf: &MoveFormat, sink: &mut W) -> Result {
+    if let Some(mv) = mf.move_ {
+        write_color(mv.color, sink)?;
+        write_place(&mv.from, sink)?;
+        write_place(&Some(mv.to), sink)?;
+        let kind = if mv.promote.unwrap_or_default() {
+            mv.piece.promoted()
         } else {
-            unreachable!()
+            mv.piece
+        };
+        write_kind(kind, sink)?;
+    } else if let Some(special) = &mf.special {
+        sink.write_char('%')?;
+        match special {
+            MoveSpecial::SpecialToryo => sink.write_str("TORYO")?,
+            MoveSpecial::SpecialChudan => sink.write_str("CHUDAN")?,
+            MoveSpecial::SpecialSennichite => sink.write_str("SENNICHITE")?,
+            MoveSpecial::SpecialTimeUp => sink.write_str("TIME_UP")?,
+            MoveSpecial::SpecialIllegalMove => sink.write_str("ILLEGAL_MOVE")?,
+            MoveSpecial::SpecialIllegalActionBlack => sink.write_str("+ILLEGAL_ACTION")?,
+            MoveSpecial::SpecialIllegalActionWhite => sink.write_str("-ILLEGAL_ACTION")?,
+            MoveSpecial::SpecialJishogi => sink.write_str("JISHOGI")?,
+            MoveSpecial::SpecialKachi => sink.write_str("KACHI")?,
+            MoveSpecial::SpecialHikiwake => sink.write_str("HIKIWAKE")?,
+            MoveSpecial::SpecialMatta => sink.write_str("MATTA")?,
+            MoveSpecial::SpecialTsumi => sink.write_str("TSUMI")?,
+            MoveSpecial::SpecialFuzumi => sink.write_str("FUZUMI")?,
+            MoveSpecial::SpecialError => sink.write_str("ERROR")?,
         }
-        sink.write_str("\n")?;
-        if let Some(time) = &mf.time {
-            let sec = time.now.h.unwrap_or_default() as u64 * 3600
-                + time.now.m as u64 * 60
-                + time.now.s as u64;
-            sink.write_fmt(format_args!("T{}\n", sec))?;
-        }
-        if let Some(comments) = &mf.comments {
-            for comment in comments {
-                sink.write_fmt(format_args!("'{}\n", comment))?;
-            }
+    } else {
+        unreachable!()
+    }
+    sink.write_str("\n")?;
+    if let Some(time) = &mf.time {
+        let sec = time.now.h.unwrap_or_default() as u64 * 3600
+            + time.now.m as u64 * 60
+            + time.now.s as u64;
+        sink.write_fmt(format_args!("T{}\n", sec))?;
+    }
+    write_comments(mf, sink)?;
+    Ok(())
+}
+
+fn write_comments<W: Write>(mf: &MoveFormat, sink: &mut W) -> Result {
+    if let Some(comments) = &mf.comments {
+        for comment in comments {
+            sink.write_fmt(format_args!("'{}\n", comment))?;
         }
     }
     Ok(())
@@ -232,4 +311,84 @@ PI
             JsonKifuFormat::default().to_csa_owned()
         );
     }
+
+    #[test]
+    fn to_csa_header() {
+        let jkf = JsonKifuFormat {
+            header: IndexMap::from(
+                [
+                    ("開始日時", "1999/07/15(木) 19:07:12"),
+                    ("持ち時間", "各８時間"),
+                    ("秒読み", "60秒"),
+                    ("先手持ち時間", "1時間30分（1手ごとに10秒加算）"),
+                    ("持将棋", "27点法"),
+                    ("最大手数", "256手"),
+                    ("備考", "foo"),
+                ]
+                .map(|(k, v)| (String::from(k), String::from(v))),
+            ),
+            ..Default::default()
+        };
+        assert_eq!(
+            r#"
+V3.0
+$START_TIME:1999/07/15 19:07:12
+$TIME_LIMIT:08:00+60
+$TIME+:5400+0+10
+$JISHOGI:27
+$MAX_MOVES:256
+$NOTE:foo
+PI
++
+"#[1..],
+            jkf.to_csa_owned()
+        );
+    }
+
+    #[test]
+    fn to_csa_header_comments() {
+        let mut jkf = JsonKifuFormat::default();
+        jkf.header.insert(
+            String::from("持ち時間"),
+            String::from("2時間（ストップウォッチ）"),
+        );
+        let csa = jkf.to_csa_owned();
+        assert_eq!("V2.2\n'持ち時間：2時間（ストップウォッチ）\nPI\n+\n", csa);
+        // The comment in the header is skipped by the parser
+        let parsed = crate::parser::parse_csa_str(&csa).expect("failed to parse csa");
+        assert!(parsed.header.is_empty());
+    }
+
+    #[test]
+    fn to_csa_forks() {
+        let jkf = crate::parser::parse_kif_file("data/tests/kif/short_forks.kif")
+            .expect("failed to parse kif");
+        assert_eq!(
+            r#"
+V2.2
+PI
++
++7776FU
+-3334FU
+"#[1..],
+            jkf.to_csa_owned()
+        );
+        assert_eq!(
+            r#"
+V2.2
+PI
++
++7776FU
+-3334FU
+/
+'変化：2手
+V2.2
+PI
++
++7776FU
+-8384FU
+"#[1..],
+            jkf.to_csa_owned_with_options(&CsaOptions { forks: true })
+        );
+    }
 }
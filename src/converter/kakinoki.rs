@@ -1,5 +1,5 @@
 use crate::jkf::*;
-use std::collections::HashMap;
+use indexmap::IndexMap;
 use std::fmt::{Result, Write};
 
 const SANYOU_SUJI: [char; 9] = ['１', '２', '３', '４', '５', '６', '７', '８', '９'];
@@ -104,23 +104,69 @@ fn write_initial_preset<W: Write>(preset: Preset, sink: &mut W) -> Result {
     sink.write_str("手合割：")?;
     match preset {
         Preset::PresetHirate => sink.write_str("平手")?,
+        // `OTHER` without the board data cannot be written since the position is unknown
+        Preset::PresetOther => return Err(std::fmt::Error),
         Preset::PresetKY => sink.write_str("香落ち")?,
         Preset::PresetKYR => sink.write_str("右香落ち")?,
         Preset::PresetKA => sink.write_str("角落ち")?,
         Preset::PresetHI => sink.write_str("飛車落ち")?,
         Preset::PresetHIKY => sink.write_str("飛香落ち")?,
         Preset::Preset2 => sink.write_str("二枚落ち")?,
+        Preset::Preset3 => sink.write_str("三枚落ち")?,
         Preset::Preset4 => sink.write_str("四枚落ち")?,
+        Preset::Preset5 => sink.write_str("五枚落ち")?,
+        Preset::Preset5L => sink.write_str("左五枚落ち")?,
         Preset::Preset6 => sink.write_str("六枚落ち")?,
+        Preset::Preset7L => sink.write_str("左七枚落ち")?,
+        Preset::Preset7R => sink.write_str("右七枚落ち")?,
         Preset::Preset8 => sink.write_str("八枚落ち")?,
         Preset::Preset10 => sink.write_str("十枚落ち")?,
-        _ => unimplemented!(),
     }
     sink.write_char('\n')?;
     Ok(())
 }
 
-pub(super) fn write_header<W: Write>(header: &HashMap<String, String>, sink: &mut W) -> Result {
+// The order of the header keys in the canonical ordering, which follows the files written by Kifu for Windows.
+// The preset (手合割) is written from `initial`, and the unknown keys follow the known ones in their original order.
+const CANONICAL_HEADER_ORDER: [&str; 25] = [
+    "開始日時",
+    "終了日時",
+    "対局日",
+    "棋戦",
+    "戦型",
+    "表題",
+    "場所",
+    "掲載",
+    "持ち時間",
+    "秒読み",
+    "先手持ち時間",
+    "先手秒読み",
+    "後手持ち時間",
+    "後手秒読み",
+    "消費時間",
+    "最大手数",
+    "持将棋",
+    "手合割",
+    "先手",
+    "下手",
+    "後手",
+    "上手",
+    "先手省略名",
+    "後手省略名",
+    "備考",
+];
+
+fn header_rank(key: &str) -> usize {
+    CANONICAL_HEADER_ORDER
+        .iter()
+        .position(|&k| k == key)
+        .unwrap_or(CANONICAL_HEADER_ORDER.len())
+}
+
+fn write_header<'a, W: Write>(
+    header: impl IntoIterator<Item = (&'a String, &'a String)>,
+    sink: &mut W,
+) -> Result {
     for (k, v) in header {
         sink.write_str(k)?;
         sink.write_char('：')?;
@@ -130,7 +176,32 @@ pub(super) fn write_header<W: Write>(header: &HashMap<String, String>, sink: &mu
     Ok(())
 }
 
-pub(super) fn write_initial<W: Write>(
+// Writes the header and the initial position.
+// With `canonical`, the header is sorted by `CANONICAL_HEADER_ORDER` and the preset is written in its place.
+// Otherwise, the header is written in the order of `header` and followed by the initial position.
+pub(super) fn write_header_and_initial<W: Write>(
+    header: &IndexMap<String, String>,
+    initial: &Option<Initial>,
+    omit_hirate: bool,
+    canonical: bool,
+    sink: &mut W,
+) -> Result {
+    if !canonical {
+        write_header(header, sink)?;
+        return write_initial(initial, omit_hirate, sink);
+    }
+    let mut entries = header.iter().collect::<Vec<_>>();
+    // Stable sort keeps the order of the unknown keys
+    entries.sort_by_key(|(k, _)| header_rank(k));
+    let preset_rank = header_rank("手合割");
+    let mid = entries.partition_point(|(k, _)| header_rank(k) < preset_rank);
+    write_header(entries[..mid].iter().copied(), sink)?;
+    write_initial(initial, omit_hirate, sink)?;
+    write_header(entries[mid..].iter().copied(), sink)?;
+    Ok(())
+}
+
+pub(crate) fn write_initial<W: Write>(
     initial: &Option<Initial>,
     omit_hirate: bool,
     sink: &mut W,
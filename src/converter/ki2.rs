@@ -1,23 +1,75 @@
-use super::kakinoki::{write_header, write_initial, write_kansuji, write_sanyou_suji};
+use super::kakinoki::{write_header_and_initial, write_kansuji, write_sanyou_suji};
 use crate::jkf::*;
+use crate::shogi_core::CheckReplay;
 use std::fmt::{Result, Write};
 
+/// Options for writing in KI2 format.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Ki2Options {
+    /// Write the header in the canonical order (開始日時, 終了日時, 棋戦, 場所, 持ち時間, 手合割, 先手, 後手, ...)
+    /// instead of the order of [`JsonKifuFormat::header`].
+    ///
+    /// The unknown keys are written after the known ones in their original order.
+    pub canonical_header_order: bool,
+    /// Write `王手` as a comment of each move which gives check,
+    /// and `まで{n}手で詰み` at the end of each line which ends with a checkmate without a special move.
+    ///
+    /// The annotations are not written if the initial position cannot be converted.
+    pub check_annotation: bool,
+}
+
 /// A type that is convertible to KI2 format.
 pub trait ToKi2 {
     /// Write `self` in KI2 format.
     ///
     /// This function returns Err(core::fmt::Error)
-    /// if and only if it fails to write to `sink`.
+    /// if it fails to write to `sink`, or if the initial position is
+    /// [`PresetOther`](crate::jkf::Preset::PresetOther) without the board data.
     fn to_ki2<W: Write>(&self, sink: &mut W) -> Result;
 
+    /// Write `self` in KI2 format with the given options.
+    ///
+    /// This function returns Err(core::fmt::Error) in the same cases as [`ToKi2::to_ki2`].
+    ///
+    /// The default implementation ignores the options and calls [`ToKi2::to_ki2`].
+    fn to_ki2_with_options<W: Write>(&self, _options: &Ki2Options, sink: &mut W) -> Result {
+        self.to_ki2(sink)
+    }
+
     /// Returns `self`'s string representation.
+    ///
+    /// Writing never fails for a normalized kifu, since [`JsonKifuFormat::normalize()`] rejects the initial position
+    /// which cannot be written. Otherwise the string written until the failure is returned.
+    /// Use [`ToKi2::try_to_ki2_owned`] to detect the failure.
     fn to_ki2_owned(&self) -> String {
+        self.to_ki2_owned_with_options(&Ki2Options::default())
+    }
+
+    /// Returns `self`'s string representation with the given options.
+    ///
+    /// See [`ToKi2::to_ki2_owned`] for the failure.
+    fn to_ki2_owned_with_options(&self, options: &Ki2Options) -> String {
         let mut s = String::new();
-        // guaranteed to be Ok(())
-        let result = self.to_ki2(&mut s);
-        debug_assert_eq!(result, Ok(()));
+        // The failure is ignored as documented
+        let _ = self.to_ki2_with_options(options, &mut s);
         s
     }
+
+    /// Returns `self`'s string representation, or Err(core::fmt::Error) in the same cases as [`ToKi2::to_ki2`].
+    fn try_to_ki2_owned(&self) -> std::result::Result<String, std::fmt::Error> {
+        self.try_to_ki2_owned_with_options(&Ki2Options::default())
+    }
+
+    /// Returns `self`'s string representation with the given options,
+    /// or Err(core::fmt::Error) in the same cases as [`ToKi2::to_ki2`].
+    fn try_to_ki2_owned_with_options(
+        &self,
+        options: &Ki2Options,
+    ) -> std::result::Result<String, std::fmt::Error> {
+        let mut s = String::new();
+        self.to_ki2_with_options(options, &mut s)?;
+        Ok(s)
+    }
 }
 
 fn write_move_kind<W: Write>(kind: Kind, sink: &mut W) -> Result {
@@ -39,18 +91,21 @@ fn write_move_kind<W: Write>(kind: Kind, sink: &mut W) -> Result {
     }
 }
 
-fn write_moves<W: Write>(moves: &[MoveFormat], sink: &mut W) -> Result {
-    if let Some(comments) = &moves[0].comments {
-        for comment in comments {
-            if !comment.starts_with('&') {
-                sink.write_char('*')?;
+// `replay` is at the position before the moves, which finds no checks if they are not annotated.
+fn write_move_lines<W: Write>(
+    moves: &[MoveFormat],
+    index: usize,
+    mut replay: CheckReplay,
+    sink: &mut W,
+) -> Result {
+    let mut forks_stack = Vec::new();
+    let mut it = (index..).zip(moves).peekable();
+    while let Some((i, mf)) = it.next() {
+        if let Some(ref forks) = mf.forks {
+            for fork in forks {
+                forks_stack.push((i, fork, replay.clone()));
             }
-            sink.write_str(comment)?;
-            sink.write_char('\n')?;
         }
-    }
-    let mut it = moves[1..].iter().peekable();
-    while let Some(mf) = it.next() {
         if let Some(mv) = &mf.move_ {
             match mv.color {
                 Color::Black => sink.write_char('▲')?,
@@ -88,9 +143,13 @@ fn write_moves<W: Write>(moves: &[MoveFormat], sink: &mut W) -> Result {
                 }
             }
         }
-        if let Some(comments) = &mf.comments {
+        let check = replay.make_move(mf) && !mf.comments.iter().flatten().any(|c| c == "王手");
+        if mf.comments.is_some() || check {
             sink.write_char('\n')?;
-            for comment in comments {
+            if check {
+                sink.write_str("*王手\n")?;
+            }
+            for comment in mf.comments.iter().flatten() {
                 if !comment.starts_with('&') {
                     sink.write_char('*')?;
                 }
@@ -101,15 +160,50 @@ fn write_moves<W: Write>(moves: &[MoveFormat], sink: &mut W) -> Result {
             sink.write_char(' ')?;
         }
     }
+    if !moves.is_empty() && replay.is_mate() {
+        sink.write_fmt(format_args!("まで{}手で詰み\n", index + moves.len() - 1))?;
+    }
     sink.write_char('\n')?;
+    while let Some((i, fork, replay)) = forks_stack.pop() {
+        sink.write_char('\n')?;
+        sink.write_fmt(format_args!("変化：{}手\n", i))?;
+        write_move_lines(fork, i, replay, sink)?;
+    }
     Ok(())
 }
 
+fn write_moves<W: Write>(moves: &[MoveFormat], replay: CheckReplay, sink: &mut W) -> Result {
+    if let Some(comments) = &moves[0].comments {
+        for comment in comments {
+            if !comment.starts_with('&') {
+                sink.write_char('*')?;
+            }
+            sink.write_str(comment)?;
+            sink.write_char('\n')?;
+        }
+    }
+    write_move_lines(&moves[1..], 1, replay, sink)
+}
+
 impl ToKi2 for JsonKifuFormat {
     fn to_ki2<W: Write>(&self, sink: &mut W) -> Result {
-        write_header(&self.header, sink)?;
-        write_initial(&self.initial, true, sink)?;
-        write_moves(&self.moves, sink)?;
+        self.to_ki2_with_options(&Ki2Options::default(), sink)
+    }
+
+    fn to_ki2_with_options<W: Write>(&self, options: &Ki2Options, sink: &mut W) -> Result {
+        write_header_and_initial(
+            &self.header,
+            &self.initial,
+            true,
+            options.canonical_header_order,
+            sink,
+        )?;
+        let replay = if options.check_annotation {
+            CheckReplay::new(self)
+        } else {
+            CheckReplay::default()
+        };
+        write_moves(&self.moves, replay, sink)?;
         Ok(())
     }
 }
@@ -175,4 +269,52 @@ mod tests {
             .to_ki2_owned()
         );
     }
+
+    #[test]
+    fn to_ki2_forks() {
+        let jkf = crate::parser::parse_kif_file("data/tests/kif/short_forks.kif")
+            .expect("failed to parse kif");
+        assert_eq!(
+            "▲７六歩 △３四歩\n\n変化：2手\n△８四歩\n",
+            jkf.to_ki2_owned()
+        );
+    }
+
+    #[test]
+    fn check_annotation() {
+        let mut jkf = crate::parser::parse_usi_str(
+            "position sfen 4k4/9/p3P4/9/9/9/9/9/4K4 b G 1 moves 5i4i 9c9d G*4b",
+        )
+        .expect("failed to parse usi");
+        let mut mate = jkf.moves[3].clone();
+        mate.move_.as_mut().unwrap().to = PlaceFormat { x: 5, y: 2 };
+        jkf.moves[3].forks = Some(vec![vec![mate]]);
+        jkf.normalize().expect("failed to normalize");
+        let options = Ki2Options {
+            check_annotation: true,
+            ..Default::default()
+        };
+        let ki2 = jkf.to_ki2_owned_with_options(&options);
+        assert!(
+            ki2.ends_with(
+                &r#"
+▲４九玉 △９四歩 ▲４二金
+*王手
+
+
+変化：3手
+▲５二金
+*王手
+まで3手で詰み
+
+"#[1..]
+            ),
+            "unexpected ki2: {ki2}"
+        );
+
+        // Parsed back with the comments, and not annotated twice
+        let parsed = crate::parser::parse_ki2_str(&ki2).expect("failed to parse ki2");
+        assert_eq!(Some(vec![String::from("王手")]), parsed.moves[3].comments);
+        assert_eq!(ki2, parsed.to_ki2_owned_with_options(&options));
+    }
 }
@@ -1,30 +1,96 @@
-use super::kakinoki::{write_header, write_initial, write_kansuji, write_sanyou_suji};
+use super::kakinoki::{write_header_and_initial, write_kansuji, write_sanyou_suji};
 use crate::jkf::*;
+use crate::shogi_core::CheckReplay;
 use std::fmt::{Result, Write};
 
+/// Options for writing in KIF format.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KifOptions {
+    /// Write the header in the canonical order (開始日時, 終了日時, 棋戦, 場所, 持ち時間, 手合割, 先手, 後手, ...)
+    /// instead of the order of [`JsonKifuFormat::header`].
+    ///
+    /// The unknown keys are written after the known ones in their original order.
+    pub canonical_header_order: bool,
+    /// Write `王手` as a comment of each move which gives check,
+    /// and `詰み` at the end of each line which ends with a checkmate without a special move.
+    ///
+    /// The annotations are not written if the initial position cannot be converted.
+    pub check_annotation: bool,
+}
+
 /// A type that is convertible to KIF format.
 pub trait ToKif {
     /// Write `self` in KIF format.
     ///
     /// This function returns Err(core::fmt::Error)
-    /// if and only if it fails to write to `sink`.
+    /// if it fails to write to `sink`, or if the initial position is
+    /// [`PresetOther`](crate::jkf::Preset::PresetOther) without the board data.
     fn to_kif<W: Write>(&self, sink: &mut W) -> Result;
 
+    /// Write `self` in KIF format with the given options.
+    ///
+    /// This function returns Err(core::fmt::Error) in the same cases as [`ToKif::to_kif`].
+    ///
+    /// The default implementation ignores the options and calls [`ToKif::to_kif`].
+    fn to_kif_with_options<W: Write>(&self, _options: &KifOptions, sink: &mut W) -> Result {
+        self.to_kif(sink)
+    }
+
     /// Returns `self`'s string representation.
+    ///
+    /// Writing never fails for a normalized kifu, since [`JsonKifuFormat::normalize()`] rejects the initial position
+    /// which cannot be written. Otherwise the string written until the failure is returned.
+    /// Use [`ToKif::try_to_kif_owned`] to detect the failure.
     fn to_kif_owned(&self) -> String {
+        self.to_kif_owned_with_options(&KifOptions::default())
+    }
+
+    /// Returns `self`'s string representation with the given options.
+    ///
+    /// See [`ToKif::to_kif_owned`] for the failure.
+    fn to_kif_owned_with_options(&self, options: &KifOptions) -> String {
         let mut s = String::new();
-        // guaranteed to be Ok(())
-        let result = self.to_kif(&mut s);
-        debug_assert_eq!(result, Ok(()));
+        // The failure is ignored as documented
+        let _ = self.to_kif_with_options(options, &mut s);
         s
     }
+
+    /// Returns `self`'s string representation, or Err(core::fmt::Error) in the same cases as [`ToKif::to_kif`].
+    fn try_to_kif_owned(&self) -> std::result::Result<String, std::fmt::Error> {
+        self.try_to_kif_owned_with_options(&KifOptions::default())
+    }
+
+    /// Returns `self`'s string representation with the given options,
+    /// or Err(core::fmt::Error) in the same cases as [`ToKif::to_kif`].
+    fn try_to_kif_owned_with_options(
+        &self,
+        options: &KifOptions,
+    ) -> std::result::Result<String, std::fmt::Error> {
+        let mut s = String::new();
+        self.to_kif_with_options(options, &mut s)?;
+        Ok(s)
+    }
 }
 
 impl ToKif for JsonKifuFormat {
     fn to_kif<W: Write>(&self, sink: &mut W) -> Result {
-        write_header(&self.header, sink)?;
-        write_initial(&self.initial, false, sink)?;
-        write_moves(&self.moves, sink)?;
+        self.to_kif_with_options(&KifOptions::default(), sink)
+    }
+
+    fn to_kif_with_options<W: Write>(&self, options: &KifOptions, sink: &mut W) -> Result {
+        write_header_and_initial(
+            &self.header,
+            &self.initial,
+            false,
+            options.canonical_header_order,
+            sink,
+        )?;
+        let replay = if options.check_annotation {
+            CheckReplay::new(self)
+        } else {
+            CheckReplay::default()
+        };
+        write_moves(&self.moves, self.initial_color(), replay, sink)?;
         Ok(())
     }
 }
@@ -59,11 +125,59 @@ fn write_move_kind<W: Write>(kind: Kind, sink: &mut W, offset: &mut usize) -> Re
     Ok(())
 }
 
-fn write_move_lines<W: Write>(moves: &[MoveFormat], index: usize, sink: &mut W) -> Result {
+// The color of the side to move at the ply `i`, where `first` is the color at the first move
+fn color(first: Color, i: usize) -> Color {
+    match (first, i % 2) {
+        (Color::Black, 1) | (Color::White, 0) => Color::Black,
+        _ => Color::White,
+    }
+}
+
+// The word of the special move in KIF, or `None` if no word is parsed back to the same special move.
+// `color` is the side to move.
+fn special_word(special: MoveSpecial, color: Color) -> Option<&'static str> {
+    match (special, color) {
+        (MoveSpecial::SpecialToryo, _) => Some("投了"),
+        (MoveSpecial::SpecialChudan, _) => Some("中断"),
+        (MoveSpecial::SpecialSennichite, _) => Some("千日手"),
+        (MoveSpecial::SpecialTimeUp, _) => Some("切れ負け"),
+        (MoveSpecial::SpecialIllegalMove, _) => Some("反則負け"),
+        // 反則勝ち is the win of the side to move by the illegal action of the opponent
+        (MoveSpecial::SpecialIllegalActionBlack, Color::White)
+        | (MoveSpecial::SpecialIllegalActionWhite, Color::Black) => Some("反則勝ち"),
+        (MoveSpecial::SpecialJishogi, _) => Some("持将棋"),
+        (MoveSpecial::SpecialKachi, _) => Some("入玉勝ち"),
+        (MoveSpecial::SpecialTsumi, _) => Some("詰み"),
+        (MoveSpecial::SpecialFuzumi, _) => Some("不詰"),
+        (MoveSpecial::SpecialIllegalActionBlack, Color::Black)
+        | (MoveSpecial::SpecialIllegalActionWhite, Color::White)
+        | (MoveSpecial::SpecialHikiwake, _)
+        | (MoveSpecial::SpecialMatta, _)
+        | (MoveSpecial::SpecialError, _) => None,
+    }
+}
+
+// `first` is the color of the side to move at the first move.
+// `replay` is at the position before the moves, which finds no checks if they are not annotated.
+fn write_move_lines<W: Write>(
+    moves: &[MoveFormat],
+    index: usize,
+    first: Color,
+    mut replay: CheckReplay,
+    sink: &mut W,
+) -> Result {
     let mut forks_stack = Vec::new();
     for (i, mf) in (index..).zip(moves) {
+        if let Some(ref forks) = mf.forks {
+            for fork in forks {
+                forks_stack.push((i, fork, replay.clone()));
+            }
+        }
         sink.write_fmt(format_args!("{:4} ", i))?;
         let mut offset = 0;
+        // The special move which cannot be represented by a word in KIF is written as 中断,
+        // and annotated with its name by a `&` line
+        let mut annotation = None;
         if let Some(mv) = &mf.move_ {
             if mv.same.is_some() {
                 sink.write_str("同　")?;
@@ -85,38 +199,12 @@ fn write_move_lines<W: Write>(moves: &[MoveFormat], index: usize, sink: &mut W)
                 offset += 2;
             }
         } else if let Some(special) = &mf.special {
-            match special {
-                MoveSpecial::SpecialToryo => {
-                    sink.write_str("投了")?;
-                    offset += 4;
-                }
-                MoveSpecial::SpecialSennichite => {
-                    sink.write_str("千日手")?;
-                    offset += 6;
-                }
-                MoveSpecial::SpecialTimeUp => {
-                    sink.write_str("切れ負け")?;
-                    offset += 8;
-                }
-                MoveSpecial::SpecialIllegalMove => {
-                    sink.write_str("反則負け")?;
-                    offset += 8;
-                }
-                MoveSpecial::SpecialJishogi => {
-                    sink.write_str("持将棋")?;
-                    offset += 6;
-                }
-                MoveSpecial::SpecialKachi => {
-                    sink.write_str("入玉勝ち")?;
-                    offset += 8;
-                }
-                MoveSpecial::SpecialTsumi => {
-                    sink.write_str("詰み")?;
-                    offset += 4;
-                }
-                // TODO: SpecialIllegalActionBlack, SpecialIllegalActionWhite, SpecialFuzumi, SpecialError, etc...
-                _ => sink.write_str("中断")?,
-            }
+            let word = special_word(*special, color(first, i)).unwrap_or_else(|| {
+                annotation = Some(special.name());
+                "中断"
+            });
+            sink.write_str(word)?;
+            offset += word.chars().count() * 2;
         } else {
             unreachable!()
         }
@@ -132,30 +220,54 @@ fn write_move_lines<W: Write>(moves: &[MoveFormat], index: usize, sink: &mut W)
             ))?;
         }
         sink.write_char('\n')?;
-        if let Some(comments) = &mf.comments {
-            for comment in comments {
-                if !comment.starts_with('&') {
-                    sink.write_char('*')?;
-                }
-                sink.write_str(comment)?;
-                sink.write_char('\n')?;
-            }
+        if let Some(name) = annotation {
+            sink.write_fmt(format_args!("&{name}\n"))?;
         }
-        if let Some(ref forks) = mf.forks {
-            for fork in forks {
-                forks_stack.push((i, fork));
+        let check = replay.make_move(mf);
+        let comments = mf.comments.iter().flatten();
+        if check && !comments.clone().any(|c| c == "王手") {
+            sink.write_str("*王手\n")?;
+        }
+        for comment in comments {
+            if !comment.starts_with('&') {
+                sink.write_char('*')?;
             }
+            sink.write_str(comment)?;
+            sink.write_char('\n')?;
         }
     }
-    while let Some((i, fork)) = forks_stack.pop() {
+    if !moves.is_empty() && replay.is_mate() {
+        sink.write_fmt(format_args!("{:4} 詰み\n", index + moves.len()))?;
+    }
+    while let Some((i, fork, replay)) = forks_stack.pop() {
         sink.write_char('\n')?;
         sink.write_fmt(format_args!("変化：{}手\n", i))?;
-        write_move_lines(fork, i, sink)?;
+        write_move_lines(fork, i, first, replay, sink)?;
     }
     Ok(())
 }
 
-fn write_moves<W: Write>(moves: &[MoveFormat], sink: &mut W) -> Result {
+// Writes the line of the single move at the ply, without its time, comments and variations.
+pub(crate) fn write_move_line<W: Write>(
+    mf: &MoveFormat,
+    ply: usize,
+    first: Color,
+    sink: &mut W,
+) -> Result {
+    let mf = MoveFormat {
+        move_: mf.move_,
+        special: mf.special,
+        ..Default::default()
+    };
+    write_move_lines(&[mf], ply, first, CheckReplay::default(), sink)
+}
+
+fn write_moves<W: Write>(
+    moves: &[MoveFormat],
+    first: Color,
+    replay: CheckReplay,
+    sink: &mut W,
+) -> Result {
     sink.write_str("手数----指手---------消費時間--\n")?;
     if let Some(comments) = &moves[0].comments {
         for comment in comments {
@@ -166,7 +278,7 @@ fn write_moves<W: Write>(moves: &[MoveFormat], sink: &mut W) -> Result {
             sink.write_char('\n')?;
         }
     }
-    write_move_lines(&moves[1..], 1, sink)
+    write_move_lines(&moves[1..], 1, first, replay, sink)
 }
 
 #[cfg(test)]
@@ -185,10 +297,66 @@ mod tests {
         );
     }
 
+    #[test]
+    fn header_order() {
+        let jkf = JsonKifuFormat {
+            header: IndexMap::from([
+                (String::from("後手"), String::from("後手の対局者名")),
+                (String::from("作者"), String::from("作者名")),
+                (String::from("先手"), String::from("先手の対局者名")),
+                (String::from("棋戦"), String::from("棋戦名")),
+                (
+                    String::from("開始日時"),
+                    String::from("1999/07/15(木) 19:07:12"),
+                ),
+            ]),
+            initial: Some(Initial {
+                preset: Preset::PresetHirate,
+                data: None,
+            }),
+            ..Default::default()
+        };
+        // The order of the header is kept
+        assert_eq!(
+            r#"
+後手：後手の対局者名
+作者：作者名
+先手：先手の対局者名
+棋戦：棋戦名
+開始日時：1999/07/15(木) 19:07:12
+手合割：平手
+手数----指手---------消費時間--
+"#[1..],
+            jkf.to_kif_owned()
+        );
+        assert_eq!(
+            r#"
+開始日時：1999/07/15(木) 19:07:12
+棋戦：棋戦名
+手合割：平手
+先手：先手の対局者名
+後手：後手の対局者名
+作者：作者名
+手数----指手---------消費時間--
+"#[1..],
+            jkf.to_kif_owned_with_options(&KifOptions {
+                canonical_header_order: true,
+                ..Default::default()
+            })
+        );
+        // Parsed in the order of the source
+        let parsed =
+            crate::parser::parse_kif_str(&jkf.to_kif_owned()).expect("failed to parse kif");
+        assert_eq!(
+            jkf.header.keys().collect::<Vec<_>>(),
+            parsed.header.keys().collect::<Vec<_>>()
+        );
+    }
+
     #[test]
     fn fork_moves() {
         let path = Path::new("data/tests/kif/forks.json");
-        let jkf = parse_jkf_file(&path).expect("failed to parse kif");
+        let jkf = parse_jkf_file(path).expect("failed to parse kif");
         let kif = jkf.to_kif_owned();
         assert_eq!(
             &r#"
@@ -235,4 +403,44 @@ mod tests {
             kif.lines().skip(3).collect::<Vec<_>>().join("\n") + "\n"
         );
     }
+
+    #[test]
+    fn check_annotation() {
+        let mut jkf = crate::parser::parse_usi_str(
+            "position sfen 4k4/9/p3P4/9/9/9/9/9/4K4 b G 1 moves 5i4i 9c9d G*4b",
+        )
+        .expect("failed to parse usi");
+        let mut mate = jkf.moves[3].clone();
+        mate.move_.as_mut().unwrap().to = PlaceFormat { x: 5, y: 2 };
+        jkf.moves[3].forks = Some(vec![vec![mate]]);
+        jkf.normalize().expect("failed to normalize");
+        let options = KifOptions {
+            check_annotation: true,
+            ..Default::default()
+        };
+        let kif = jkf.to_kif_owned_with_options(&options);
+        assert!(
+            kif.ends_with(
+                &r#"
+   1 ４九玉(59)
+   2 ９四歩(93)
+   3 ４二金打
+*王手
+
+変化：3手
+   3 ５二金打
+*王手
+   4 詰み
+"#[1..]
+            ),
+            "unexpected kif: {kif}"
+        );
+
+        // Parsed back with the comments and the special move, and not annotated twice
+        let parsed = crate::parser::parse_kif_str(&kif).expect("failed to parse kif");
+        assert_eq!(Some(vec![String::from("王手")]), parsed.moves[3].comments);
+        let fork = &parsed.moves[3].forks.as_ref().expect("no forks")[0];
+        assert_eq!(Some(MoveSpecial::SpecialTsumi), fork[1].special);
+        assert_eq!(kif, parsed.to_kif_owned_with_options(&options));
+    }
 }
@@ -16,7 +16,10 @@ pub enum ConvertError {
     InvalidHandPiece(shogi_core::PieceKind),
     /// An error that occurred while normalizing [`JsonKifuFormat`](crate::jkf::JsonKifuFormat)
     #[error("Failed to normalize: {0}")]
-    Normalize(String),
+    Normalize(LocatedNormalizeError),
+    /// The path to the variation doesn't exist. See [`JsonKifuFormat::line()`](crate::jkf::JsonKifuFormat::line)
+    #[error("Invalid path to the variation: {0:?}")]
+    InvalidForkPath(Vec<(usize, usize)>),
 }
 
 /// An error that can occur while normalizing [`JsonKifuFormat`](crate::jkf::JsonKifuFormat)
@@ -42,34 +45,185 @@ pub enum NormalizeError {
     InvalidColor,
 }
 
+/// A [`NormalizeError`] with the location of the move where it occurred
+#[derive(Error, Debug, PartialEq)]
+pub struct LocatedNormalizeError {
+    /// The original error
+    #[source]
+    pub error: NormalizeError,
+    /// The ply number of the move, or `0` if it occurred with the initial position
+    pub ply: usize,
+    /// The path to the variation which contains the move, empty if it is in the main line.
+    ///
+    /// Each element is a pair of the ply number where the variation branches
+    /// and the index of the variation in [`MoveFormat::forks`](crate::jkf::MoveFormat::forks) of the move.
+    pub forks: Vec<(usize, usize)>,
+}
+
+// Writes the error followed by the ply and the variations from the innermost one
+fn fmt_located(
+    f: &mut std::fmt::Formatter<'_>,
+    error: &dyn std::fmt::Display,
+    ply: usize,
+    forks: &[(usize, usize)],
+) -> std::fmt::Result {
+    write!(f, "{error} at move {ply}")?;
+    for (ply, index) in forks.iter().rev() {
+        write!(f, " in variation {} from move {}", index + 1, ply)?;
+    }
+    Ok(())
+}
+
+impl std::fmt::Display for LocatedNormalizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt_located(f, &self.error, self.ply, &self.forks)
+    }
+}
+
+impl NormalizeError {
+    pub(crate) fn at(self, ply: usize) -> LocatedNormalizeError {
+        LocatedNormalizeError {
+            error: self,
+            ply,
+            forks: Vec::new(),
+        }
+    }
+}
+
+/// A problem found by [`JsonKifuFormat::validate()`](crate::jkf::JsonKifuFormat::validate)
+#[derive(Error, Debug, PartialEq)]
+pub enum ValidationError {
+    /// The number of the pieces of the kind in the initial position is more than possible.
+    /// For [`Kind::OU`](crate::jkf::Kind::OU), it is the number of the kings of a player
+    #[error("Too many pieces of {0:?}: {1}")]
+    InvalidPieceCount(crate::jkf::Kind, usize),
+    /// The initial position cannot be converted into [`shogi_core::PartialPosition`]
+    #[error("Invalid initial position: {0}")]
+    InvalidInitial(ConvertError),
+    /// The color of the move is not the side to move
+    #[error("Wrong side to move: {0:?}")]
+    WrongSideToMove(crate::jkf::Color),
+    /// The move cannot be resolved in the position
+    #[error("Invalid move: {0}")]
+    InvalidMove(NormalizeError),
+    /// The move is not legal in the position
+    #[error("Illegal move {0:?}: {1:?}")]
+    IllegalMove(shogi_core::Move, shogi_core::IllegalMoveKind),
+    /// The move follows the special move which ends the game
+    #[error("Move after the special move {0:?}")]
+    MoveAfterSpecial(crate::jkf::MoveSpecial),
+    /// `KACHI` is declared in the position which doesn't satisfy the rule of [`ValidateOptions::declaration_rule`](crate::jkf::ValidateOptions::declaration_rule)
+    #[error("Invalid declaration: {} points with {} pieces in the zone", .0.points, .0.pieces_in_zone)]
+    InvalidDeclaration(crate::tree::Declaration),
+}
+
+/// A [`ValidationError`] with the location of the move where it was found
+#[derive(Error, Debug, PartialEq)]
+pub struct LocatedValidationError {
+    /// The problem
+    #[source]
+    pub error: ValidationError,
+    /// The ply number of the move, or `0` if it is of the initial position
+    pub ply: usize,
+    /// The path to the variation which contains the move, empty if it is in the main line.
+    /// See [`LocatedNormalizeError::forks`]
+    pub forks: Vec<(usize, usize)>,
+}
+
+impl std::fmt::Display for LocatedValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt_located(f, &self.error, self.ply, &self.forks)
+    }
+}
+
+/// The position and the expectation where parsing a string had failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    /// Byte offset from the beginning of the string
+    pub offset: usize,
+    /// Line number, starting from 1
+    pub line: usize,
+    /// Column number in characters, starting from 1
+    pub column: usize,
+    /// The line where the error occurred, without the line ending
+    pub line_text: String,
+    /// Labels of what was expected at the position (e.g. `"piece"`, `"square"`)
+    pub expected: Vec<String>,
+}
+
+impl std::fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, column {}: ", self.line, self.column)?;
+        if self.expected.is_empty() {
+            write!(f, "unexpected input")?;
+        } else {
+            write!(f, "expected {}", self.expected.join(" or "))?;
+        }
+        write!(f, ": {:?}", self.line_text)
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
 /// An error that can occur while parsing kifu data
 #[derive(Error, Debug)]
 pub enum ParseError {
     /// From [`std::io::Error`]
     #[error(transparent)]
     Io(#[from] std::io::Error),
-    /// From [`csa::CsaError`]
-    #[error(transparent)]
-    Csa(#[from] csa::CsaError),
     /// From [`serde_json::Error`]
     #[error(transparent)]
     Serde(#[from] serde_json::Error),
-    /// An error that occurred while converting from [`csa::GameRecord`]
-    #[error("Failed to convert from `csa::GameRecord`: {0}")]
-    CsaConvert(&'static str),
+    /// An error that occurred while parsing a CSA string
+    #[error("CSA Error: {0}")]
+    Csa(SyntaxError),
     /// An error that occurred while parsing a KIF string
     #[error("KIF Error: {0}")]
-    Kif(String),
+    Kif(SyntaxError),
     /// An error that occurred while parsing a KI2 string
     #[error("KI2 Error: {0}")]
-    Ki2(String),
+    Ki2(SyntaxError),
+    /// An error that occurred while parsing a USI `position` command or a SFEN string
+    #[error("USI Error: {0}")]
+    Usi(SyntaxError),
     /// Decoding the string had failed
     #[error("Decode Error")]
     Decode,
-    /// The file extension was unexpected
+    /// The format of the kifu data could not be detected
+    #[error("Unknown format")]
+    UnknownFormat,
+    /// The file extension was unexpected.
+    /// This is kept for compatibility, and no longer returned since the encoding is detected from the contents.
+    #[deprecated(note = "no longer returned since the encoding is detected from the contents")]
     #[error("File extension Error")]
     FileExtension,
     /// An error that occurred while normalizing [`JsonKifuFormat`](crate::jkf::JsonKifuFormat)
     #[error("Faield to normalize: {0}")]
-    Normalize(String),
+    Normalize(#[from] LocatedNormalizeError),
+}
+
+/// An error that can occur while editing the moves of [`JsonKifuFormat`](crate::jkf::JsonKifuFormat)
+#[derive(Error, Debug, PartialEq)]
+pub enum EditError {
+    /// The node doesn't exist in [`KifuTree`](crate::tree::KifuTree)
+    #[error("Node not found: {0:?}")]
+    NodeNotFound(crate::tree::NodeId),
+    /// The operation cannot be applied to the node
+    #[error("Invalid node for the operation: {0:?}")]
+    InvalidNode(crate::tree::NodeId),
+    /// The initial position of the kifu at the index is different from the first one
+    #[error("Different initial position: kifu {0}")]
+    InitialMismatch(usize),
+    /// No moves can follow a special move such as `TORYO`
+    #[error("No moves can follow the special move")]
+    AfterSpecialMove,
+    /// The move is not legal in the position
+    #[error("Illegal move {0:?}: {1:?}")]
+    IllegalMove(shogi_core::Move, shogi_core::IllegalMoveKind),
+    /// An error that occurred while converting the moves into [`shogi_core::Position`]
+    #[error("Failed to convert: {0}")]
+    Convert(#[from] ConvertError),
+    /// An error that occurred while normalizing the edited moves
+    #[error("Failed to normalize: {0}")]
+    Normalize(#[from] LocatedNormalizeError),
 }
@@ -2,9 +2,18 @@
 //!
 //! Reference: [https://apps.81.la/json-kifu-format/docs/modules/Formats.html](https://apps.81.la/json-kifu-format/docs/modules/Formats.html)
 
+mod game_info;
+mod game_result;
+mod positions;
+
+pub use self::game_info::{DateTime, GameInfo, TimeControl};
+pub use self::game_result::{GameResult, Outcome, ResultReason};
+pub use self::positions::Positions;
+pub use crate::normalizer::NormalizeOptions;
+pub use crate::validator::ValidateOptions;
+pub use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
-use std::collections::HashMap;
 
 /// A representation of a side-to-move
 #[derive(Serialize_repr, Deserialize_repr, Clone, Copy, Debug, PartialEq, Eq)]
@@ -183,11 +192,54 @@ pub enum MoveSpecial {
     SpecialError,
 }
 
+impl MoveSpecial {
+    // The name in JKF, such as `TORYO`, which is the same as the serialized one
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            MoveSpecial::SpecialToryo => "TORYO",
+            MoveSpecial::SpecialChudan => "CHUDAN",
+            MoveSpecial::SpecialSennichite => "SENNICHITE",
+            MoveSpecial::SpecialTimeUp => "TIME_UP",
+            MoveSpecial::SpecialIllegalMove => "ILLEGAL_MOVE",
+            MoveSpecial::SpecialIllegalActionBlack => "+ILLEGAL_ACTION",
+            MoveSpecial::SpecialIllegalActionWhite => "-ILLEGAL_ACTION",
+            MoveSpecial::SpecialJishogi => "JISHOGI",
+            MoveSpecial::SpecialKachi => "KACHI",
+            MoveSpecial::SpecialHikiwake => "HIKIWAKE",
+            MoveSpecial::SpecialMatta => "MATTA",
+            MoveSpecial::SpecialTsumi => "TSUMI",
+            MoveSpecial::SpecialFuzumi => "FUZUMI",
+            MoveSpecial::SpecialError => "ERROR",
+        }
+    }
+
+    // The special move of the name in JKF, the inverse of `name`
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "TORYO" => Some(MoveSpecial::SpecialToryo),
+            "CHUDAN" => Some(MoveSpecial::SpecialChudan),
+            "SENNICHITE" => Some(MoveSpecial::SpecialSennichite),
+            "TIME_UP" => Some(MoveSpecial::SpecialTimeUp),
+            "ILLEGAL_MOVE" => Some(MoveSpecial::SpecialIllegalMove),
+            "+ILLEGAL_ACTION" => Some(MoveSpecial::SpecialIllegalActionBlack),
+            "-ILLEGAL_ACTION" => Some(MoveSpecial::SpecialIllegalActionWhite),
+            "JISHOGI" => Some(MoveSpecial::SpecialJishogi),
+            "KACHI" => Some(MoveSpecial::SpecialKachi),
+            "HIKIWAKE" => Some(MoveSpecial::SpecialHikiwake),
+            "MATTA" => Some(MoveSpecial::SpecialMatta),
+            "TSUMI" => Some(MoveSpecial::SpecialTsumi),
+            "FUZUMI" => Some(MoveSpecial::SpecialFuzumi),
+            "ERROR" => Some(MoveSpecial::SpecialError),
+            _ => None,
+        }
+    }
+}
+
 /// The type translated from [`IJSONKifuFormat`](https://apps.81.la/json-kifu-format/docs/interfaces/Formats.IJSONKifuFormat.html)
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct JsonKifuFormat {
     /// 対局情報
-    pub header: HashMap<String, String>,
+    pub header: IndexMap<String, String>,
     /// 開始局面
     pub initial: Option<Initial>,
     /// 指し手
@@ -197,13 +249,52 @@ pub struct JsonKifuFormat {
 impl Default for JsonKifuFormat {
     fn default() -> Self {
         JsonKifuFormat {
-            header: HashMap::new(),
+            header: IndexMap::new(),
             initial: None,
             moves: vec![MoveFormat::default()],
         }
     }
 }
 
+impl JsonKifuFormat {
+    /// Returns the moves of the line from the initial position, indexed by the ply numbers.
+    ///
+    /// `forks` is the path to the variation, and each element is a pair of the ply number where the variation branches
+    /// and the index of the variation in [`MoveFormat::forks`] of the move. It is empty for the main line.
+    ///
+    /// Returns `None` if the path doesn't exist.
+    pub fn line(&self, forks: &[(usize, usize)]) -> Option<Vec<&MoveFormat>> {
+        let mut line = Vec::new();
+        let (mut moves, mut start) = (&self.moves[..], 0);
+        for &(ply, index) in forks {
+            if ply == 0 || ply < start {
+                return None;
+            }
+            line.extend(moves.get(..ply - start)?);
+            moves = moves.get(ply - start)?.forks.as_ref()?.get(index)?;
+            start = ply;
+        }
+        line.extend(moves);
+        Some(line)
+    }
+
+    // The color of the side to move at the first move.
+    // The handicapped side (上手) moves first with the handicap presets.
+    pub(crate) fn initial_color(&self) -> Color {
+        match &self.initial {
+            Some(Initial {
+                data: Some(data), ..
+            }) => data.color,
+            Some(Initial {
+                preset: Preset::PresetHirate | Preset::PresetOther,
+                ..
+            })
+            | None => Color::Black,
+            Some(_) => Color::White,
+        }
+    }
+}
+
 /// The Initial state for [`JsonKifuFormat`]
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Initial {
@@ -333,6 +424,47 @@ pub struct TimeFormat {
     pub s: u8,
 }
 
+/// An evaluation by a shogi engine, written in a comment such as `** 123 -3334FU +2625FU` by floodgate
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Evaluation {
+    /// 評価値
+    pub score: i32,
+    /// 読み筋 (in CSA format such as `-3334FU`)
+    pub pv: Vec<String>,
+}
+
+impl Evaluation {
+    /// Parses a comment (without the leading `'`) as an evaluation, or returns `None` if it is not.
+    pub fn parse(comment: &str) -> Option<Self> {
+        let mut words = comment.strip_prefix("**")?.split_whitespace();
+        let score = words.next()?.parse().ok()?;
+        Some(Self {
+            score,
+            pv: words.map(String::from).collect(),
+        })
+    }
+}
+
+impl std::fmt::Display for Evaluation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "** {}", self.score)?;
+        for mv in &self.pv {
+            write!(f, " {}", mv)?;
+        }
+        Ok(())
+    }
+}
+
+impl MoveFormat {
+    /// Returns the first [`Evaluation`] in [`MoveFormat::comments`]
+    pub fn evaluation(&self) -> Option<Evaluation> {
+        self.comments
+            .as_ref()?
+            .iter()
+            .find_map(|comment| Evaluation::parse(comment))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -367,6 +499,53 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn line() {
+        let mf = |comment: &str, forks| MoveFormat {
+            comments: Some(vec![String::from(comment)]),
+            forks,
+            ..Default::default()
+        };
+        let jkf = JsonKifuFormat {
+            moves: vec![
+                mf("0", None),
+                mf("1", None),
+                mf(
+                    "2",
+                    Some(vec![vec![
+                        mf("2-a", Some(vec![vec![mf("2-b", None)]])),
+                        mf("3-a", None),
+                    ]]),
+                ),
+                mf("3", None),
+            ],
+            ..Default::default()
+        };
+        let comments = |forks: &[(usize, usize)]| {
+            jkf.line(forks).map(|line| {
+                line.iter()
+                    .map(|mf| mf.comments.as_ref().unwrap()[0].as_str())
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(Some(vec!["0", "1", "2", "3"]), comments(&[]));
+        assert_eq!(Some(vec!["0", "1", "2-a", "3-a"]), comments(&[(2, 0)]));
+        assert_eq!(Some(vec!["0", "1", "2-b"]), comments(&[(2, 0), (2, 0)]));
+        assert_eq!(None, comments(&[(2, 1)]));
+        assert_eq!(None, comments(&[(3, 0)]));
+        assert_eq!(None, comments(&[(2, 0), (1, 0)]));
+    }
+
+    #[test]
+    fn evaluation() {
+        let eval = Evaluation::parse("** -2 -7162GI +3736FU").expect("should be an evaluation");
+        assert_eq!(-2, eval.score);
+        assert_eq!(vec!["-7162GI", "+3736FU"], eval.pv);
+        assert_eq!("** -2 -7162GI +3736FU", eval.to_string());
+        assert_eq!(None, Evaluation::parse("* 10 -7162GI"));
+        assert_eq!(None, Evaluation::parse("**foo"));
+    }
+
     #[test]
     fn deserialize() -> Result<()> {
         visit_dirs(Path::new("data/tests"), &|entry: &DirEntry| -> Result<()> {
@@ -388,10 +567,10 @@ mod tests {
     fn validate_default() -> Result<()> {
         let schema = load_schema()?;
 
-        let value = serde_json::to_value(&JsonKifuFormat::default()).expect("failed to serialize");
+        let value = serde_json::to_value(JsonKifuFormat::default()).expect("failed to serialize");
         let result = schema.validate(&value);
-        if let Err(errors) = result {
-            for err in errors {
+        if let Err(mut errors) = result {
+            if let Some(err) = errors.next() {
                 panic!("{:?}", err);
             }
         }
@@ -418,12 +597,39 @@ mod tests {
             };
             let value = serde_json::to_value(&jkf).expect("failed to serialize");
             let result = schema.validate(&value);
-            if let Err(errors) = result {
-                for err in errors {
+            if let Err(mut errors) = result {
+                if let Some(err) = errors.next() {
                     panic!("error on {}: {:?}", path.display(), err);
                 }
             }
             Ok(())
         })
     }
+
+    #[test]
+    fn move_special_names() {
+        for special in [
+            MoveSpecial::SpecialToryo,
+            MoveSpecial::SpecialChudan,
+            MoveSpecial::SpecialSennichite,
+            MoveSpecial::SpecialTimeUp,
+            MoveSpecial::SpecialIllegalMove,
+            MoveSpecial::SpecialIllegalActionBlack,
+            MoveSpecial::SpecialIllegalActionWhite,
+            MoveSpecial::SpecialJishogi,
+            MoveSpecial::SpecialKachi,
+            MoveSpecial::SpecialHikiwake,
+            MoveSpecial::SpecialMatta,
+            MoveSpecial::SpecialTsumi,
+            MoveSpecial::SpecialFuzumi,
+            MoveSpecial::SpecialError,
+        ] {
+            assert_eq!(
+                serde_json::Value::from(special.name()),
+                serde_json::to_value(special).expect("failed to serialize")
+            );
+            assert_eq!(Some(special), MoveSpecial::from_name(special.name()));
+        }
+        assert_eq!(None, MoveSpecial::from_name("FOO"));
+    }
 }
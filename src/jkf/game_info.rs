@@ -0,0 +1,829 @@
+use super::{IndexMap, JsonKifuFormat};
+use std::fmt;
+
+/// A date and time in the header, such as `開始日時：1999/07/15(木) 19:07:12`
+///
+/// The day of the week is not kept, since it is decided by the date.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    /// Year
+    pub year: u16,
+    /// Month, from 1 to 12
+    pub month: u8,
+    /// Day, from 1 to 31
+    pub day: u8,
+    /// Hour, from 0 to 23. `None` if the time is not recorded.
+    pub hour: Option<u8>,
+    /// Minute, from 0 to 59. `None` if the time is not recorded.
+    pub minute: Option<u8>,
+    /// Second, from 0 to 59. `None` if the time is not recorded to seconds.
+    pub second: Option<u8>,
+}
+
+impl DateTime {
+    /// Parses a date and time in KIF or CSA, such as `1999/07/15(木) 19:07:12`, `2022/05/07 10:00` or `2022/05/07`
+    ///
+    /// Returns `None` if the string is not formatted as expected.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = strip_weekday(s.trim());
+        let (date, time) = s.split_once(' ').unwrap_or((&s, ""));
+        let mut date = date.split('/').map(|s| s.parse::<u16>().ok());
+        let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+        if date.next().is_some()
+            || !(1..=12).contains(&month)
+            || !(1..=days_in_month(year, month)).contains(&day)
+        {
+            return None;
+        }
+        let mut datetime = DateTime {
+            year,
+            month: month as u8,
+            day: day as u8,
+            hour: None,
+            minute: None,
+            second: None,
+        };
+        let time = time.trim();
+        if !time.is_empty() {
+            let mut time = time.split(':').map(|s| s.parse::<u8>().ok());
+            let (hour, minute) = (time.next()??, time.next()??);
+            let second = time.next().map_or(Some(None), |s| s.map(Some))?;
+            if time.next().is_some() || hour > 23 || minute > 59 || second.is_some_and(|s| s > 59) {
+                return None;
+            }
+            (datetime.hour, datetime.minute, datetime.second) = (Some(hour), Some(minute), second);
+        }
+        Some(datetime)
+    }
+}
+
+// The number of the days in the month of the Gregorian calendar
+fn days_in_month(year: u16, month: u16) -> u16 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => {
+            29
+        }
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl fmt::Display for DateTime {
+    /// Formats as `YYYY/MM/DD HH:MM:SS`, which is used in both KIF and CSA
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}/{:02}/{:02}", self.year, self.month, self.day)?;
+        if let (Some(hour), Some(minute)) = (self.hour, self.minute) {
+            write!(f, " {hour:02}:{minute:02}")?;
+            if let Some(second) = self.second {
+                write!(f, ":{second:02}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Removes the day of the week from the date time in KIF, such as `1999/07/15(木) 19:07:12`
+fn strip_weekday(s: &str) -> String {
+    for (open, close) in [('(', ')'), ('（', '）')] {
+        if let Some((date, rest)) = s.split_once(open) {
+            if let Some((_, time)) = rest.split_once(close) {
+                return format!("{}{}", date.trim_end(), time);
+            }
+        }
+    }
+    String::from(s)
+}
+
+/// A time control, in seconds
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TimeControl {
+    /// The main time (持ち時間)
+    pub total: u64,
+    /// The time for each move after the main time is used up (秒読み)
+    pub byoyomi: u64,
+    /// The time added for each move (フィッシャールール)
+    pub increment: u64,
+}
+
+impl TimeControl {
+    // Parses the headers in KIF such as `持ち時間：各25分（1手ごとに10秒加算）` and `秒読み：30秒`
+    pub(crate) fn from_kif(main: &str, byoyomi: Option<&str>) -> Option<Self> {
+        let (total, rest) = duration(main.trim().trim_start_matches('各'))?;
+        let increment = match rest.trim() {
+            "" => 0,
+            rest => rest
+                .split_once("ごとに")
+                .and_then(|(_, s)| duration(s))
+                .map(|(secs, _)| secs)?,
+        };
+        let byoyomi = match byoyomi {
+            Some(s) => duration(s.trim().trim_start_matches('各'))
+                .filter(|(_, rest)| rest.trim().is_empty())
+                .map(|(secs, _)| secs)?,
+            None => 0,
+        };
+        Some(TimeControl {
+            total,
+            byoyomi,
+            increment,
+        })
+    }
+
+    // The values of the headers in KIF for the main time and byoyomi, such as `各25分` and `30秒`.
+    // The main time is prefixed with `各` if `each` is `true`, which means the time control is for both players.
+    pub(crate) fn to_kif(self, each: bool) -> (String, Option<String>) {
+        let mut main = format!(
+            "{}{}",
+            if each { "各" } else { "" },
+            kif_duration(self.total)
+        );
+        if self.increment > 0 {
+            main += &format!("（1手ごとに{}加算）", kif_duration(self.increment));
+        }
+        (
+            main,
+            (self.byoyomi > 0).then(|| format!("{}秒", self.byoyomi)),
+        )
+    }
+
+    // Parses `HH:MM+SS` of `$TIME_LIMIT` in CSA V2.2
+    pub(crate) fn from_csa_time_limit(value: &str) -> Option<Self> {
+        let (main, byoyomi) = value.split_once('+')?;
+        let (h, m) = main.split_once(':')?;
+        let secs = |s: &str| s.trim().parse::<u64>().ok();
+        Some(TimeControl {
+            total: secs(h)?
+                .checked_mul(3600)?
+                .checked_add(secs(m)?.checked_mul(60)?)?,
+            byoyomi: secs(byoyomi)?,
+            increment: 0,
+        })
+    }
+
+    // Parses `TOTAL+BYOYOMI+INCREMENT` in seconds of `$TIME` in CSA V3.0. Fractions of a second are truncated.
+    pub(crate) fn from_csa_time(value: &str) -> Option<Self> {
+        let mut secs = value.split('+').map(|s| {
+            let s = s.trim();
+            s.split_once('.')
+                .map_or(s, |(int, _)| int)
+                .parse::<u64>()
+                .ok()
+        });
+        let total = secs.next()??;
+        let byoyomi = secs.next().unwrap_or(Some(0))?;
+        let increment = secs.next().unwrap_or(Some(0))?;
+        secs.next().is_none().then_some(TimeControl {
+            total,
+            byoyomi,
+            increment,
+        })
+    }
+
+    // The value of `$TIME` in CSA V3.0
+    fn to_csa_time(self) -> String {
+        format!("{}+{}+{}", self.total, self.byoyomi, self.increment)
+    }
+}
+
+// Formats the seconds as a duration in KIF, such as `1時間30分`
+fn kif_duration(secs: u64) -> String {
+    if secs == 0 {
+        return String::from("0秒");
+    }
+    let mut s = String::new();
+    for (n, unit) in [
+        (secs / 3600, "時間"),
+        (secs / 60 % 60, "分"),
+        (secs % 60, "秒"),
+    ] {
+        if n > 0 {
+            s += &format!("{n}{unit}");
+        }
+    }
+    s
+}
+
+// Parses the leading number, which may be written in full-width digits, and returns it with the rest.
+// Returns `None` if the number overflows.
+fn leading_number(s: &str) -> Option<(u64, &str)> {
+    let mut n = None;
+    for (i, c) in s.char_indices() {
+        let d = match c {
+            '0'..='9' => c as u64 - '0' as u64,
+            '０'..='９' => c as u64 - '０' as u64,
+            _ => return n.map(|n| (n, &s[i..])),
+        };
+        n = Some(n.unwrap_or(0).checked_mul(10)?.checked_add(d)?);
+    }
+    n.map(|n| (n, ""))
+}
+
+// Parses the leading duration in KIF, such as `1時間30分`, in seconds
+fn duration(s: &str) -> Option<(u64, &str)> {
+    let mut secs: Option<u64> = None;
+    let mut rest = s;
+    while let Some((n, r)) = leading_number(rest) {
+        let (unit, r) = [("時間", 3600), ("分", 60), ("秒", 1)]
+            .into_iter()
+            .find_map(|(unit, scale)| r.strip_prefix(unit).map(|r| (scale, r)))?;
+        secs = Some(secs.unwrap_or(0).checked_add(n.checked_mul(unit)?)?);
+        rest = r;
+    }
+    secs.map(|secs| (secs, rest))
+}
+
+// Parses a number with the suffix, such as `256手`
+fn number_with_suffix(s: &str, suffix: &str) -> Option<u32> {
+    let (n, rest) = leading_number(s.trim())?;
+    (rest == suffix).then_some(u32::try_from(n).ok()?)
+}
+
+/// Typed view of the game information in [`JsonKifuFormat::header`]
+///
+/// The known keys of the header are parsed into the fields,
+/// and the other keys and the values which cannot be parsed are kept in [`GameInfo::others`] as they are.
+///
+/// [`GameInfo::update_header`] (and [`JsonKifuFormat::set_game_info`]) writes back only the changed fields,
+/// so the conversion from and to the header is lossless:
+/// the notations and the order of the unchanged values, such as `1500.50` or `90分`, are kept.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GameInfo {
+    /// 先手 (or 下手)
+    pub black: Option<String>,
+    /// 後手 (or 上手)
+    pub white: Option<String>,
+    /// `true` if the players are named 下手 and 上手 instead of 先手 and 後手, as in handicap games
+    pub handicap_names: bool,
+    /// 棋戦
+    pub event: Option<String>,
+    /// 場所
+    pub site: Option<String>,
+    /// 開始日時
+    pub start_time: Option<DateTime>,
+    /// 終了日時
+    pub end_time: Option<DateTime>,
+    /// 持ち時間 and 秒読み for both players
+    pub time_control: Option<TimeControl>,
+    /// 先手持ち時間 and 先手秒読み
+    pub black_time_control: Option<TimeControl>,
+    /// 後手持ち時間 and 後手秒読み
+    pub white_time_control: Option<TimeControl>,
+    /// 戦型
+    pub opening: Option<String>,
+    /// 結果
+    pub result: Option<String>,
+    /// 先手レーティング
+    pub black_rating: Option<f64>,
+    /// 後手レーティング
+    pub white_rating: Option<f64>,
+    /// 最大手数
+    pub max_moves: Option<u32>,
+    /// 持将棋, the points to win by the impasse rule
+    pub jishogi: Option<u32>,
+    /// 備考
+    pub note: Option<String>,
+    /// The other headers, and the headers of the known keys whose values cannot be parsed
+    pub others: IndexMap<String, String>,
+}
+
+impl GameInfo {
+    /// Parses the header of [`JsonKifuFormat`]
+    pub fn from_header(header: &IndexMap<String, String>) -> Self {
+        let mut info = GameInfo::default();
+        // The time controls consist of two keys
+        let mut consumed = Vec::new();
+        for (main_key, byoyomi_key, field) in [
+            ("持ち時間", "秒読み", &mut info.time_control),
+            ("先手持ち時間", "先手秒読み", &mut info.black_time_control),
+            ("後手持ち時間", "後手秒読み", &mut info.white_time_control),
+        ] {
+            let Some(main) = header.get(main_key) else {
+                continue;
+            };
+            let byoyomi = header.get(byoyomi_key).map(String::as_str);
+            if let Some(tc) = TimeControl::from_kif(main, byoyomi) {
+                *field = Some(tc);
+                consumed.extend([main_key, byoyomi_key]);
+            }
+        }
+        for (key, value) in header {
+            if consumed.contains(&key.as_str()) {
+                continue;
+            }
+            let text = || Some(value.clone());
+            let rating = || value.trim().parse::<f64>().ok().filter(|r| r.is_finite());
+            let parsed = match key.as_str() {
+                "先手" => set(&mut info.black, text()),
+                "後手" => set(&mut info.white, text()),
+                "下手" if !header.contains_key("先手") => {
+                    info.handicap_names = true;
+                    set(&mut info.black, text())
+                }
+                "上手" if !header.contains_key("後手") => {
+                    info.handicap_names = true;
+                    set(&mut info.white, text())
+                }
+                "棋戦" => set(&mut info.event, text()),
+                "場所" => set(&mut info.site, text()),
+                "開始日時" => set(&mut info.start_time, DateTime::parse(value)),
+                "終了日時" => set(&mut info.end_time, DateTime::parse(value)),
+                "戦型" => set(&mut info.opening, text()),
+                "結果" => set(&mut info.result, text()),
+                "先手レーティング" => set(&mut info.black_rating, rating()),
+                "後手レーティング" => set(&mut info.white_rating, rating()),
+                "最大手数" => set(&mut info.max_moves, number_with_suffix(value, "手")),
+                "持将棋" => set(&mut info.jishogi, number_with_suffix(value, "点法")),
+                "備考" => set(&mut info.note, text()),
+                _ => false,
+            };
+            if !parsed {
+                info.others.insert(key.clone(), value.clone());
+            }
+        }
+        info
+    }
+
+    /// Converts to a new header of [`JsonKifuFormat`]
+    ///
+    /// The known keys are written in the canonical order and format, and followed by [`GameInfo::others`].
+    /// Use [`GameInfo::update_header`] to keep the original values of an existing header.
+    pub fn to_header(&self) -> IndexMap<String, String> {
+        let (black_key, white_key) = if self.handicap_names {
+            ("下手", "上手")
+        } else {
+            ("先手", "後手")
+        };
+        let mut header = Vec::new();
+        let mut push = |key: &str, value: Option<String>| {
+            if let Some(value) = value {
+                header.push((String::from(key), value));
+            }
+        };
+        push("開始日時", self.start_time.map(|dt| dt.to_string()));
+        push("終了日時", self.end_time.map(|dt| dt.to_string()));
+        push("棋戦", self.event.clone());
+        push("戦型", self.opening.clone());
+        push("場所", self.site.clone());
+        for (main_key, byoyomi_key, tc, each) in [
+            ("持ち時間", "秒読み", self.time_control, true),
+            ("先手持ち時間", "先手秒読み", self.black_time_control, false),
+            ("後手持ち時間", "後手秒読み", self.white_time_control, false),
+        ] {
+            if let Some(tc) = tc {
+                let (main, byoyomi) = tc.to_kif(each);
+                push(main_key, Some(main));
+                push(byoyomi_key, byoyomi);
+            }
+        }
+        push("最大手数", self.max_moves.map(|n| format!("{n}手")));
+        push("持将棋", self.jishogi.map(|n| format!("{n}点法")));
+        push(black_key, self.black.clone());
+        push(white_key, self.white.clone());
+        push("先手レーティング", self.black_rating.map(|r| r.to_string()));
+        push("後手レーティング", self.white_rating.map(|r| r.to_string()));
+        push("結果", self.result.clone());
+        push("備考", self.note.clone());
+        let mut header = header.into_iter().collect::<IndexMap<_, _>>();
+        for (key, value) in &self.others {
+            header.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+        header
+    }
+
+    /// Writes the game information to the existing header in place
+    ///
+    /// Only the fields which differ from the values parsed from `header` are written,
+    /// so the original values and the order of the unchanged fields are kept.
+    /// The changed values are written in the canonical format as [`GameInfo::to_header`],
+    /// in place of the original values, and the new keys are appended.
+    pub fn update_header(&self, header: &mut IndexMap<String, String>) {
+        let current = GameInfo::from_header(header);
+        for key in current.others.keys() {
+            if !self.others.contains_key(key) {
+                header.shift_remove(key);
+            }
+        }
+        for (key, value) in &self.others {
+            if header.get(key) != Some(value) {
+                header.insert(key.clone(), value.clone());
+            }
+        }
+        let mut write = |key: &str, changed: bool, value: Option<String>| {
+            match value {
+                Some(value) if changed => {
+                    header.insert(String::from(key), value);
+                }
+                // The key may be kept in `others` with the value which cannot be parsed
+                None if changed && !self.others.contains_key(key) => {
+                    header.shift_remove(key);
+                }
+                _ => {}
+            }
+        };
+
+        let names = |handicap| {
+            if handicap {
+                ("下手", "上手")
+            } else {
+                ("先手", "後手")
+            }
+        };
+        let renamed = self.handicap_names != current.handicap_names;
+        if renamed {
+            let (black_key, white_key) = names(current.handicap_names);
+            write(black_key, current.black.is_some(), None);
+            write(white_key, current.white.is_some(), None);
+        }
+        let (black_key, white_key) = names(self.handicap_names);
+        write(
+            black_key,
+            renamed || self.black != current.black,
+            self.black.clone(),
+        );
+        write(
+            white_key,
+            renamed || self.white != current.white,
+            self.white.clone(),
+        );
+        for (key, value, original) in [
+            ("開始日時", self.start_time, current.start_time),
+            ("終了日時", self.end_time, current.end_time),
+        ] {
+            write(key, value != original, value.map(|dt| dt.to_string()));
+        }
+        for (key, value, original) in [
+            ("棋戦", &self.event, &current.event),
+            ("場所", &self.site, &current.site),
+            ("戦型", &self.opening, &current.opening),
+            ("結果", &self.result, &current.result),
+            ("備考", &self.note, &current.note),
+        ] {
+            write(key, value != original, value.clone());
+        }
+        for (main_key, byoyomi_key, tc, original, each) in [
+            (
+                "持ち時間",
+                "秒読み",
+                self.time_control,
+                current.time_control,
+                true,
+            ),
+            (
+                "先手持ち時間",
+                "先手秒読み",
+                self.black_time_control,
+                current.black_time_control,
+                false,
+            ),
+            (
+                "後手持ち時間",
+                "後手秒読み",
+                self.white_time_control,
+                current.white_time_control,
+                false,
+            ),
+        ] {
+            let (main, byoyomi) = tc.map(|tc| tc.to_kif(each)).unzip();
+            write(main_key, tc != original, main);
+            write(byoyomi_key, tc != original, byoyomi.flatten());
+        }
+        for (key, value, original) in [
+            ("先手レーティング", self.black_rating, current.black_rating),
+            ("後手レーティング", self.white_rating, current.white_rating),
+        ] {
+            write(key, value != original, value.map(|r| r.to_string()));
+        }
+        write(
+            "最大手数",
+            self.max_moves != current.max_moves,
+            self.max_moves.map(|n| format!("{n}手")),
+        );
+        write(
+            "持将棋",
+            self.jishogi != current.jishogi,
+            self.jishogi.map(|n| format!("{n}点法")),
+        );
+    }
+
+    /// Converts to the header lines of CSA, such as `N+先手` and `$EVENT:棋戦`
+    ///
+    /// The first line is the version, which is `V3.0` if the time controls need `$TIME`, `$TIME+` or `$TIME-`,
+    /// and `V2.2` otherwise.
+    /// The date times and the time controls which cannot be parsed are written as comments such as `'持ち時間：不明`,
+    /// and the other information which cannot be represented in CSA, such as [`GameInfo::result`], is not included.
+    pub fn to_csa_header(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut comments = Vec::new();
+        let mut unparsed = |key: &str| {
+            if let Some(s) = self.others.get(key) {
+                comments.push(format!("'{key}：{s}"));
+            }
+        };
+        if let Some(s) = self.black.as_ref().filter(|s| !s.is_empty()) {
+            lines.push(format!("N+{s}"));
+        }
+        if let Some(s) = self.white.as_ref().filter(|s| !s.is_empty()) {
+            lines.push(format!("N-{s}"));
+        }
+        if let Some(s) = &self.event {
+            lines.push(format!("$EVENT:{s}"));
+        }
+        if let Some(s) = &self.site {
+            lines.push(format!("$SITE:{s}"));
+        }
+        for (key, kif_key, datetime) in [
+            ("START_TIME", "開始日時", self.start_time),
+            ("END_TIME", "終了日時", self.end_time),
+        ] {
+            match datetime {
+                Some(dt) => lines.push(format!("${key}:{dt}")),
+                None => unparsed(kif_key),
+            }
+        }
+        let mut v3 = false;
+        if let Some(tc) = self.time_control {
+            // `$TIME_LIMIT` of V2.2 cannot represent seconds of the main time and increments
+            if tc.total % 60 == 0 && tc.byoyomi < 100 && tc.increment == 0 {
+                lines.push(format!(
+                    "$TIME_LIMIT:{:02}:{:02}+{:02}",
+                    tc.total / 3600,
+                    tc.total / 60 % 60,
+                    tc.byoyomi
+                ));
+            } else {
+                lines.push(format!("$TIME:{}", tc.to_csa_time()));
+                v3 = true;
+            }
+        } else {
+            unparsed("持ち時間");
+        }
+        for (key, kif_key, tc) in [
+            ("TIME+", "先手持ち時間", self.black_time_control),
+            ("TIME-", "後手持ち時間", self.white_time_control),
+        ] {
+            match tc {
+                Some(tc) => {
+                    lines.push(format!("${key}:{}", tc.to_csa_time()));
+                    v3 = true;
+                }
+                None => unparsed(kif_key),
+            }
+        }
+        if let Some(n) = self.jishogi {
+            lines.push(format!("$JISHOGI:{n}"));
+        }
+        if let Some(n) = self.max_moves {
+            lines.push(format!("$MAX_MOVES:{n}"));
+        }
+        if let Some(s) = &self.opening {
+            lines.push(format!("$OPENING:{s}"));
+        }
+        if let Some(s) = &self.note {
+            lines.push(format!("$NOTE:{s}"));
+        }
+        let version = String::from(if v3 { "V3.0" } else { "V2.2" });
+        [vec![version], lines, comments].concat()
+    }
+}
+
+// Sets the parsed value to the field, and returns whether it was parsed
+fn set<T>(field: &mut Option<T>, value: Option<T>) -> bool {
+    let parsed = value.is_some();
+    if parsed {
+        *field = value;
+    }
+    parsed
+}
+
+impl JsonKifuFormat {
+    /// Returns the typed view of [`JsonKifuFormat::header`]
+    pub fn game_info(&self) -> GameInfo {
+        GameInfo::from_header(&self.header)
+    }
+
+    /// Writes the game information to [`JsonKifuFormat::header`] in place, as [`GameInfo::update_header`]
+    pub fn set_game_info(&mut self, info: &GameInfo) {
+        info.update_header(&mut self.header);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(v: &[(&str, &str)]) -> IndexMap<String, String> {
+        v.iter()
+            .map(|&(k, v)| (String::from(k), String::from(v)))
+            .collect()
+    }
+
+    #[test]
+    fn parse_datetime() {
+        assert_eq!(
+            Some(DateTime {
+                year: 1999,
+                month: 7,
+                day: 15,
+                hour: Some(19),
+                minute: Some(7),
+                second: Some(12),
+            }),
+            DateTime::parse("1999/07/15(木) 19:07:12")
+        );
+        assert_eq!(
+            Some("2022/05/07 10:00"),
+            DateTime::parse("2022/05/07 10:00")
+                .map(|dt| dt.to_string())
+                .as_deref()
+        );
+        assert_eq!(
+            Some("2022/05/07"),
+            DateTime::parse("2022/05/07")
+                .map(|dt| dt.to_string())
+                .as_deref()
+        );
+        assert_eq!(None, DateTime::parse("2022/13/07"));
+        assert_eq!(None, DateTime::parse("2022/02/29"));
+        assert_eq!(None, DateTime::parse("2022/04/31"));
+        assert_eq!(None, DateTime::parse("1900/02/29"));
+        assert!(DateTime::parse("2000/02/29").is_some());
+        assert!(DateTime::parse("2024/02/29").is_some());
+        assert_eq!(None, DateTime::parse("2022/05/07 10:00:00:00"));
+        assert_eq!(None, DateTime::parse("昭和60年"));
+    }
+
+    #[test]
+    fn from_header() {
+        let info = GameInfo::from_header(&header(&[
+            ("開始日時", "1999/07/15(木) 19:07:12"),
+            ("終了日時", "不明"),
+            ("持ち時間", "各25分（1手ごとに10秒加算）"),
+            ("秒読み", "30秒"),
+            ("先手持ち時間", "1時間"),
+            ("下手", "下手の対局者名"),
+            ("上手", "上手の対局者名"),
+            ("先手レーティング", "1500.5"),
+            ("最大手数", "256手"),
+            ("作者", "作者名"),
+        ]));
+        assert_eq!(
+            GameInfo {
+                black: Some(String::from("下手の対局者名")),
+                white: Some(String::from("上手の対局者名")),
+                handicap_names: true,
+                start_time: DateTime::parse("1999/07/15 19:07:12"),
+                time_control: Some(TimeControl {
+                    total: 1500,
+                    byoyomi: 30,
+                    increment: 10,
+                }),
+                black_time_control: Some(TimeControl {
+                    total: 3600,
+                    byoyomi: 0,
+                    increment: 0,
+                }),
+                black_rating: Some(1500.5),
+                max_moves: Some(256),
+                others: header(&[("終了日時", "不明"), ("作者", "作者名")]),
+                ..Default::default()
+            },
+            info
+        );
+        // Round trip
+        assert_eq!(info, GameInfo::from_header(&info.to_header()));
+        assert_eq!(
+            header(&[
+                ("開始日時", "1999/07/15 19:07:12"),
+                ("持ち時間", "各25分（1手ごとに10秒加算）"),
+                ("秒読み", "30秒"),
+                ("先手持ち時間", "1時間"),
+                ("最大手数", "256手"),
+                ("下手", "下手の対局者名"),
+                ("上手", "上手の対局者名"),
+                ("先手レーティング", "1500.5"),
+                ("終了日時", "不明"),
+                ("作者", "作者名"),
+            ]),
+            info.to_header()
+        );
+    }
+
+    #[test]
+    fn update_header() {
+        let original = header(&[
+            ("開始日時", "1999/07/15(木) 19:07:12"),
+            ("持ち時間", "各90分"),
+            ("先手", "先手の対局者名"),
+            ("先手レーティング", "1500.50"),
+            ("終了日時", "不明"),
+            ("作者", "作者名"),
+        ]);
+        let mut info = GameInfo::from_header(&original);
+        let mut header = original.clone();
+        info.update_header(&mut header);
+        assert_eq!(original, header);
+
+        // Only the changed values are written, in place of the original ones
+        info.black = Some(String::from("対局者"));
+        info.white = Some(String::from("後手の対局者名"));
+        info.black_rating = None;
+        info.others.shift_remove("作者");
+        info.update_header(&mut header);
+        assert_eq!(
+            vec![
+                ("開始日時", "1999/07/15(木) 19:07:12"),
+                ("持ち時間", "各90分"),
+                ("先手", "対局者"),
+                ("終了日時", "不明"),
+                ("後手", "後手の対局者名"),
+            ],
+            header
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(info, GameInfo::from_header(&header));
+    }
+
+    #[test]
+    fn set_game_info_lossless() {
+        for entry in std::fs::read_dir("data/tests/kif").expect("failed to read dir") {
+            let path = entry.expect("failed to read entry").path();
+            if path.extension() != Some(std::ffi::OsStr::new("kif")) {
+                continue;
+            }
+            let mut jkf = crate::parser::parse_kif_file(&path).expect("failed to parse kif");
+            let header = jkf.header.clone();
+            jkf.set_game_info(&jkf.game_info());
+            assert_eq!(header, jkf.header, "header changed: {}", path.display());
+        }
+    }
+
+    #[test]
+    fn to_csa_header() {
+        let info = GameInfo::from_header(&header(&[
+            ("先手", "先手の対局者名"),
+            ("開始日時", "1999/07/15(木) 19:07:12"),
+            ("終了日時", "1999/07/15(木) 19:07:17 頃"),
+            ("持ち時間", "各5分"),
+            ("秒読み", "10秒"),
+            ("結果", "先手の勝ち"),
+        ]));
+        assert_eq!(
+            vec![
+                "V2.2",
+                "N+先手の対局者名",
+                "$START_TIME:1999/07/15 19:07:12",
+                "$TIME_LIMIT:00:05+10",
+                "'終了日時：1999/07/15(木) 19:07:17 頃",
+            ],
+            info.to_csa_header()
+        );
+
+        // The values which cannot be parsed are written as comments
+        let info = GameInfo::from_header(&header(&[
+            ("持ち時間", "2時間（ストップウォッチ）"),
+            ("先手持ち時間", "不明"),
+        ]));
+        assert_eq!(None, info.time_control);
+        assert_eq!(
+            vec![
+                "V2.2",
+                "'持ち時間：2時間（ストップウォッチ）",
+                "'先手持ち時間：不明"
+            ],
+            info.to_csa_header()
+        );
+
+        // `$TIME` needs V3.0
+        let info = GameInfo::from_header(&header(&[("持ち時間", "各5分（1手ごとに10秒加算）")]));
+        assert_eq!(vec!["V3.0", "$TIME:300+0+10"], info.to_csa_header());
+    }
+
+    #[test]
+    fn invalid_values() {
+        // Overflows
+        assert_eq!(
+            None,
+            TimeControl::from_csa_time_limit("99999999999999999:00+00")
+        );
+        assert_eq!(None, TimeControl::from_kif("99999999999999999999分", None));
+        assert_eq!(None, TimeControl::from_kif("9999999999999999時間", None));
+        let info = GameInfo::from_header(&header(&[
+            ("持ち時間", "9999999999999999時間"),
+            ("先手レーティング", "NaN"),
+            ("後手レーティング", "1500.50"),
+        ]));
+        assert_eq!(None, info.time_control);
+        assert_eq!(None, info.black_rating);
+        assert_eq!(Some(1500.5), info.white_rating);
+        assert_eq!(
+            header(&[
+                ("持ち時間", "9999999999999999時間"),
+                ("先手レーティング", "NaN")
+            ]),
+            info.others
+        );
+    }
+}
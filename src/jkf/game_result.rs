@@ -0,0 +1,352 @@
+use super::{Color, JsonKifuFormat, MoveSpecial, Preset};
+use crate::shogi_core::is_mate;
+use crate::tree::{Declaration, DeclarationRule};
+use shogi_core::Position;
+
+/// The outcome of a game
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The player of the color won
+    Win(Color),
+    /// The game was drawn
+    Draw,
+    /// The game was aborted or suspended
+    Aborted,
+    /// The outcome is not known from the kifu
+    Unknown,
+}
+
+/// The reason of [`Outcome`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResultReason {
+    /// 投了
+    Resignation,
+    /// 詰み, recorded or detected from the final position
+    Checkmate,
+    /// 時間切れ
+    TimeUp,
+    /// 反則 (an illegal move or action)
+    Illegal,
+    /// 千日手
+    Repetition,
+    /// 連続王手の千日手, which is the loss of the checking side
+    PerpetualCheck,
+    /// 持将棋
+    Impasse,
+    /// 入玉宣言
+    Declaration,
+    /// 引き分け without the detailed reason
+    Agreement,
+    /// 中断, 待った or an error
+    Interruption,
+    /// 不詰 of a tsume problem
+    NoMate,
+    /// The value of `結果` in the header
+    Header,
+}
+
+/// The result of a game, returned by [`JsonKifuFormat::result()`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GameResult {
+    /// The outcome
+    pub outcome: Outcome,
+    /// The reason of the outcome, `None` if nothing in the kifu tells it
+    ///
+    /// The outcome can be [`Outcome::Unknown`] with a reason, such as [`ResultReason::NoMate`].
+    pub reason: Option<ResultReason>,
+}
+
+impl GameResult {
+    const UNKNOWN: GameResult = GameResult {
+        outcome: Outcome::Unknown,
+        reason: None,
+    };
+
+    fn new(outcome: Outcome, reason: ResultReason) -> Self {
+        GameResult {
+            outcome,
+            reason: Some(reason),
+        }
+    }
+}
+
+fn opponent(color: Color) -> Color {
+    match color {
+        Color::Black => Color::White,
+        Color::White => Color::Black,
+    }
+}
+
+// The result by the special move, where `color` is the side to move
+fn from_special(special: MoveSpecial, color: Color) -> GameResult {
+    use {Outcome::*, ResultReason::*};
+    match special {
+        MoveSpecial::SpecialToryo => GameResult::new(Win(opponent(color)), Resignation),
+        MoveSpecial::SpecialTsumi => GameResult::new(Win(opponent(color)), Checkmate),
+        MoveSpecial::SpecialTimeUp => GameResult::new(Win(opponent(color)), TimeUp),
+        // `ILLEGAL_MOVE` is recorded at the ply of the side to move, as `反則負け` of KIF,
+        // unlike `+ILLEGAL_ACTION` and `-ILLEGAL_ACTION` which name the losing side
+        MoveSpecial::SpecialIllegalMove => GameResult::new(Win(opponent(color)), Illegal),
+        MoveSpecial::SpecialIllegalActionBlack => GameResult::new(Win(Color::White), Illegal),
+        MoveSpecial::SpecialIllegalActionWhite => GameResult::new(Win(Color::Black), Illegal),
+        // Used only if the final position is not available, otherwise `result()` evaluates the declaration
+        MoveSpecial::SpecialKachi => GameResult::new(Win(color), Declaration),
+        MoveSpecial::SpecialSennichite => GameResult::new(Draw, Repetition),
+        MoveSpecial::SpecialJishogi => GameResult::new(Draw, Impasse),
+        MoveSpecial::SpecialHikiwake => GameResult::new(Draw, Agreement),
+        MoveSpecial::SpecialChudan | MoveSpecial::SpecialMatta | MoveSpecial::SpecialError => {
+            GameResult::new(Aborted, Interruption)
+        }
+        MoveSpecial::SpecialFuzumi => GameResult::new(Unknown, NoMate),
+    }
+}
+
+// The outcome written in `結果` of the header, such as `先手勝ち`, `下手の勝ち` or `千日手`
+fn parse_outcome(s: &str) -> Option<Outcome> {
+    let player = |names: [&str; 2]| names.iter().any(|name| s.contains(name));
+    let (black, white) = (player(["先手", "下手"]), player(["後手", "上手"]));
+    if s.contains('勝') {
+        match (black, white) {
+            (true, false) => return Some(Outcome::Win(Color::Black)),
+            (false, true) => return Some(Outcome::Win(Color::White)),
+            _ => {}
+        }
+    }
+    if s.contains('負') {
+        match (black, white) {
+            (true, false) => return Some(Outcome::Win(Color::White)),
+            (false, true) => return Some(Outcome::Win(Color::Black)),
+            _ => {}
+        }
+    }
+    if ["千日手", "持将棋", "引き分け", "引分"]
+        .iter()
+        .any(|word| s.contains(word))
+    {
+        Some(Outcome::Draw)
+    } else if s.contains("中断") {
+        Some(Outcome::Aborted)
+    } else {
+        None
+    }
+}
+
+impl JsonKifuFormat {
+    /// Infers the result of the game from the main line and the header
+    ///
+    /// The result is decided by the first one found in the following order:
+    ///
+    /// 1. the repetition of the same position for the fourth time, if the main line ends without a special move,
+    ///    or with `CHUDAN` or `SENNICHITE`
+    /// 2. the special move at the end of the main line, with the side to move (e.g. `TORYO` is the loss of the side to move).
+    ///    `KACHI` is evaluated by [`Declaration`] of the final position with [`DeclarationRule::Points27`].
+    /// 3. the checkmate of the final position
+    /// 4. `結果` in the header, such as `先手勝ち`
+    pub fn result(&self) -> GameResult {
+        let first = self.initial_color();
+        let last = self.moves.len().saturating_sub(1);
+        let special = self.moves.last().and_then(|mf| mf.special);
+        let color = if last % 2 == 1 {
+            first
+        } else {
+            opponent(first)
+        };
+
+        if matches!(
+            special,
+            None | Some(MoveSpecial::SpecialChudan | MoveSpecial::SpecialSennichite)
+        ) {
+            if let Some(repetition) = self.main_line_repetition().ok().flatten() {
+                return match repetition.perpetual_check {
+                    Some(color) => {
+                        GameResult::new(Outcome::Win(opponent(color)), ResultReason::PerpetualCheck)
+                    }
+                    None => GameResult::new(Outcome::Draw, ResultReason::Repetition),
+                };
+            }
+        }
+        let last_position = self
+            .positions()
+            .last()
+            .and_then(|item| item.ok())
+            .map(|(_, _, pos)| pos);
+        if let Some(special) = special {
+            if let (MoveSpecial::SpecialKachi, Some(pos)) = (special, &last_position) {
+                let pos = Position::arbitrary_position(pos.clone());
+                let declaration = Declaration::evaluate(&pos, DeclarationRule::Points27);
+                return GameResult::new(declaration.outcome(), ResultReason::Declaration);
+            }
+            return from_special(special, color);
+        }
+        if let Some(pos) = &last_position {
+            if is_mate(pos) {
+                let loser = pos.side_to_move().into();
+                return GameResult::new(Outcome::Win(opponent(loser)), ResultReason::Checkmate);
+            }
+        }
+        match self.game_info().result.as_deref().and_then(parse_outcome) {
+            Some(outcome) => GameResult::new(outcome, ResultReason::Header),
+            None => GameResult::UNKNOWN,
+        }
+    }
+
+    /// Writes the outcome of the result to `結果` in the header, such as `先手勝ち`,
+    /// or removes it if the outcome is unknown
+    ///
+    /// For the handicap games, the players are written as `下手` and `上手`.
+    pub fn set_result(&mut self, result: &GameResult) {
+        let handicap = self.initial.is_some_and(|initial| {
+            !matches!(initial.preset, Preset::PresetHirate | Preset::PresetOther)
+        });
+        let name = |color| match (color, handicap) {
+            (Color::Black, false) => "先手",
+            (Color::White, false) => "後手",
+            (Color::Black, true) => "下手",
+            (Color::White, true) => "上手",
+        };
+        let value = match result.outcome {
+            Outcome::Win(color) => format!("{}勝ち", name(color)),
+            Outcome::Draw => match result.reason {
+                Some(ResultReason::Repetition) => String::from("千日手"),
+                Some(ResultReason::Impasse) => String::from("持将棋"),
+                _ => String::from("引き分け"),
+            },
+            Outcome::Aborted => String::from("中断"),
+            Outcome::Unknown => {
+                self.header.shift_remove("結果");
+                return;
+            }
+        };
+        self.header.insert(String::from("結果"), value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_csa_str, parse_usi_str};
+
+    #[test]
+    fn result_from_special() {
+        let jkf = parse_csa_str("PI\n+\n+7776FU\n-3334FU\n%TORYO\n").expect("failed to parse csa");
+        assert_eq!(
+            GameResult::new(Outcome::Win(Color::White), ResultReason::Resignation),
+            jkf.result()
+        );
+        let jkf = parse_csa_str("PI\n+\n+7776FU\n%TIME_UP\n").expect("failed to parse csa");
+        assert_eq!(
+            GameResult::new(Outcome::Win(Color::Black), ResultReason::TimeUp),
+            jkf.result()
+        );
+        // The declaration of White fails in the opening
+        let jkf = parse_csa_str("PI\n+\n+7776FU\n%KACHI\n").expect("failed to parse csa");
+        assert_eq!(
+            GameResult::new(Outcome::Win(Color::Black), ResultReason::Declaration),
+            jkf.result()
+        );
+        let mut jkf = parse_usi_str("position sfen RBRB1GGSS/1K1+P1+P3/9/9/9/9/9/9/4k4 b 8P 1")
+            .expect("failed to parse usi");
+        jkf.moves.push(crate::jkf::MoveFormat {
+            special: Some(MoveSpecial::SpecialKachi),
+            ..Default::default()
+        });
+        assert_eq!(
+            GameResult::new(Outcome::Win(Color::Black), ResultReason::Declaration),
+            jkf.result()
+        );
+        // ILLEGAL_MOVE is the loss of the side to move
+        let jkf = parse_csa_str("PI\n+\n+7776FU\n%ILLEGAL_MOVE\n").expect("failed to parse csa");
+        assert_eq!(
+            GameResult::new(Outcome::Win(Color::Black), ResultReason::Illegal),
+            jkf.result()
+        );
+        let jkf = parse_csa_str("PI\n+\n+7776FU\n%+ILLEGAL_ACTION\n").expect("failed to parse csa");
+        assert_eq!(
+            GameResult::new(Outcome::Win(Color::White), ResultReason::Illegal),
+            jkf.result()
+        );
+        let jkf = parse_csa_str("PI\n+\n+7776FU\n%CHUDAN\n").expect("failed to parse csa");
+        assert_eq!(
+            GameResult::new(Outcome::Aborted, ResultReason::Interruption),
+            jkf.result()
+        );
+        // The handicapped side moves first
+        let jkf = parse_csa_str("PI11KY\n-\n-3334FU\n%TORYO\n").expect("failed to parse csa");
+        assert_eq!(
+            GameResult::new(Outcome::Win(Color::White), ResultReason::Resignation),
+            jkf.result()
+        );
+    }
+
+    #[test]
+    fn result_detected() {
+        // 頭金
+        let jkf = parse_usi_str("position sfen 4k4/9/4P4/9/9/9/9/9/4K4 b G 1 moves G*5b")
+            .expect("failed to parse usi");
+        assert_eq!(
+            GameResult::new(Outcome::Win(Color::Black), ResultReason::Checkmate),
+            jkf.result()
+        );
+
+        // 千日手 recorded as 中断
+        let mut jkf = parse_usi_str(&format!(
+            "position startpos moves {}",
+            "5i5h 5a5b 5h5i 5b5a ".repeat(3)
+        ))
+        .expect("failed to parse usi");
+        jkf.moves.push(crate::jkf::MoveFormat {
+            special: Some(MoveSpecial::SpecialChudan),
+            ..Default::default()
+        });
+        assert_eq!(
+            GameResult::new(Outcome::Draw, ResultReason::Repetition),
+            jkf.result()
+        );
+
+        let jkf = parse_usi_str(&format!(
+            "position sfen 8k/9/9/9/9/9/9/9/K7R w - 1 moves {}",
+            "1a2a 1i2i 2a1a 2i1i ".repeat(3)
+        ))
+        .expect("failed to parse usi");
+        assert_eq!(
+            GameResult::new(Outcome::Win(Color::White), ResultReason::PerpetualCheck),
+            jkf.result()
+        );
+    }
+
+    #[test]
+    fn result_header() {
+        let mut jkf = parse_csa_str("PI\n+\n+7776FU\n").expect("failed to parse csa");
+        assert_eq!(GameResult::UNKNOWN, jkf.result());
+        let mut fuzumi = jkf.clone();
+        fuzumi.moves.push(crate::jkf::MoveFormat {
+            special: Some(MoveSpecial::SpecialFuzumi),
+            ..Default::default()
+        });
+        assert_eq!(
+            GameResult::new(Outcome::Unknown, ResultReason::NoMate),
+            fuzumi.result()
+        );
+        for (value, outcome) in [
+            ("先手勝ち", Outcome::Win(Color::Black)),
+            ("後手の勝ち", Outcome::Win(Color::White)),
+            ("下手負け", Outcome::Win(Color::White)),
+            ("持将棋", Outcome::Draw),
+            ("中断", Outcome::Aborted),
+        ] {
+            jkf.header.insert(String::from("結果"), String::from(value));
+            assert_eq!(GameResult::new(outcome, ResultReason::Header), jkf.result());
+        }
+
+        // Write back the result
+        let mut jkf =
+            parse_csa_str("PI\n+\n+7776FU\n-3334FU\n%TORYO\n").expect("failed to parse csa");
+        jkf.set_result(&jkf.result());
+        assert_eq!(Some(&String::from("後手勝ち")), jkf.header.get("結果"));
+        jkf.set_result(&GameResult::UNKNOWN);
+        assert_eq!(None, jkf.header.get("結果"));
+        let mut jkf = parse_csa_str("PI11KY\n-\n-3334FU\n%TORYO\n").expect("failed to parse csa");
+        jkf.set_result(&jkf.result());
+        assert_eq!(Some(&String::from("上手勝ち")), jkf.header.get("結果"));
+    }
+}
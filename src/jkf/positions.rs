@@ -0,0 +1,171 @@
+use super::{JsonKifuFormat, MoveFormat};
+use crate::error::{ConvertError, LocatedNormalizeError, NormalizeError};
+use shogi_core::{Move, PartialPosition, Position};
+
+/// An iterator over the positions of a line, returned by [`JsonKifuFormat::positions()`]
+/// and [`JsonKifuFormat::line_positions()`]
+///
+/// Each item is the ply number, the move and the position after the move.
+/// The first item is the initial position with `moves[0]` at the ply `0`,
+/// and the position doesn't change by a special move.
+///
+/// The iterator ends after an item of the error.
+#[derive(Debug)]
+pub struct Positions<'a> {
+    moves: std::iter::Enumerate<std::vec::IntoIter<&'a MoveFormat>>,
+    forks: Vec<(usize, usize)>,
+    // `Position` keeps the history of the moves for `KifuTree::position()`
+    pos: Option<Result<Position, ConvertError>>,
+}
+
+impl Positions<'_> {
+    // The position after the last item, or `None` after an error
+    pub(crate) fn position(&self) -> Option<&Position> {
+        self.pos.as_ref()?.as_ref().ok()
+    }
+}
+
+impl<'a> Iterator for Positions<'a> {
+    type Item = Result<(usize, &'a MoveFormat, PartialPosition), ConvertError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut pos = match self.pos.take()? {
+            Ok(pos) => pos,
+            Err(err) => return Some(Err(err)),
+        };
+        let Some((ply, mf)) = self.moves.next() else {
+            self.pos = Some(Ok(pos));
+            return None;
+        };
+        if let Some(mmf) = &mf.move_ {
+            let mv = match Move::try_from(mmf) {
+                Ok(mv) => mv,
+                Err(err) => return Some(Err(err)),
+            };
+            if pos.make_move(mv).is_none() {
+                // The path to the variation which contains the move
+                let forks = self.forks.iter().take_while(|(p, _)| *p <= ply).copied();
+                return Some(Err(ConvertError::Normalize(LocatedNormalizeError {
+                    error: NormalizeError::MakeMoveFailed(mv),
+                    ply,
+                    forks: forks.collect(),
+                })));
+            }
+        }
+        let item = pos.inner().clone();
+        self.pos = Some(Ok(pos));
+        Some(Ok((ply, mf, item)))
+    }
+}
+
+impl JsonKifuFormat {
+    /// Returns an iterator over the positions of the main line, replaying the moves from the initial position
+    ///
+    /// See [`Positions`] for the items.
+    /// The SFEN, the board and the hands of each position are available from [`PartialPosition`]
+    /// (e.g. [`PartialPosition::to_sfen_owned()`]).
+    pub fn positions(&self) -> Positions<'_> {
+        self.line_positions(&[])
+            .expect("the main line always exists")
+    }
+
+    /// Returns an iterator over the positions of the line to the variation
+    ///
+    /// `forks` is the path to the variation as [`JsonKifuFormat::line()`],
+    /// and this function returns `None` if the path doesn't exist.
+    pub fn line_positions(&self, forks: &[(usize, usize)]) -> Option<Positions<'_>> {
+        let moves = self.line(forks)?;
+        Some(Positions {
+            moves: moves.into_iter().enumerate(),
+            forks: forks.to_vec(),
+            pos: Some(self.initial_position().map(Position::arbitrary_position)),
+        })
+    }
+
+    // The initial position, which is the starting position of 平手 if `initial` is not given
+    pub(crate) fn initial_position(&self) -> Result<PartialPosition, ConvertError> {
+        match &self.initial {
+            Some(initial) => PartialPosition::try_from(initial),
+            None => Ok(PartialPosition::startpos()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{parse_kif_file, parse_usi_str};
+    use shogi_core::Position;
+
+    #[test]
+    fn positions() {
+        let jkf =
+            parse_usi_str("position startpos moves 7g7f 3c3d 8h2b+").expect("failed to parse usi");
+        let positions = jkf
+            .positions()
+            .map(|item| item.map(|(ply, _, pos)| (ply, pos.to_sfen_owned())))
+            .collect::<Result<Vec<_>, _>>()
+            .expect("failed to replay");
+        assert_eq!(
+            vec![
+                (
+                    0,
+                    String::from("lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1")
+                ),
+                (
+                    1,
+                    String::from(
+                        "lnsgkgsnl/1r5b1/ppppppppp/9/9/2P6/PP1PPPPPP/1B5R1/LNSGKGSNL w - 2"
+                    )
+                ),
+                (
+                    2,
+                    String::from(
+                        "lnsgkgsnl/1r5b1/pppppp1pp/6p2/9/2P6/PP1PPPPPP/1B5R1/LNSGKGSNL b - 3"
+                    )
+                ),
+                (
+                    3,
+                    String::from(
+                        "lnsgkgsnl/1r5+B1/pppppp1pp/6p2/9/2P6/PP1PPPPPP/7R1/LNSGKGSNL w B 4"
+                    )
+                ),
+            ],
+            positions
+        );
+        assert_eq!(
+            Ok(positions[0].1.clone()),
+            jkf.initial_position().map(|pos| pos.to_sfen_owned())
+        );
+    }
+
+    #[test]
+    fn line_positions() {
+        let jkf = parse_kif_file("data/tests/kif/forks.kif").expect("failed to parse kif");
+        let tree = jkf.tree();
+        let fork = tree.children(tree.main_line()[9])[1];
+        let (ply, mf, pos) = jkf
+            .line_positions(tree.forks(fork))
+            .expect("line should exist")
+            .nth(10)
+            .expect("position should exist")
+            .expect("failed to replay");
+        assert_eq!(10, ply);
+        assert_eq!(tree.get(fork), Some(mf));
+        assert_eq!(
+            tree.position(fork).expect("failed to get position").inner(),
+            &pos
+        );
+        assert!(jkf.line_positions(&[(100, 0)]).is_none());
+
+        // The last position of the main line is the same as the conversion into `Position`
+        let (_, _, pos) = jkf
+            .positions()
+            .last()
+            .expect("position should exist")
+            .expect("failed to replay");
+        assert_eq!(
+            Position::try_from(&jkf).expect("failed to convert").inner(),
+            &pos
+        );
+    }
+}
@@ -8,12 +8,13 @@
 //! See [https://github.com/na2hiro/json-kifu-format](https://github.com/na2hiro/json-kifu-format).
 
 pub mod converter;
-mod csa;
 pub mod error;
 pub mod jkf;
 mod normalizer;
 pub mod parser;
 mod shogi_core;
+pub mod tree;
+mod validator;
 
 /// An alias for [`jkf::JsonKifuFormat`]
 pub type JKF = jkf::JsonKifuFormat;
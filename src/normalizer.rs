@@ -1,9 +1,20 @@
-use crate::error::NormalizeError;
+use crate::error::{LocatedNormalizeError, NormalizeError};
 use crate::jkf::*;
+use crate::shogi_core::CheckReplay;
 use shogi_core::{LegalityChecker, PartialPosition};
 use shogi_legality_lite::LiteLegalityChecker;
 use shogi_official_kifu::display_single_move_kansuji;
 
+/// Options for [`JsonKifuFormat::normalize_with_options()`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NormalizeOptions {
+    /// If `true`, `TSUMI` is appended to the main line and the variations
+    /// which end with a move checkmating the opponent, without a special move.
+    pub append_tsumi: bool,
+    /// If `true`, `王手` is added to the comments of each move which gives check, unless it is already there.
+    pub annotate_checks: bool,
+}
+
 pub(crate) const HIRATE_BOARD: [[Piece; 9]; 9] = {
     #[rustfmt::skip]
     const EMP: Piece = Piece { color: None, kind: None };
@@ -68,6 +79,16 @@ const STATE_KY: StateFormat = {
     }
 };
 
+const STATE_KYR: StateFormat = {
+    let mut board = HIRATE_BOARD;
+    board[8][0] = Piece::empty();
+    StateFormat {
+        color: Color::White,
+        board,
+        hands: [Hand::empty(); 2],
+    }
+};
+
 const STATE_KA: StateFormat = {
     let mut board = HIRATE_BOARD;
     board[1][1] = Piece::empty();
@@ -110,6 +131,16 @@ const STATE_2: StateFormat = {
     }
 };
 
+const STATE_3: StateFormat = {
+    let mut board = STATE_2.board;
+    board[0][0] = Piece::empty();
+    StateFormat {
+        color: Color::White,
+        board,
+        hands: [Hand::empty(); 2],
+    }
+};
+
 const STATE_4: StateFormat = {
     let mut board = STATE_2.board;
     board[0][0] = Piece::empty();
@@ -121,6 +152,26 @@ const STATE_4: StateFormat = {
     }
 };
 
+const STATE_5: StateFormat = {
+    let mut board = STATE_4.board;
+    board[7][0] = Piece::empty();
+    StateFormat {
+        color: Color::White,
+        board,
+        hands: [Hand::empty(); 2],
+    }
+};
+
+const STATE_5L: StateFormat = {
+    let mut board = STATE_4.board;
+    board[1][0] = Piece::empty();
+    StateFormat {
+        color: Color::White,
+        board,
+        hands: [Hand::empty(); 2],
+    }
+};
+
 const STATE_6: StateFormat = {
     let mut board = STATE_4.board;
     board[1][0] = Piece::empty();
@@ -132,6 +183,26 @@ const STATE_6: StateFormat = {
     }
 };
 
+const STATE_7L: StateFormat = {
+    let mut board = STATE_6.board;
+    board[2][0] = Piece::empty();
+    StateFormat {
+        color: Color::White,
+        board,
+        hands: [Hand::empty(); 2],
+    }
+};
+
+const STATE_7R: StateFormat = {
+    let mut board = STATE_6.board;
+    board[6][0] = Piece::empty();
+    StateFormat {
+        color: Color::White,
+        board,
+        hands: [Hand::empty(); 2],
+    }
+};
+
 const STATE_8: StateFormat = {
     let mut board = STATE_6.board;
     board[2][0] = Piece::empty();
@@ -259,9 +330,17 @@ fn pk2k(pk: shogi_core::PieceKind) -> Kind {
 }
 
 impl JsonKifuFormat {
-    pub fn normalize(&mut self) -> Result<(), NormalizeError> {
-        normalize_initial(self)?;
-        let pos = if let Some(initial) = &self.initial {
+    pub fn normalize(&mut self) -> Result<(), LocatedNormalizeError> {
+        self.normalize_with_options(&NormalizeOptions::default())
+    }
+
+    /// Normalizes `self` as [`JsonKifuFormat::normalize()`] with the given options
+    pub fn normalize_with_options(
+        &mut self,
+        options: &NormalizeOptions,
+    ) -> Result<(), LocatedNormalizeError> {
+        normalize_initial(self).map_err(|err| err.at(0))?;
+        if let Some(initial) = &self.initial {
             if !matches!(initial.preset, Preset::PresetHirate | Preset::PresetOther)
                 && self
                     .moves
@@ -278,18 +357,47 @@ impl JsonKifuFormat {
                     }
                 }
             }
-            match PartialPosition::try_from(initial) {
-                Ok(pos) => pos,
-                Err(err) => return Err(NormalizeError::Convert(err.to_string())),
-            }
-        } else {
-            PartialPosition::startpos()
-        };
-        normalize_moves(&mut self.moves[1..], pos, [TimeFormat::default(); 2])?;
+        }
+        let pos = self
+            .initial_position()
+            .map_err(|err| NormalizeError::Convert(err.to_string()).at(0))?;
+        normalize_moves(&mut self.moves[1..], 1, pos, [TimeFormat::default(); 2])?;
+        if options.append_tsumi || options.annotate_checks {
+            let replay = CheckReplay::new(self);
+            annotate_checks(&mut self.moves, 1, replay, options);
+        }
         Ok(())
     }
 }
 
+// Adds `王手` to the comments of the moves which give check in the line from `moves[start]` and its variations,
+// and appends `TSUMI` to the lines which end with a checkmate, as enabled by the options.
+// The moves must be normalized already.
+fn annotate_checks(
+    moves: &mut Vec<MoveFormat>,
+    start: usize,
+    mut replay: CheckReplay,
+    options: &NormalizeOptions,
+) {
+    for mf in moves.iter_mut().skip(start) {
+        for fork in mf.forks.iter_mut().flatten() {
+            annotate_checks(fork, 0, replay.clone(), options);
+        }
+        if replay.make_move(mf) && options.annotate_checks {
+            let comments = mf.comments.get_or_insert_with(Vec::new);
+            if !comments.iter().any(|c| c == "王手") {
+                comments.insert(0, String::from("王手"));
+            }
+        }
+    }
+    if options.append_tsumi && start < moves.len() && replay.is_mate() {
+        moves.push(MoveFormat {
+            special: Some(MoveSpecial::SpecialTsumi),
+            ..Default::default()
+        });
+    }
+}
+
 fn normalize_initial(jkf: &mut JsonKifuFormat) -> Result<(), NormalizeError> {
     if let Some(initial) = &mut jkf.initial {
         *initial = match initial.data {
@@ -301,6 +409,10 @@ fn normalize_initial(jkf: &mut JsonKifuFormat) -> Result<(), NormalizeError> {
                 preset: Preset::PresetKY,
                 data: None,
             },
+            Some(STATE_KYR) => Initial {
+                preset: Preset::PresetKYR,
+                data: None,
+            },
             Some(STATE_KA) => Initial {
                 preset: Preset::PresetKA,
                 data: None,
@@ -317,14 +429,34 @@ fn normalize_initial(jkf: &mut JsonKifuFormat) -> Result<(), NormalizeError> {
                 preset: Preset::Preset2,
                 data: None,
             },
+            Some(STATE_3) => Initial {
+                preset: Preset::Preset3,
+                data: None,
+            },
             Some(STATE_4) => Initial {
                 preset: Preset::Preset4,
                 data: None,
             },
+            Some(STATE_5) => Initial {
+                preset: Preset::Preset5,
+                data: None,
+            },
+            Some(STATE_5L) => Initial {
+                preset: Preset::Preset5L,
+                data: None,
+            },
             Some(STATE_6) => Initial {
                 preset: Preset::Preset6,
                 data: None,
             },
+            Some(STATE_7L) => Initial {
+                preset: Preset::Preset7L,
+                data: None,
+            },
+            Some(STATE_7R) => Initial {
+                preset: Preset::Preset7R,
+                data: None,
+            },
             Some(STATE_8) => Initial {
                 preset: Preset::Preset8,
                 data: None,
@@ -400,7 +532,10 @@ fn calculate_from(
     }
 }
 
-fn normalize_move(mmf: &mut MoveMoveFormat, pos: &PartialPosition) -> Result<(), NormalizeError> {
+pub(crate) fn normalize_move(
+    mmf: &mut MoveMoveFormat,
+    pos: &PartialPosition,
+) -> Result<(), NormalizeError> {
     if matches!(
         (mmf.color, pos.side_to_move()),
         (Color::Black, shogi_core::Color::White) | (Color::White, shogi_core::Color::Black)
@@ -420,7 +555,8 @@ fn normalize_move(mmf: &mut MoveMoveFormat, pos: &PartialPosition) -> Result<(),
         Ok(to) => to,
         Err(err) => return Err(NormalizeError::Convert(err.to_string())),
     };
-    if mmf.from.is_none() {
+    // `relative` is `H` if it is a drop move even though there are pieces which can move to the square
+    if mmf.from.is_none() && mmf.relative != Some(Relative::H) {
         mmf.from = calculate_from(mmf, pos, to)?;
     }
     if let Some(pf) = &mmf.from {
@@ -495,14 +631,18 @@ fn normalize_move(mmf: &mut MoveMoveFormat, pos: &PartialPosition) -> Result<(),
 
 fn normalize_moves(
     moves: &mut [MoveFormat],
+    start: usize,
     mut pos: PartialPosition,
     mut totals: [TimeFormat; 2],
-) -> Result<(), NormalizeError> {
-    for mf in moves {
+) -> Result<(), LocatedNormalizeError> {
+    for (ply, mf) in (start..).zip(moves) {
         // Normalize forks
         if let Some(forks) = &mut mf.forks {
-            for v in forks.iter_mut() {
-                normalize_moves(v, pos.clone(), totals)?;
+            for (i, v) in forks.iter_mut().enumerate() {
+                normalize_moves(v, ply, pos.clone(), totals).map_err(|mut err| {
+                    err.forks.insert(0, (ply, i));
+                    err
+                })?;
             }
         }
         // Calculate total time
@@ -512,13 +652,13 @@ fn normalize_moves(
             time.total = totals[pos.side_to_move().array_index()];
         }
         if let Some(mmf) = &mut mf.move_ {
-            normalize_move(mmf, &pos)?;
+            normalize_move(mmf, &pos).map_err(|err| err.at(ply))?;
             let mv = match shogi_core::Move::try_from(&*mmf) {
                 Ok(mv) => mv,
-                Err(err) => return Err(NormalizeError::Convert(err.to_string())),
+                Err(err) => return Err(NormalizeError::Convert(err.to_string()).at(ply)),
             };
             pos.make_move(mv)
-                .ok_or(NormalizeError::MakeMoveFailed(mv))?;
+                .ok_or(NormalizeError::MakeMoveFailed(mv).at(ply))?;
         } else {
             break;
         }
@@ -533,7 +673,7 @@ mod tests {
     #[test]
     fn normalize_moves_empty() {
         let pos = PartialPosition::startpos();
-        assert!(normalize_moves(&mut [], pos, [TimeFormat::default(); 2]).is_ok());
+        assert!(normalize_moves(&mut [], 1, pos, [TimeFormat::default(); 2]).is_ok());
     }
 
     #[test]
@@ -555,6 +695,7 @@ mod tests {
                         }),
                         ..Default::default()
                     }],
+                    1,
                     pos,
                     [TimeFormat::default(); 2]
                 )
@@ -579,6 +720,7 @@ mod tests {
                         }),
                         ..Default::default()
                     }],
+                    1,
                     pos,
                     [TimeFormat::default(); 2]
                 )
@@ -587,4 +729,85 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn normalize_move_drop() {
+        // The gold on 6i can also move to 5h
+        let jkf = crate::parser::parse_usi_str("position sfen 4k4/9/9/9/9/9/9/9/3GK4 b G 1")
+            .expect("failed to parse usi");
+        let pos = shogi_core::Position::try_from(&jkf).expect("failed to convert");
+        let mut mmf = MoveMoveFormat {
+            color: Color::Black,
+            piece: Kind::KI,
+            from: None,
+            to: PlaceFormat { x: 5, y: 8 },
+            promote: None,
+            capture: None,
+            relative: Some(Relative::H),
+            same: None,
+        };
+        normalize_move(&mut mmf, pos.inner()).expect("normalize should succeed");
+        assert_eq!(None, mmf.from, "the move should be kept as a drop");
+    }
+
+    #[test]
+    fn normalize_append_tsumi() {
+        let mut jkf = crate::parser::parse_usi_str(
+            "position sfen 4k4/9/p3P4/9/9/9/9/9/4K4 b G 1 moves 5i4i 9c9d G*5b",
+        )
+        .expect("failed to parse usi");
+        // A variation which doesn't checkmate
+        let mut fork = jkf.moves[3].clone();
+        fork.move_.as_mut().unwrap().to = PlaceFormat { x: 5, y: 4 };
+        jkf.moves[3].forks = Some(vec![vec![fork]]);
+        jkf.normalize().expect("failed to normalize");
+        assert_eq!(4, jkf.moves.len());
+
+        let options = NormalizeOptions {
+            append_tsumi: true,
+            ..Default::default()
+        };
+        jkf.normalize_with_options(&options)
+            .expect("failed to normalize");
+        assert_eq!(5, jkf.moves.len());
+        assert_eq!(Some(MoveSpecial::SpecialTsumi), jkf.moves[4].special);
+        assert_eq!(1, jkf.moves[3].forks.as_ref().unwrap()[0].len());
+        // Not appended twice
+        jkf.normalize_with_options(&options)
+            .expect("failed to normalize");
+        assert_eq!(5, jkf.moves.len());
+    }
+
+    #[test]
+    fn normalize_annotate_checks() {
+        let mut jkf = crate::parser::parse_usi_str(
+            "position sfen 4k4/9/p3P4/9/9/9/9/9/4K4 b G 1 moves 5i4i 9c9d G*4b",
+        )
+        .expect("failed to parse usi");
+        // A variation which checkmates
+        let mut fork = jkf.moves[3].clone();
+        fork.move_.as_mut().unwrap().to = PlaceFormat { x: 5, y: 2 };
+        jkf.moves[3].forks = Some(vec![vec![fork]]);
+        jkf.moves[3].comments = Some(vec![String::from("好手")]);
+
+        let options = NormalizeOptions {
+            annotate_checks: true,
+            ..Default::default()
+        };
+        jkf.normalize_with_options(&options)
+            .expect("failed to normalize");
+        let comments = |mf: &MoveFormat| mf.comments.clone().unwrap_or_default();
+        assert!(comments(&jkf.moves[2]).is_empty());
+        assert_eq!(vec!["王手", "好手"], comments(&jkf.moves[3]));
+        let fork = &jkf.moves[3].forks.as_ref().unwrap()[0];
+        assert_eq!(vec!["王手"], comments(&fork[0]));
+        // `TSUMI` is appended only by `append_tsumi`
+        assert_eq!(1, fork.len());
+        assert_eq!(4, jkf.moves.len());
+        // Not annotated twice
+        let annotated = jkf.clone();
+        jkf.normalize_with_options(&options)
+            .expect("failed to normalize");
+        assert_eq!(annotated, jkf);
+    }
 }
@@ -1,142 +1,316 @@
 //! Parsers for [`jkf::JsonKifuFormat`](crate::jkf::JsonKifuFormat)
 
+mod csa;
+mod encoding;
+mod error;
+mod format;
 mod kakinoki;
 mod ki2;
 mod kif;
+mod recover;
+mod usi;
 
-use crate::error::ParseError;
+use self::csa::{is_variation_record, merge_variation, split_records, unknown_attributes};
+use self::recover::parse_with_options;
+use crate::error::{LocatedNormalizeError, ParseError, SyntaxError};
 use crate::jkf::JsonKifuFormat;
-use encoding_rs::{SHIFT_JIS, UTF_8};
-use nom::error::convert_error;
 use nom::Finish;
+use std::borrow::Cow;
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::BufReader;
 use std::path::Path;
 
+/// Options for parsing kifu strings
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// If `true`, the lines which cannot be parsed are skipped and the moves from an illegal one are truncated,
+    /// and they are reported as [`Diagnostic`]s instead of errors.
+    pub recover: bool,
+}
+
+/// Formats of kifu data detected by [`parse_any`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Format {
+    /// CSA format
+    Csa,
+    /// KIF format
+    Kif,
+    /// KI2 format
+    Ki2,
+    /// JSON Kifu Format
+    Jkf,
+    /// `position` command of USI
+    Usi,
+    /// SFEN string of a position
+    Sfen,
+}
+
+/// A warning reported while parsing kifu strings
+#[derive(Debug, PartialEq)]
+pub enum Diagnostic {
+    /// The header was ignored since it is unknown.
+    /// This is reported only for CSA, since the headers of KIF and KI2 are kept whatever their keys are.
+    UnknownHeader {
+        /// Line number, starting from 1
+        line: usize,
+        /// The key of the header
+        key: String,
+    },
+    /// The line was skipped since it contains an unknown special move
+    UnknownSpecialMove(SyntaxError),
+    /// The line was skipped since it could not be parsed
+    SkippedLine(SyntaxError),
+    /// The moves from the illegal move were truncated
+    TruncatedMoves(LocatedNormalizeError),
+}
+
+/// Decodes the bytes of a kifu file to a string
+///
+/// The encoding is detected from the contents in the following order:
+///
+/// 1. BOM
+/// 2. Declaration in the first lines, such as `#KIF version=2.0 encoding=UTF-8` in KIF/KI2 or `'encoding=UTF-8` in CSA
+/// 3. UTF-8 if the bytes are valid as UTF-8, otherwise Shift-JIS
+///
+/// # Errors
+///
+/// This function returns [`ParseError::Decode`] if the bytes are malformed in the detected encoding.
+pub fn decode(bytes: &[u8]) -> Result<Cow<'_, str>, ParseError> {
+    let (encoding, bom_len) = encoding::detect(bytes);
+    encoding
+        .decode_without_bom_handling_and_without_replacement(&bytes[bom_len..])
+        .ok_or(ParseError::Decode)
+}
+
 /// Parses a CSA file to [`jkf::JsonKifuFormat`](crate::jkf::JsonKifuFormat)
 ///
+/// The encoding is detected from the contents. See [`decode`].
+///
 /// # Errors
 ///
-/// This function returns [`ConvertError`](crate::error::ConvertError) if it fails to parse the file.
+/// This function returns [`ParseError`] if it fails to parse the file.
 pub fn parse_csa_file<P: AsRef<Path>>(path: P) -> Result<JsonKifuFormat, ParseError> {
-    let mut file = File::open(&path)?;
-    let mut buf = String::new();
-    file.read_to_string(&mut buf)?;
-    parse_csa_str(&buf)
+    parse_csa_bytes(&std::fs::read(path)?)
+}
+
+/// Parses the bytes of CSA formatted data to [`jkf::JsonKifuFormat`](crate::jkf::JsonKifuFormat)
+///
+/// The encoding is detected from the contents. See [`decode`].
+///
+/// # Errors
+///
+/// This function returns [`ParseError`] if it fails to decode or parse the bytes.
+pub fn parse_csa_bytes(bytes: &[u8]) -> Result<JsonKifuFormat, ParseError> {
+    parse_csa_str(&decode(bytes)?)
 }
 
 /// Parses a CSA formatted string to [`jkf::JsonKifuFormat`](crate::jkf::JsonKifuFormat)
 ///
+/// If the string contains multiple game records separated by `/` lines,
+/// the variation records written by [`ToCsa`](crate::converter::ToCsa) are merged into the first record as forks,
+/// and the other records are ignored.
+///
 /// # Errors
 ///
-/// This function returns [`ConvertError`](crate::error::ConvertError) if it fails to parse the string.
+/// This function returns [`ParseError`] if it fails to parse the string.
 pub fn parse_csa_str(s: &str) -> Result<JsonKifuFormat, ParseError> {
-    let mut jkf = JsonKifuFormat::try_from(csa::parse_csa(s)?)?;
-    if let Err(err) = jkf.normalize() {
-        Err(ParseError::Normalize(err.to_string()))
-    } else {
+    parse_csa_str_with_options(s, &ParseOptions::default()).map(|(jkf, _)| jkf)
+}
+
+/// Parses a CSA formatted string to [`jkf::JsonKifuFormat`](crate::jkf::JsonKifuFormat) with [`ParseOptions`]
+///
+/// The ignored attributes such as `$FOO:...` are reported as [`Diagnostic::UnknownHeader`].
+///
+/// # Errors
+///
+/// This function returns [`ParseError`] if it fails to parse the string.
+pub fn parse_csa_str_with_options(
+    s: &str,
+    options: &ParseOptions,
+) -> Result<(JsonKifuFormat, Vec<Diagnostic>), ParseError> {
+    let parse = |input: &str| {
+        let parse_record = |record| {
+            csa::parse(record)
+                .finish()
+                .map(|(_, jkf)| jkf)
+                .map_err(|err| err.into_syntax_error(input))
+        };
+        let records = split_records(input);
+        let mut jkf = parse_record(records[0])?;
+        for record in records[1..].iter().filter(|r| is_variation_record(r)) {
+            merge_variation(&mut jkf.moves, parse_record(record)?.moves);
+        }
         Ok(jkf)
-    }
+    };
+    let skipped = |err: SyntaxError| {
+        if err.line_text.starts_with('%') {
+            Diagnostic::UnknownSpecialMove(err)
+        } else {
+            Diagnostic::SkippedLine(err)
+        }
+    };
+    let broken = |input: &str| {
+        split_records(input)
+            .into_iter()
+            .enumerate()
+            .filter(|(i, record)| *i == 0 || is_variation_record(record))
+            .flat_map(|(_, record)| csa::broken_move_lines(record))
+            .map(|err| err.into_syntax_error(input))
+            .collect()
+    };
+    let (jkf, diagnostics) =
+        parse_with_options(s, options, parse, broken, ParseError::Csa, skipped)?;
+    let headers = unknown_attributes(s)
+        .into_iter()
+        .map(|(line, key)| Diagnostic::UnknownHeader {
+            line,
+            key: String::from(key),
+        });
+    Ok((jkf, headers.chain(diagnostics).collect()))
 }
 
 /// Parses a KIF file to [`jkf::JsonKifuFormat`](crate::jkf::JsonKifuFormat)
 ///
-/// If the file extension is `.kif`, it is decoded as Shift-JIS, and if it is `.kifu`, it is decoded as UTF-8 and parsed.
+/// The encoding is detected from the contents regardless of the file extension,
+/// so both `.kif` (Shift-JIS) and `.kifu` (UTF-8) files can be parsed. See [`decode`].
 ///
 /// See: [http://kakinoki.o.oo7.jp/kif_format.html](http://kakinoki.o.oo7.jp/kif_format.html)
 ///
 /// # Errors
 ///
-/// This function returns [`ConvertError`](crate::error::ConvertError) if it fails to parse the file.
+/// This function returns [`ParseError`] if it fails to parse the file.
 pub fn parse_kif_file<P: AsRef<Path>>(path: P) -> Result<JsonKifuFormat, ParseError> {
-    let mut file = File::open(&path)?;
-    let ext = path.as_ref().extension().ok_or(ParseError::FileExtension)?;
-    let encoding = match ext.to_str() {
-        Some("kif") => SHIFT_JIS,
-        Some("kifu") => UTF_8,
-        _ => return Err(ParseError::FileExtension),
-    };
-    let mut buf = Vec::new();
-    file.read_to_end(&mut buf)?;
-    let (cow, _, had_errors) = encoding.decode(&buf);
-    if had_errors {
-        return Err(ParseError::Decode);
-    }
-    parse_kif_str(&cow)
+    parse_kif_bytes(&std::fs::read(path)?)
+}
+
+/// Parses the bytes of KIF formatted data to [`jkf::JsonKifuFormat`](crate::jkf::JsonKifuFormat)
+///
+/// The encoding is detected from the contents. See [`decode`].
+///
+/// # Errors
+///
+/// This function returns [`ParseError`] if it fails to decode or parse the bytes.
+pub fn parse_kif_bytes(bytes: &[u8]) -> Result<JsonKifuFormat, ParseError> {
+    parse_kif_str(&decode(bytes)?)
 }
 
 /// Parses a KIF formatted string to [`jkf::JsonKifuFormat`](crate::jkf::JsonKifuFormat)
 ///
 /// # Errors
 ///
-/// This function returns [`ConvertError`](crate::error::ConvertError) if it fails to parse the string.
+/// This function returns [`ParseError`] if it fails to parse the string.
 pub fn parse_kif_str(s: &str) -> Result<JsonKifuFormat, ParseError> {
-    match kif::parse(s).finish() {
-        Ok((_, mut jkf)) => {
-            if let Err(err) = jkf.normalize() {
-                Err(ParseError::Normalize(err.to_string()))
-            } else {
-                Ok(jkf)
-            }
+    parse_kif_str_with_options(s, &ParseOptions::default()).map(|(jkf, _)| jkf)
+}
+
+/// Parses a KIF formatted string to [`jkf::JsonKifuFormat`](crate::jkf::JsonKifuFormat) with [`ParseOptions`]
+///
+/// # Errors
+///
+/// This function returns [`ParseError`] if it fails to parse the string.
+pub fn parse_kif_str_with_options(
+    s: &str,
+    options: &ParseOptions,
+) -> Result<(JsonKifuFormat, Vec<Diagnostic>), ParseError> {
+    let parse = |input: &str| {
+        kif::parse(input)
+            .finish()
+            .map(|(_, jkf)| jkf)
+            .map_err(|err| err.into_syntax_error(input))
+    };
+    let skipped = |err: SyntaxError| {
+        // Neither a move nor a known special move follows the move number
+        if err.expected.iter().any(|s| s == "special move") {
+            Diagnostic::UnknownSpecialMove(err)
+        } else {
+            Diagnostic::SkippedLine(err)
         }
-        Err(err) => Err(ParseError::Kif(convert_error(s, err))),
-    }
+    };
+    let broken = |input: &str| {
+        kif::broken_move_lines(input)
+            .into_iter()
+            .map(|err| err.into_syntax_error(input))
+            .collect()
+    };
+    parse_with_options(s, options, parse, broken, ParseError::Kif, skipped)
 }
 
 /// Parses a KI2 file to [`jkf::JsonKifuFormat`](crate::jkf::JsonKifuFormat)
 ///
-/// If the file extension is `.ki2`, it is decoded as Shift-JIS, and if it is `.ki2u`, it is decoded as UTF-8 and parsed.
+/// The encoding is detected from the contents regardless of the file extension,
+/// so both `.ki2` (Shift-JIS) and `.ki2u` (UTF-8) files can be parsed. See [`decode`].
 ///
 /// See: [http://kakinoki.o.oo7.jp/KifuwInt.htm](http://kakinoki.o.oo7.jp/KifuwInt.htm)
 ///
 /// # Errors
 ///
-/// This function returns [`ConvertError`](crate::error::ConvertError) if it fails to parse the file.
+/// This function returns [`ParseError`] if it fails to parse the file.
 pub fn parse_ki2_file<P: AsRef<Path>>(path: P) -> Result<JsonKifuFormat, ParseError> {
-    let mut file = File::open(&path)?;
-    let ext = path.as_ref().extension().ok_or(ParseError::FileExtension)?;
-    let encoding = match ext.to_str() {
-        Some("ki2") => SHIFT_JIS,
-        Some("ki2u") => UTF_8,
-        _ => return Err(ParseError::FileExtension),
-    };
-    let mut buf = Vec::new();
-    file.read_to_end(&mut buf)?;
-    let (cow, _, had_errors) = encoding.decode(&buf);
-    if had_errors {
-        return Err(ParseError::Decode);
-    }
-    parse_ki2_str(&cow)
+    parse_ki2_bytes(&std::fs::read(path)?)
+}
+
+/// Parses the bytes of KI2 formatted data to [`jkf::JsonKifuFormat`](crate::jkf::JsonKifuFormat)
+///
+/// The encoding is detected from the contents. See [`decode`].
+///
+/// # Errors
+///
+/// This function returns [`ParseError`] if it fails to decode or parse the bytes.
+pub fn parse_ki2_bytes(bytes: &[u8]) -> Result<JsonKifuFormat, ParseError> {
+    parse_ki2_str(&decode(bytes)?)
 }
 
 /// Parses a KI2 formatted string to [`jkf::JsonKifuFormat`](crate::jkf::JsonKifuFormat)
 ///
 /// # Errors
 ///
-/// This function returns [`ConvertError`](crate::error::ConvertError) if it fails to parse the string.
+/// This function returns [`ParseError`] if it fails to parse the string.
 pub fn parse_ki2_str(s: &str) -> Result<JsonKifuFormat, ParseError> {
-    match ki2::parse(s).finish() {
-        Ok((_, mut jkf)) => {
-            if let Err(err) = jkf.normalize() {
-                Err(ParseError::Normalize(err.to_string()))
-            } else {
-                Ok(jkf)
-            }
-        }
-        Err(err) => Err(ParseError::Ki2(convert_error(s, err))),
-    }
+    parse_ki2_str_with_options(s, &ParseOptions::default()).map(|(jkf, _)| jkf)
+}
+
+/// Parses a KI2 formatted string to [`jkf::JsonKifuFormat`](crate::jkf::JsonKifuFormat) with [`ParseOptions`]
+///
+/// # Errors
+///
+/// This function returns [`ParseError`] if it fails to parse the string.
+pub fn parse_ki2_str_with_options(
+    s: &str,
+    options: &ParseOptions,
+) -> Result<(JsonKifuFormat, Vec<Diagnostic>), ParseError> {
+    let parse = |input: &str| {
+        ki2::parse(input)
+            .finish()
+            .map(|(_, jkf)| jkf)
+            .map_err(|err| err.into_syntax_error(input))
+    };
+    let broken = |input: &str| {
+        ki2::broken_move_lines(input)
+            .into_iter()
+            .map(|err| err.into_syntax_error(input))
+            .collect()
+    };
+    parse_with_options(
+        s,
+        options,
+        parse,
+        broken,
+        ParseError::Ki2,
+        Diagnostic::SkippedLine,
+    )
 }
 
 /// Parses a JSON file to [`jkf::JsonKifuFormat`](crate::jkf::JsonKifuFormat)
 ///
 /// # Errors
 ///
-/// This function returns [`ConvertError`](crate::error::ConvertError) if it fails to parse the file.
+/// This function returns [`ParseError`] if it fails to parse the file.
 pub fn parse_jkf_file<P: AsRef<Path>>(path: P) -> Result<JsonKifuFormat, ParseError> {
     let file = File::open(&path)?;
     let mut jkf = serde_json::from_reader::<_, JsonKifuFormat>(BufReader::new(file))?;
     if let Err(err) = jkf.normalize() {
-        Err(ParseError::Normalize(err.to_string()))
+        Err(ParseError::Normalize(err))
     } else {
         Ok(jkf)
     }
@@ -146,20 +320,96 @@ pub fn parse_jkf_file<P: AsRef<Path>>(path: P) -> Result<JsonKifuFormat, ParseEr
 ///
 /// # Errors
 ///
-/// This function returns [`ConvertError`](crate::error::ConvertError) if it fails to parse the file.
+/// This function returns [`ParseError`] if it fails to parse the file.
 pub fn parse_jkf_str(s: &str) -> Result<JsonKifuFormat, ParseError> {
     let mut jkf = serde_json::from_str::<JsonKifuFormat>(s)?;
     if let Err(err) = jkf.normalize() {
-        Err(ParseError::Normalize(err.to_string()))
+        Err(ParseError::Normalize(err))
     } else {
         Ok(jkf)
     }
 }
 
+/// Parses a `position` command of USI to [`jkf::JsonKifuFormat`](crate::jkf::JsonKifuFormat)
+///
+/// The `position` keyword can be omitted, such as `startpos moves 7g7f 3c3d` written by [`ToUsi`](shogi_core::ToUsi).
+/// The handicap presets are recognized from the position.
+///
+/// # Errors
+///
+/// This function returns [`ParseError`] if it fails to parse the string or to make the moves.
+pub fn parse_usi_str(s: &str) -> Result<JsonKifuFormat, ParseError> {
+    let (pos, moves) = usi::position(s)
+        .finish()
+        .map(|(_, ret)| ret)
+        .map_err(|err| ParseError::Usi(err.into_syntax_error(s)))?;
+    Ok(usi::into_jkf(pos, moves)?)
+}
+
+/// Parses a SFEN string to [`jkf::JsonKifuFormat`](crate::jkf::JsonKifuFormat) without moves
+///
+/// The handicap presets are recognized from the position. The move number is ignored.
+///
+/// # Errors
+///
+/// This function returns [`ParseError`] if it fails to parse the string.
+pub fn parse_sfen_str(s: &str) -> Result<JsonKifuFormat, ParseError> {
+    let pos = usi::standalone_sfen(s)
+        .finish()
+        .map(|(_, pos)| pos)
+        .map_err(|err| ParseError::Usi(err.into_syntax_error(s)))?;
+    Ok(usi::into_jkf(pos, Vec::new())?)
+}
+
+/// Guesses the format of a kifu string from its contents
+///
+/// Returns `None` if it doesn't look like any of the supported formats.
+pub fn detect_format(s: &str) -> Option<Format> {
+    format::detect(s)
+}
+
+/// Parses a kifu file of any supported format to [`jkf::JsonKifuFormat`](crate::jkf::JsonKifuFormat)
+///
+/// The encoding and the format are detected from the contents regardless of the file name.
+/// See [`decode`] and [`detect_format`].
+///
+/// # Errors
+///
+/// This function returns [`ParseError`] if it fails to detect the format or to parse the file.
+pub fn parse_any_file<P: AsRef<Path>>(path: P) -> Result<(Format, JsonKifuFormat), ParseError> {
+    parse_any(&std::fs::read(path)?)
+}
+
+/// Parses the bytes of kifu data of any supported format to [`jkf::JsonKifuFormat`](crate::jkf::JsonKifuFormat)
+///
+/// Returns the detected format together with the parsed data.
+///
+/// # Errors
+///
+/// This function returns [`ParseError::UnknownFormat`] if it fails to detect the format,
+/// or [`ParseError`] if it fails to parse the bytes.
+pub fn parse_any(bytes: &[u8]) -> Result<(Format, JsonKifuFormat), ParseError> {
+    let s = decode(bytes)?;
+    let format = detect_format(&s).ok_or(ParseError::UnknownFormat)?;
+    let jkf = match format {
+        Format::Csa => parse_csa_str(&s)?,
+        Format::Kif => parse_kif_str(&s)?,
+        Format::Ki2 => parse_ki2_str(&s)?,
+        Format::Jkf => parse_jkf_str(&s)?,
+        Format::Usi => parse_usi_str(&s)?,
+        Format::Sfen => parse_sfen_str(&s)?,
+    };
+    Ok((format, jkf))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::converter::{CsaOptions, ToCsa, ToKi2, ToKif};
+    use crate::error::{LocatedNormalizeError, NormalizeError, SyntaxError};
+    use crate::jkf::{Evaluation, Initial, MoveFormat, MoveSpecial, Preset};
     use serde_json::Value;
+    use shogi_core::ToUsi;
     use std::ffi::OsStr;
     use std::io::Result;
 
@@ -179,23 +429,490 @@ mod tests {
             // Load exptected JSON
             assert!(path.set_extension("json"));
             let file = File::open(&path)?;
-            let mut expected = serde_json::from_reader::<_, JsonKifuFormat>(BufReader::new(file))
+            let expected = serde_json::from_reader::<_, JsonKifuFormat>(BufReader::new(file))
                 .expect("failed to parse json");
-            // Remove all move comments (they cannot be restored from csa...)
-            expected.moves.iter_mut().for_each(|m| m.comments = None);
 
             assert_eq!(expected, jkf, "different from expected: {}", path.display());
         }
         Ok(())
     }
 
+    #[test]
+    fn csa_round_trip() -> Result<()> {
+        for entry in Path::new("data/tests/csa").read_dir()? {
+            let path = entry?.path();
+            if path.extension() != Some(OsStr::new("csa")) {
+                continue;
+            }
+            let jkf = parse_csa_file(&path).expect("failed to parse csa");
+            let parsed = parse_csa_str(&jkf.to_csa_owned()).expect("failed to parse csa");
+            assert_eq!(jkf.header, parsed.header, "{}", path.display());
+            assert_eq!(jkf.moves, parsed.moves, "{}", path.display());
+        }
+        // Evaluations by floodgate
+        let jkf = parse_csa_file("data/tests/csa/floodgate.csa").expect("failed to parse csa");
+        assert_eq!(None, jkf.moves[0].evaluation());
+        assert_eq!(
+            Some(Evaluation {
+                score: 113,
+                pv: vec![String::from("-3334FU"), String::from("+2625FU")],
+            }),
+            jkf.moves[1].evaluation().map(|mut e| {
+                e.pv.truncate(2);
+                e
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn csa_forks() {
+        let jkf = parse_jkf_file("data/tests/kif/forks.json").expect("failed to parse json");
+        let csa = jkf.to_csa_owned_with_options(&CsaOptions { forks: true });
+        let parsed = parse_csa_str(&csa).expect("failed to parse csa");
+        assert_eq!(jkf.moves, parsed.moves);
+        // Variations are ignored without the option
+        let csa = jkf.to_csa_owned();
+        let parsed = parse_csa_str(&csa).expect("failed to parse csa");
+        assert!(parsed.moves.iter().all(|mf| mf.forks.is_none()));
+    }
+
+    #[test]
+    fn handicap_presets() {
+        for preset in [
+            Preset::PresetHirate,
+            Preset::PresetKY,
+            Preset::PresetKYR,
+            Preset::PresetKA,
+            Preset::PresetHI,
+            Preset::PresetHIKY,
+            Preset::Preset2,
+            Preset::Preset3,
+            Preset::Preset4,
+            Preset::Preset5,
+            Preset::Preset5L,
+            Preset::Preset6,
+            Preset::Preset7L,
+            Preset::Preset7R,
+            Preset::Preset8,
+            Preset::Preset10,
+        ] {
+            let jkf = JsonKifuFormat {
+                initial: Some(Initial { preset, data: None }),
+                ..Default::default()
+            };
+            assert!(shogi_core::PartialPosition::try_from(&jkf.initial.unwrap()).is_ok());
+            // The board written in CSA is detected as the preset
+            let parsed = parse_csa_str(&jkf.to_csa_owned()).expect("failed to parse csa");
+            assert_eq!(jkf.initial, parsed.initial, "{preset:?}");
+            let parsed = parse_kif_str(&jkf.to_kif_owned()).expect("failed to parse kif");
+            assert_eq!(jkf.initial, parsed.initial, "{preset:?}");
+            let parsed = parse_ki2_str(&jkf.to_ki2_owned()).expect("failed to parse ki2");
+            assert_eq!(jkf.initial, parsed.initial, "{preset:?}");
+            let parsed = parse_usi_str(&jkf.to_usi_owned()).expect("failed to parse usi");
+            assert_eq!(jkf.initial, parsed.initial, "{preset:?}");
+        }
+        // `OTHER` without the board data cannot be written, instead of being written as 平手
+        let jkf = JsonKifuFormat {
+            initial: Some(Initial {
+                preset: Preset::PresetOther,
+                data: None,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(Err(std::fmt::Error), jkf.to_csa(&mut String::new()));
+        assert_eq!(Err(std::fmt::Error), jkf.to_kif(&mut String::new()));
+        assert_eq!(Err(std::fmt::Error), jkf.to_ki2(&mut String::new()));
+        assert_eq!(Err(std::fmt::Error), jkf.try_to_csa_owned());
+        assert_eq!(Err(std::fmt::Error), jkf.try_to_kif_owned());
+        assert_eq!(Err(std::fmt::Error), jkf.try_to_ki2_owned());
+        // The owned strings don't panic, and such a kifu is rejected by the normalizer
+        assert!(!jkf.to_csa_owned().contains("PI"));
+        assert!(!jkf.to_kif_owned().is_empty());
+        assert!(!jkf.to_ki2_owned().is_empty());
+        assert!(jkf.clone().normalize().is_err());
+    }
+
+    #[test]
+    fn kif_specials() -> std::result::Result<(), ParseError> {
+        let specials = [
+            MoveSpecial::SpecialToryo,
+            MoveSpecial::SpecialChudan,
+            MoveSpecial::SpecialSennichite,
+            MoveSpecial::SpecialTimeUp,
+            MoveSpecial::SpecialIllegalMove,
+            MoveSpecial::SpecialIllegalActionBlack,
+            MoveSpecial::SpecialIllegalActionWhite,
+            MoveSpecial::SpecialJishogi,
+            MoveSpecial::SpecialKachi,
+            MoveSpecial::SpecialHikiwake,
+            MoveSpecial::SpecialMatta,
+            MoveSpecial::SpecialTsumi,
+            MoveSpecial::SpecialFuzumi,
+            MoveSpecial::SpecialError,
+        ];
+        // Black moves first with 平手, and White moves first with 香落ち
+        for preset in [Preset::PresetHirate, Preset::PresetKY] {
+            for special in specials {
+                let jkf = JsonKifuFormat {
+                    initial: Some(Initial { preset, data: None }),
+                    moves: vec![
+                        MoveFormat::default(),
+                        MoveFormat {
+                            special: Some(special),
+                            comments: Some(vec![String::from("comment")]),
+                            ..Default::default()
+                        },
+                    ],
+                    ..Default::default()
+                };
+                let kif = jkf.to_kif_owned();
+                assert_eq!(jkf.moves, parse_kif_str(&kif)?.moves, "{kif}");
+            }
+        }
+        // 反則勝ち depends on the side to move
+        let kif = "手合割：平手\n手数----指手---------消費時間--\n   1 ７六歩(77)\n   2 反則勝ち\n";
+        assert_eq!(
+            Some(MoveSpecial::SpecialIllegalActionBlack),
+            parse_kif_str(kif)?.moves[2].special
+        );
+        let kif =
+            "手合割：香落ち\n手数----指手---------消費時間--\n   1 ３四歩(33)\n   2 反則勝ち\n";
+        assert_eq!(
+            Some(MoveSpecial::SpecialIllegalActionWhite),
+            parse_kif_str(kif)?.moves[2].special
+        );
+        let kif = "手数----指手---------消費時間--\n   1 不詰\n";
+        assert_eq!(
+            Some(MoveSpecial::SpecialFuzumi),
+            parse_kif_str(kif)?.moves[1].special
+        );
+        // The `&` line is kept as a comment unless it annotates 中断
+        let kif =
+            "手合割：平手\n手数----指手---------消費時間--\n   1 ７六歩(77)\n   2 投了\n&TIME_UP\n";
+        let jkf = parse_kif_str(kif)?;
+        assert_eq!(Some(MoveSpecial::SpecialToryo), jkf.moves[2].special);
+        assert_eq!(Some(vec![String::from("&TIME_UP")]), jkf.moves[2].comments);
+        let kif = "手合割：平手\n手数----指手---------消費時間--\n   1 中断\n&しおり\n";
+        let jkf = parse_kif_str(kif)?;
+        assert_eq!(Some(MoveSpecial::SpecialChudan), jkf.moves[1].special);
+        assert_eq!(Some(vec![String::from("&しおり")]), jkf.moves[1].comments);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_usi() -> std::result::Result<(), ParseError> {
+        let jkf = parse_kif_file("data/tests/kif/forks.kif")?;
+        let usi = jkf.to_usi_owned();
+        let parsed = parse_usi_str(&format!("position {usi}\n"))?;
+        assert_eq!(usi, parsed.to_usi_owned());
+        assert_eq!(jkf.moves.len(), parsed.moves.len());
+        assert_eq!(jkf.moves[1].move_, parsed.moves[1].move_);
+
+        // Drops and promotions from a SFEN position
+        let parsed = parse_usi_str("sfen 8k/9/9/9/9/9/9/9/K8 w 2Pb 1 moves B*5e 9i8h 5e9i+")?;
+        assert_eq!(Some(Preset::PresetOther), parsed.initial.map(|i| i.preset));
+        assert_eq!(
+            "sfen 8k/9/9/9/9/9/9/9/K8 w 2Pb 1 moves B*5e 9i8h 5e9i+",
+            parsed.to_usi_owned()
+        );
+
+        assert!(matches!(
+            parse_usi_str("position startpos moves 7g7f 7f7g"),
+            Err(ParseError::Normalize(LocatedNormalizeError { ply: 2, .. }))
+        ));
+        assert!(matches!(
+            parse_usi_str("position startpos moves 7g7f foo"),
+            Err(ParseError::Usi(SyntaxError { column: 30, .. }))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_sfen() -> std::result::Result<(), ParseError> {
+        let parsed =
+            parse_sfen_str("lnsgkgsn1/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL w - 1\n")?;
+        assert_eq!(
+            Some(Initial {
+                preset: Preset::PresetKY,
+                data: None
+            }),
+            parsed.initial
+        );
+        assert_eq!(1, parsed.moves.len());
+        assert!(matches!(
+            parse_sfen_str("lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL x - 1"),
+            Err(ParseError::Usi(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn ki2_forks() {
+        // KI2 has neither times nor special moves
+        fn strip(moves: &mut Vec<MoveFormat>) {
+            moves.retain(|mf| mf.special.is_none());
+            for mf in moves.iter_mut() {
+                mf.time = None;
+                mf.forks.iter_mut().flatten().for_each(strip);
+            }
+        }
+        let mut jkf = parse_jkf_file("data/tests/kif/forks.json").expect("failed to parse json");
+        strip(&mut jkf.moves);
+        let ki2 = jkf.to_ki2_owned();
+        let parsed = parse_ki2_str(&ki2).expect("failed to parse ki2");
+        assert_eq!(jkf.moves, parsed.moves);
+    }
+
+    #[test]
+    fn parse_bytes() {
+        let csa = "N+先手\nN-後手\nPI\n+\n+7776FU\n";
+        let expected = parse_csa_str(csa).expect("failed to parse csa");
+        let (sjis, _, _) = encoding_rs::SHIFT_JIS.encode(csa);
+        assert_eq!(expected, parse_csa_bytes(&sjis).expect("failed to parse"));
+        let bom = [b"\xEF\xBB\xBF", csa.as_bytes()].concat();
+        assert_eq!(expected, parse_csa_bytes(&bom).expect("failed to parse"));
+        // The declaration takes precedence over the bytes valid as UTF-8
+        let declared = [b"'encoding=Shift_JIS\n", csa.as_bytes()].concat();
+        let jkf = parse_csa_bytes(&declared).expect("failed to parse");
+        assert_eq!(Some("蜈域焔"), jkf.header.get("先手").map(String::as_str));
+        assert!(matches!(parse_csa_bytes(b"\xFF"), Err(ParseError::Decode)));
+    }
+
+    #[test]
+    fn parse_any_files() -> Result<()> {
+        for dir in ["data/tests/csa", "data/tests/kif", "data/tests/ki2"] {
+            for entry in Path::new(dir).read_dir()? {
+                let path = entry?.path();
+                let expected = match path.extension().and_then(OsStr::to_str) {
+                    Some("csa") => Format::Csa,
+                    Some("kif" | "kifu") => Format::Kif,
+                    Some("ki2" | "ki2u") => Format::Ki2,
+                    Some("json") => Format::Jkf,
+                    _ => continue,
+                };
+                match parse_any_file(&path) {
+                    Ok((format, _)) => assert_eq!(expected, format, "{}", path.display()),
+                    Err(err) => panic!("failed to parse file {}: {err}", path.display()),
+                }
+            }
+        }
+        assert!(matches!(
+            parse_any(b"foo\nbar\n"),
+            Err(ParseError::UnknownFormat)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn csa_syntax_error() {
+        let s = "PI\n+\n+7776FU\nT1\n-3334XX\n";
+        match parse_csa_str(s) {
+            Err(ParseError::Csa(err)) => assert_eq!(
+                SyntaxError {
+                    offset: s.find("XX").unwrap(),
+                    line: 5,
+                    column: 6,
+                    line_text: String::from("-3334XX"),
+                    expected: vec![String::from("piece")],
+                },
+                err
+            ),
+            result => panic!("unexpected result: {result:?}"),
+        }
+        // The position is reported in the entire string, even in a variation record
+        let s = "PI\n+\n+7776FU\n/\n'変化：1手\nPI\n+\n+2726FU\nfoo\n";
+        match parse_csa_str(s) {
+            Err(ParseError::Csa(err)) => assert_eq!(
+                SyntaxError {
+                    offset: s.find("foo").unwrap(),
+                    line: 9,
+                    column: 1,
+                    line_text: String::from("foo"),
+                    expected: vec![String::from("move"), String::from("special move")],
+                },
+                err
+            ),
+            result => panic!("unexpected result: {result:?}"),
+        }
+    }
+
+    #[test]
+    fn kif_syntax_error() {
+        let s = "手数----指手---------消費時間--\n   1 ７六歩(77)\n   2 ３四Ｘ(33)\n";
+        match parse_kif_str(s) {
+            Err(ParseError::Kif(err)) => assert_eq!(
+                SyntaxError {
+                    offset: s.find('Ｘ').unwrap(),
+                    line: 3,
+                    column: 8,
+                    line_text: String::from("   2 ３四Ｘ(33)"),
+                    expected: vec![String::from("piece")],
+                },
+                err
+            ),
+            result => panic!("unexpected result: {result:?}"),
+        }
+        // The variation branches from a ply beyond the main line
+        let s = "手数----指手---------消費時間--\n   1 ７六歩(77)\n   2 ３四歩(33)\n\n変化：99手\n  99 ２六歩(27)\n";
+        match parse_kif_str(s) {
+            Err(ParseError::Kif(err)) => {
+                assert_eq!((5, String::from("変化：99手")), (err.line, err.line_text))
+            }
+            result => panic!("unexpected result: {result:?}"),
+        }
+    }
+
+    #[test]
+    fn ki2_syntax_error() {
+        let s = "▲７六歩 △３Ｘ歩\n";
+        match parse_ki2_str(s) {
+            Err(ParseError::Ki2(err)) => assert_eq!(
+                SyntaxError {
+                    offset: s.find('Ｘ').unwrap(),
+                    line: 1,
+                    column: 8,
+                    line_text: String::from("▲７六歩 △３Ｘ歩"),
+                    expected: vec![String::from("rank")],
+                },
+                err
+            ),
+            result => panic!("unexpected result: {result:?}"),
+        }
+        // The variation branches from a ply beyond the main line
+        let s = "▲７六歩 △３四歩\n\n変化：99手\n▲２六歩\n";
+        match parse_ki2_str(s) {
+            Err(ParseError::Ki2(err)) => assert_eq!(
+                SyntaxError {
+                    offset: s.find('変').unwrap(),
+                    line: 3,
+                    column: 1,
+                    line_text: String::from("変化：99手"),
+                    expected: vec![String::from("ply of the variation")],
+                },
+                err
+            ),
+            result => panic!("unexpected result: {result:?}"),
+        }
+    }
+
+    #[test]
+    fn normalize_error() {
+        let s = r#"
+手数----指手---------消費時間--
+   1 ７六歩(77)
+   2 ３四歩(33)
+   3 ２六歩(27)
+
+変化：2手
+   2 ８四歩(83)
+   3 ５五飛(58)
+"#;
+        match parse_kif_str(&s[1..]) {
+            Err(ParseError::Normalize(err)) => {
+                assert!(matches!(err.error, NormalizeError::NoPieceAt(_)));
+                assert_eq!(3, err.ply);
+                assert_eq!(vec![(2, 0)], err.forks);
+                assert!(err
+                    .to_string()
+                    .ends_with("at move 3 in variation 1 from move 2"));
+            }
+            result => panic!("unexpected result: {result:?}"),
+        }
+        match parse_csa_str("PI\n+\n+7776FU\n+3334FU\n") {
+            Err(ParseError::Normalize(err)) => assert_eq!(
+                LocatedNormalizeError {
+                    error: NormalizeError::InvalidColor,
+                    ply: 2,
+                    forks: Vec::new(),
+                },
+                err
+            ),
+            result => panic!("unexpected result: {result:?}"),
+        }
+    }
+
+    #[test]
+    fn recover_csa() {
+        let s = "V2.2\n$FOO:bar\nPI\n+\n+7776FU\n%FOO\n-3334FU\n";
+        assert!(matches!(parse_csa_str(s), Err(ParseError::Csa(_))));
+        let (jkf, diagnostics) = parse_csa_str_with_options(s, &ParseOptions { recover: true })
+            .expect("failed to parse csa");
+        assert_eq!(3, jkf.moves.len());
+        assert_eq!(2, diagnostics.len());
+        assert_eq!(
+            Diagnostic::UnknownHeader {
+                line: 2,
+                key: String::from("FOO")
+            },
+            diagnostics[0]
+        );
+        assert!(
+            matches!(&diagnostics[1], Diagnostic::UnknownSpecialMove(err) if err.line == 6 && err.offset == s.find("FOO\n").unwrap())
+        );
+    }
+
+    #[test]
+    fn recover_kif() {
+        let s = r#"
+手数----指手---------消費時間--
+   1 ７六歩(77)
+   2 ３四Ｘ(33)
+   3 ２六歩(27)
+   4 反則
+"#;
+        let s = &s[1..];
+        assert!(matches!(parse_kif_str(s), Err(ParseError::Kif(_))));
+        let (jkf, diagnostics) = parse_kif_str_with_options(s, &ParseOptions { recover: true })
+            .expect("failed to parse kif");
+        assert_eq!(2, jkf.moves.len());
+        assert_eq!(3, diagnostics.len());
+        assert!(matches!(&diagnostics[0], Diagnostic::SkippedLine(err) if err.line == 3));
+        assert!(
+            matches!(&diagnostics[1], Diagnostic::UnknownSpecialMove(err) if err.line == 5 && err.line_text == "   4 反則")
+        );
+        assert!(matches!(
+            &diagnostics[2],
+            Diagnostic::TruncatedMoves(LocatedNormalizeError {
+                error: NormalizeError::InvalidColor,
+                ply: 2,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn recover_ki2() {
+        let s = "手合割：平手\n▲７六歩    △３四歩\n▲２六Ｘ\n▲２六歩    △８四歩\n\n変化：3手\n▲６六Ｘ\n▲６六歩    △８四歩\n";
+        assert!(matches!(parse_ki2_str(s), Err(ParseError::Ki2(_))));
+        let (jkf, diagnostics) = parse_ki2_str_with_options(s, &ParseOptions { recover: true })
+            .expect("failed to parse ki2");
+        // The broken lines are skipped wherever they are, including in the variation
+        assert_eq!(
+            vec![3, 7],
+            diagnostics
+                .iter()
+                .map(|d| match d {
+                    Diagnostic::SkippedLine(err) => err.line,
+                    d => panic!("unexpected diagnostic: {d:?}"),
+                })
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(5, jkf.moves.len());
+        assert_eq!(
+            Some(2),
+            jkf.moves[3].forks.as_ref().map(|forks| forks[0].len())
+        );
+    }
+
     #[test]
     fn kif_to_jkf() -> Result<()> {
         let dir = Path::new("data/tests/kif");
         for entry in dir.read_dir()? {
             // Parse and convert KIF to JKF, and serialize to Value
             let mut path = entry?.path();
-            if path.extension() != Some(OsStr::new("kif")) {
+            // Both Shift-JIS and UTF-8 files are detected from their contents
+            if ![Some(OsStr::new("kif")), Some(OsStr::new("kifu"))].contains(&path.extension()) {
                 continue;
             }
             let jkf = match parse_kif_file(&path) {
@@ -222,7 +939,8 @@ mod tests {
         for entry in dir.read_dir()? {
             // Parse and convert KI2 to JKF, and serialize to Value
             let mut path = entry?.path();
-            if path.extension() != Some(OsStr::new("ki2")) {
+            // Both Shift-JIS and UTF-8 files are detected from their contents
+            if ![Some(OsStr::new("ki2")), Some(OsStr::new("ki2u"))].contains(&path.extension()) {
                 continue;
             }
             let jkf = match parse_ki2_file(&path) {
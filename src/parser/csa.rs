@@ -0,0 +1,743 @@
+use super::error::ParserError;
+use super::recover;
+use crate::jkf::*;
+use crate::normalizer::HIRATE_BOARD;
+use indexmap::IndexMap;
+use nom::branch::alt;
+use nom::bytes::complete::{is_not, tag};
+use nom::character::complete::{char, digit1, line_ending, not_line_ending, one_of};
+use nom::combinator::{cut, eof, map, map_res, opt, peek, value};
+use nom::error::context;
+use nom::multi::{count, many0, many_till};
+use nom::sequence::{pair, preceded, separated_pair, terminated, tuple};
+use nom::IResult;
+
+#[derive(Clone)]
+enum HeaderLine<'a> {
+    Version,
+    Player(Color, &'a str),
+    Attribute(&'a str, &'a str),
+}
+
+// A piece in the placements `P+`, `P-`. `None` represents `AL`.
+type Placement = (Color, PlaceFormat, Option<Kind>);
+
+struct Position {
+    drop_pieces: Vec<PlaceFormat>,
+    bulk: Option<[[Piece; 9]; 9]>,
+    add_pieces: Vec<Placement>,
+    color: Color,
+}
+
+fn line_sep(input: &str) -> IResult<&str, &str, ParserError<&str>> {
+    alt((line_ending, tag(","), eof))(input)
+}
+
+fn comment_line(input: &str) -> IResult<&str, &str, ParserError<&str>> {
+    terminated(
+        preceded(char('\''), not_line_ending),
+        alt((line_ending, eof)),
+    )(input)
+}
+
+// Skips the comment lines and the empty lines
+fn skip_lines(input: &str) -> IResult<&str, (), ParserError<&str>> {
+    value((), many0(alt((comment_line, line_ending))))(input)
+}
+
+// The comment lines (and the empty lines) attached to the previous statement
+fn comments(input: &str) -> IResult<&str, Option<Vec<String>>, ParserError<&str>> {
+    map(
+        many0(alt((map(comment_line, Some), value(None, line_ending)))),
+        |lines| {
+            let comments = lines
+                .into_iter()
+                .flatten()
+                .map(String::from)
+                .collect::<Vec<_>>();
+            Some(comments).filter(|v| !v.is_empty())
+        },
+    )(input)
+}
+
+fn statement<'a, O, F>(f: F) -> impl FnMut(&'a str) -> IResult<&'a str, O, ParserError<&'a str>>
+where
+    F: FnMut(&'a str) -> IResult<&'a str, O, ParserError<&'a str>>,
+{
+    preceded(skip_lines, terminated(f, line_sep))
+}
+
+fn color(input: &str) -> IResult<&str, Color, ParserError<&str>> {
+    alt((
+        value(Color::Black, char('+')),
+        value(Color::White, char('-')),
+    ))(input)
+}
+
+fn piece_kind(input: &str) -> IResult<&str, Kind, ParserError<&str>> {
+    context(
+        "piece",
+        alt((
+            value(Kind::FU, tag("FU")),
+            value(Kind::KY, tag("KY")),
+            value(Kind::KE, tag("KE")),
+            value(Kind::GI, tag("GI")),
+            value(Kind::KI, tag("KI")),
+            value(Kind::KA, tag("KA")),
+            value(Kind::HI, tag("HI")),
+            value(Kind::OU, tag("OU")),
+            value(Kind::TO, tag("TO")),
+            value(Kind::NY, tag("NY")),
+            value(Kind::NK, tag("NK")),
+            value(Kind::NG, tag("NG")),
+            value(Kind::UM, tag("UM")),
+            value(Kind::RY, tag("RY")),
+        )),
+    )(input)
+}
+
+fn square(input: &str) -> IResult<&str, PlaceFormat, ParserError<&str>> {
+    context(
+        "square",
+        map(pair(one_of("123456789"), one_of("123456789")), |(x, y)| {
+            PlaceFormat {
+                x: x as u8 - b'0',
+                y: y as u8 - b'0',
+            }
+        }),
+    )(input)
+}
+
+// `00` represents the pieces in hand
+fn square_or_hand(input: &str) -> IResult<&str, PlaceFormat, ParserError<&str>> {
+    alt((value(PlaceFormat { x: 0, y: 0 }, tag("00")), square))(input)
+}
+
+fn header_line(input: &str) -> IResult<&str, HeaderLine<'_>, ParserError<&str>> {
+    alt((
+        value(HeaderLine::Version, preceded(char('V'), not_line_ending)),
+        map(
+            pair(
+                alt((
+                    value(Color::Black, tag("N+")),
+                    value(Color::White, tag("N-")),
+                )),
+                not_line_ending,
+            ),
+            |(c, s)| HeaderLine::Player(c, s),
+        ),
+        map(
+            preceded(
+                char('$'),
+                separated_pair(is_not(":\r\n"), char(':'), not_line_ending),
+            ),
+            |(k, v)| HeaderLine::Attribute(k, v),
+        ),
+    ))(input)
+}
+
+// The headers in KIF for the time control, such as `持ち時間：各25分` and `秒読み：30秒`.
+// `keys` are the keys of the main time and byoyomi, and the main time is prefixed with `each` for both players.
+fn time_control(
+    keys: (&'static str, &'static str),
+    each: bool,
+    tc: TimeControl,
+) -> Vec<(&'static str, String)> {
+    let (main, byoyomi) = tc.to_kif(each);
+    let mut headers = vec![(keys.0, main)];
+    if let Some(byoyomi) = byoyomi {
+        headers.push((keys.1, byoyomi));
+    }
+    headers
+}
+
+// The headers in KIF for the attribute `$KEY:VALUE` in CSA, or `None` if the key is unknown.
+// The value is kept as it is if it is not formatted as expected.
+fn attribute(key: &str, value: &str) -> Option<Vec<(&'static str, String)>> {
+    let (kif_key, kif_value) = match key {
+        "EVENT" => ("棋戦", None),
+        "SITE" => ("場所", None),
+        "START_TIME" => ("開始日時", None),
+        "END_TIME" => ("終了日時", None),
+        "OPENING" => ("戦型", None),
+        "NOTE" => ("備考", None),
+        "MAX_MOVES" => (
+            "最大手数",
+            value.trim().parse::<u32>().ok().map(|n| format!("{n}手")),
+        ),
+        "JISHOGI" => (
+            "持将棋",
+            value.trim().parse::<u32>().ok().map(|n| format!("{n}点法")),
+        ),
+        "TIME_LIMIT" | "TIME" | "TIME+" | "TIME-" => {
+            let (keys, each) = match key {
+                "TIME+" => (("先手持ち時間", "先手秒読み"), false),
+                "TIME-" => (("後手持ち時間", "後手秒読み"), false),
+                _ => (("持ち時間", "秒読み"), true),
+            };
+            let tc = if key == "TIME_LIMIT" {
+                TimeControl::from_csa_time_limit(value)
+            } else {
+                TimeControl::from_csa_time(value)
+            };
+            return Some(tc.map_or_else(
+                || vec![(keys.0, String::from(value))],
+                |tc| time_control(keys, each, tc),
+            ));
+        }
+        _ => return None,
+    };
+    Some(vec![(
+        kif_key,
+        kif_value.unwrap_or_else(|| String::from(value)),
+    )])
+}
+
+fn header(input: &str) -> IResult<&str, IndexMap<String, String>, ParserError<&str>> {
+    map(many0(statement(header_line)), |lines| {
+        let mut map = IndexMap::new();
+        for line in lines {
+            let headers = match line {
+                HeaderLine::Version => continue,
+                HeaderLine::Player(Color::Black, s) => vec![("先手", String::from(s))],
+                HeaderLine::Player(Color::White, s) => vec![("後手", String::from(s))],
+                HeaderLine::Attribute(k, v) => match attribute(k, v) {
+                    Some(headers) => headers,
+                    None => continue,
+                },
+            };
+            for (key, value) in headers {
+                map.insert(String::from(key), value);
+            }
+        }
+        map
+    })(input)
+}
+
+/// Returns the line numbers and the keys of the attributes which are ignored by the parser
+pub(crate) fn unknown_attributes(s: &str) -> Vec<(usize, &str)> {
+    s.lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let (key, value) = line.strip_prefix('$')?.split_once(':')?;
+            attribute(key, value).is_none().then_some((i + 1, key))
+        })
+        .collect()
+}
+
+fn handicap(input: &str) -> IResult<&str, Vec<PlaceFormat>, ParserError<&str>> {
+    preceded(tag("PI"), many0(terminated(square, piece_kind)))(input)
+}
+
+fn board_piece(input: &str) -> IResult<&str, Piece, ParserError<&str>> {
+    context(
+        "piece",
+        alt((
+            map(pair(color, piece_kind), |(c, k)| Piece {
+                color: Some(c),
+                kind: Some(k),
+            }),
+            // The trailing space of the last column might be omitted
+            value(Piece::empty(), terminated(tag(" *"), opt(char(' ')))),
+        )),
+    )(input)
+}
+
+fn board(input: &str) -> IResult<&str, [[Piece; 9]; 9], ParserError<&str>> {
+    let mut ret = [[Piece::empty(); 9]; 9];
+    // Once `P1` has appeared, all the rows must follow
+    let (mut input, _) = peek(tag("P1"))(input)?;
+    for (i, row) in ["P1", "P2", "P3", "P4", "P5", "P6", "P7", "P8", "P9"]
+        .into_iter()
+        .enumerate()
+    {
+        let (rest, pieces) = cut(preceded(
+            context("board row", tag(row)),
+            terminated(count(board_piece, 9), line_sep),
+        ))(input)?;
+        for (j, p) in pieces.into_iter().enumerate() {
+            ret[8 - j][i] = p;
+        }
+        input = rest;
+    }
+    Ok((input, ret))
+}
+
+fn placement(input: &str) -> IResult<&str, Vec<Placement>, ParserError<&str>> {
+    map(
+        pair(
+            preceded(char('P'), color),
+            many0(pair(
+                square_or_hand,
+                alt((map(piece_kind, Some), value(None, tag("AL")))),
+            )),
+        ),
+        |(c, v)| v.into_iter().map(|(sq, k)| (c, sq, k)).collect(),
+    )(input)
+}
+
+fn position(input: &str) -> IResult<&str, Position, ParserError<&str>> {
+    map(
+        tuple((
+            opt(statement(handicap)),
+            opt(preceded(skip_lines, board)),
+            many0(statement(placement)),
+            statement(context("side to move", color)),
+        )),
+        |(drop_pieces, bulk, add_pieces, color)| Position {
+            drop_pieces: drop_pieces.unwrap_or_default(),
+            bulk,
+            add_pieces: add_pieces.into_iter().flatten().collect(),
+            color,
+        },
+    )(input)
+}
+
+fn normal_move(input: &str) -> IResult<&str, MoveFormat, ParserError<&str>> {
+    map(
+        context("move", tuple((color, square_or_hand, square, piece_kind))),
+        |(color, from, to, piece)| MoveFormat {
+            move_: Some(MoveMoveFormat {
+                color,
+                from: Some(from),
+                to,
+                piece,
+                same: None,
+                promote: None,
+                capture: None,
+                relative: None,
+            }),
+            ..Default::default()
+        },
+    )(input)
+}
+
+fn special_move(input: &str) -> IResult<&str, MoveFormat, ParserError<&str>> {
+    map(
+        context(
+            "special move",
+            preceded(
+                char('%'),
+                alt((
+                    value(MoveSpecial::SpecialToryo, tag("TORYO")),
+                    value(MoveSpecial::SpecialChudan, tag("CHUDAN")),
+                    value(MoveSpecial::SpecialSennichite, tag("SENNICHITE")),
+                    value(MoveSpecial::SpecialTimeUp, tag("TIME_UP")),
+                    value(MoveSpecial::SpecialIllegalMove, tag("ILLEGAL_MOVE")),
+                    value(
+                        MoveSpecial::SpecialIllegalActionBlack,
+                        tag("+ILLEGAL_ACTION"),
+                    ),
+                    value(
+                        MoveSpecial::SpecialIllegalActionWhite,
+                        tag("-ILLEGAL_ACTION"),
+                    ),
+                    value(MoveSpecial::SpecialJishogi, tag("JISHOGI")),
+                    value(MoveSpecial::SpecialKachi, tag("KACHI")),
+                    value(MoveSpecial::SpecialHikiwake, tag("HIKIWAKE")),
+                    value(MoveSpecial::SpecialMatta, tag("MATTA")),
+                    value(MoveSpecial::SpecialTsumi, tag("TSUMI")),
+                    value(MoveSpecial::SpecialFuzumi, tag("FUZUMI")),
+                    value(MoveSpecial::SpecialError, tag("ERROR")),
+                )),
+            ),
+        ),
+        |special| MoveFormat {
+            special: Some(special),
+            ..Default::default()
+        },
+    )(input)
+}
+
+fn move_time(input: &str) -> IResult<&str, Time, ParserError<&str>> {
+    context(
+        "time",
+        map_res(
+            preceded(
+                char('T'),
+                // Fractions of a second are truncated
+                terminated(
+                    map_res(digit1, str::parse::<u64>),
+                    opt(pair(char('.'), digit1)),
+                ),
+            ),
+            // Fails if the hours don't fit in `TimeFormat`
+            |secs| {
+                u8::try_from(secs / 3600).map(|h| Time {
+                    now: TimeFormat {
+                        h: Some(h).filter(|&h| h > 0),
+                        m: (secs / 60 % 60) as u8,
+                        s: (secs % 60) as u8,
+                    },
+                    total: TimeFormat::default(),
+                })
+            },
+        ),
+    )(input)
+}
+
+fn move_record(input: &str) -> IResult<&str, MoveFormat, ParserError<&str>> {
+    map(
+        tuple((
+            statement(alt((normal_move, special_move))),
+            comments,
+            opt(terminated(move_time, line_sep)),
+            comments,
+        )),
+        |(mf, before, time, after)| {
+            let comments = match (before, after) {
+                (Some(before), Some(after)) => Some([before, after].concat()),
+                (before, after) => before.or(after),
+            };
+            MoveFormat {
+                comments,
+                time,
+                ..mf
+            }
+        },
+    )(input)
+}
+
+fn end_of_record(input: &str) -> IResult<&str, (), ParserError<&str>> {
+    preceded(
+        skip_lines,
+        alt((
+            value((), eof),
+            // Parse the remaining line as a move to report where it is broken.
+            // If it can be parsed, the move itself is unexpected here.
+            map_res(move_record, |_| Err(())),
+        )),
+    )(input)
+}
+
+pub(crate) fn parse(input: &str) -> IResult<&str, JsonKifuFormat, ParserError<&str>> {
+    map(
+        terminated(
+            tuple((header, position, comments, many0(move_record))),
+            end_of_record,
+        ),
+        |(header, position, comments, moves)| JsonKifuFormat {
+            header,
+            initial: Some(position.into()),
+            moves: [
+                vec![MoveFormat {
+                    comments,
+                    ..Default::default()
+                }],
+                moves,
+            ]
+            .concat(),
+        },
+    )(input)
+}
+
+/// Returns the errors of the lines of moves in the record which cannot be parsed
+pub(crate) fn broken_move_lines(record: &str) -> Vec<ParserError<&str>> {
+    let is_move = |line: &str| {
+        line.starts_with('%')
+            || line.starts_with(['+', '-']) && line[1..].starts_with(|c: char| c.is_ascii_digit())
+    };
+    recover::broken_move_lines(record, is_move, many_till(move_record, eof))
+}
+
+/// Splits the CSA formatted string into game records separated by `/` lines
+pub(crate) fn split_records(s: &str) -> Vec<&str> {
+    let mut records = Vec::new();
+    let mut start = 0;
+    let mut pos = 0;
+    for line in s.split_inclusive('\n') {
+        if line.trim_end() == "/" {
+            records.push(&s[start..pos]);
+            start = pos + line.len();
+        }
+        pos += line.len();
+    }
+    records.push(&s[start..]);
+    records
+}
+
+/// Returns `true` if the record is a variation written by [`ToCsa`](crate::converter::ToCsa)
+pub(crate) fn is_variation_record(record: &str) -> bool {
+    record
+        .lines()
+        .find(|line| !line.trim().is_empty())
+        .is_some_and(|line| line.starts_with("'変化："))
+}
+
+/// Merges the moves of a variation record into `moves` as forks, from the first different move
+pub(crate) fn merge_variation(moves: &mut Vec<MoveFormat>, mut variation: Vec<MoveFormat>) {
+    let same =
+        |lhs: &MoveFormat, rhs: &MoveFormat| lhs.move_ == rhs.move_ && lhs.special == rhs.special;
+    let i = moves
+        .iter()
+        .zip(&variation)
+        .take_while(|(lhs, rhs)| same(lhs, rhs))
+        .count();
+    if i == variation.len() {
+        return;
+    }
+    let rest = variation.split_off(i);
+    if i == moves.len() {
+        moves.extend(rest);
+        return;
+    }
+    let forks = moves[i].forks.get_or_insert_with(Vec::new);
+    if let Some(fork) = forks
+        .iter_mut()
+        .find(|fork| fork.first().is_some_and(|mf| same(mf, &rest[0])))
+    {
+        merge_variation(fork, rest);
+    } else {
+        forks.push(rest);
+    }
+}
+
+impl From<Position> for Initial {
+    fn from(mut pos: Position) -> Self {
+        let mut all_pieces = Hand {
+            FU: 18,
+            KY: 4,
+            KE: 4,
+            GI: 4,
+            KI: 4,
+            KA: 2,
+            HI: 2,
+        };
+        // split to hands' and board's
+        let mut hand_pieces = Vec::new();
+        pos.add_pieces.retain(|&(c, sq, k)| {
+            if sq.x == 0 && sq.y == 0 {
+                hand_pieces.push((c, k));
+                false
+            } else {
+                true
+            }
+        });
+        // Board
+        let board = if let Some(bulk) = pos.bulk {
+            // 一括表現
+            bulk
+        } else if pos.add_pieces.is_empty() {
+            // 平手初期配置と駒落ち
+            let mut b = HIRATE_BOARD;
+            for sq in &pos.drop_pieces {
+                b[sq.x as usize - 1][sq.y as usize - 1] = Piece::empty()
+            }
+            b
+        } else {
+            // 駒別単独表現
+            let mut b = [[Piece::empty(); 9]; 9];
+            for &(c, sq, k) in &pos.add_pieces {
+                b[sq.x as usize - 1][sq.y as usize - 1] = Piece {
+                    color: Some(c),
+                    kind: k,
+                };
+            }
+            b
+        };
+        for row in &board {
+            for col in row {
+                if let Some(unpromoted) = col.kind.map(Kind::unpromoted) {
+                    if unpromoted != Kind::OU {
+                        all_pieces.decrement(unpromoted);
+                    }
+                }
+            }
+        }
+        // Hands
+        let mut hands = [Hand::default(); 2];
+        for &(c, k) in &hand_pieces {
+            match k {
+                Some(kind) => hands[c as usize].increment(kind),
+                // In case `AL`
+                None => hands[c as usize] = all_pieces,
+            }
+        }
+        Self {
+            preset: Preset::PresetOther,
+            data: Some(StateFormat {
+                color: pos.color,
+                board,
+                hands,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_board_piece() {
+        assert_eq!(Ok(("", Piece::empty())), board_piece(" * "));
+        assert_eq!(Ok(("", Piece::empty())), board_piece(" *"));
+        assert_eq!(
+            Ok((
+                "",
+                Piece {
+                    color: Some(Color::White),
+                    kind: Some(Kind::RY),
+                }
+            )),
+            board_piece("-RY")
+        );
+        assert!(board_piece("+AL").is_err());
+    }
+
+    #[test]
+    fn parse_placement() {
+        assert_eq!(
+            Ok((
+                "",
+                vec![
+                    (Color::Black, PlaceFormat { x: 5, y: 9 }, Some(Kind::OU)),
+                    (Color::Black, PlaceFormat { x: 0, y: 0 }, None),
+                ]
+            )),
+            placement("P+59OU00AL")
+        );
+    }
+
+    #[test]
+    fn parse_move_record() {
+        assert_eq!(
+            Ok((
+                "",
+                MoveFormat {
+                    move_: Some(MoveMoveFormat {
+                        color: Color::Black,
+                        from: Some(PlaceFormat { x: 0, y: 0 }),
+                        to: PlaceFormat { x: 5, y: 5 },
+                        piece: Kind::KA,
+                        same: None,
+                        promote: None,
+                        capture: None,
+                        relative: None,
+                    }),
+                    time: Some(Time {
+                        now: TimeFormat {
+                            h: Some(1),
+                            m: 2,
+                            s: 3
+                        },
+                        total: TimeFormat::default(),
+                    }),
+                    ..Default::default()
+                }
+            )),
+            move_record("+0055KA,T3723.5\n")
+        );
+        assert_eq!(
+            Ok((
+                "",
+                MoveFormat {
+                    special: Some(MoveSpecial::SpecialIllegalActionWhite),
+                    ..Default::default()
+                }
+            )),
+            move_record("'comment\n%-ILLEGAL_ACTION")
+        );
+        // The comments after the move belong to it
+        assert_eq!(
+            Ok((
+                "-3334FU\n",
+                MoveFormat {
+                    special: Some(MoveSpecial::SpecialToryo),
+                    comments: Some(vec![String::from("foo"), String::from("** 12 -3334FU")]),
+                    time: Some(Time {
+                        now: TimeFormat {
+                            h: None,
+                            m: 0,
+                            s: 5
+                        },
+                        total: TimeFormat::default(),
+                    }),
+                    ..Default::default()
+                }
+            )),
+            move_record("%TORYO\n'foo\nT5\n'** 12 -3334FU\n\n-3334FU\n")
+        );
+    }
+
+    #[test]
+    fn parse_move_time() {
+        assert_eq!(
+            Ok((
+                "",
+                Time {
+                    now: TimeFormat {
+                        h: Some(255),
+                        m: 59,
+                        s: 59
+                    },
+                    total: TimeFormat::default(),
+                }
+            )),
+            move_time("T921599")
+        );
+        // 256 hours don't fit in the hours of `TimeFormat`
+        assert!(move_time("T921600").is_err());
+        assert!(crate::parser::parse_csa_str("PI\n+\n+7776FU\nT921600\n").is_err());
+    }
+
+    #[test]
+    fn parse_header() {
+        assert_eq!(
+            Ok((
+                "",
+                IndexMap::from([
+                    (String::from("先手"), String::from("player, black")),
+                    (String::from("場所"), String::from("KAZUSA ARC")),
+                    (String::from("持ち時間"), String::from("各25分")),
+                ])
+            )),
+            header("V2.2\nN+player, black\n$SITE:KAZUSA ARC\n$TIME_LIMIT:00:25+00\n$UNKNOWN:foo\n")
+        );
+    }
+
+    #[test]
+    fn parse_attribute() {
+        let expected = |v: &[(&'static str, &str)]| {
+            Some(
+                v.iter()
+                    .map(|&(k, v)| (k, String::from(v)))
+                    .collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(
+            expected(&[("持ち時間", "各1時間30分"), ("秒読み", "60秒")]),
+            attribute("TIME_LIMIT", "01:30+60")
+        );
+        assert_eq!(
+            expected(&[
+                ("持ち時間", "各5分（1手ごとに10秒加算）"),
+                ("秒読み", "30秒")
+            ]),
+            attribute("TIME", "300+30+10")
+        );
+        assert_eq!(
+            expected(&[("先手持ち時間", "10分")]),
+            attribute("TIME+", "600.000+0+0")
+        );
+        assert_eq!(
+            expected(&[("持将棋", "24点法")]),
+            attribute("JISHOGI", "24")
+        );
+        assert_eq!(
+            expected(&[("最大手数", "256手")]),
+            attribute("MAX_MOVES", "256")
+        );
+        assert_eq!(expected(&[("備考", "foo")]), attribute("NOTE", "foo"));
+        // Kept as it is if it is not formatted as expected
+        assert_eq!(
+            expected(&[("持ち時間", "foo")]),
+            attribute("TIME_LIMIT", "foo")
+        );
+        assert_eq!(
+            expected(&[("持ち時間", "99999999999999999:00+00")]),
+            attribute("TIME_LIMIT", "99999999999999999:00+00")
+        );
+        assert_eq!(None, attribute("UNKNOWN", "foo"));
+    }
+}
@@ -0,0 +1,75 @@
+use encoding_rs::{Encoding, SHIFT_JIS, UTF_8};
+
+// The number of lines to look for the declaration of the encoding
+const DECLARATION_LINES: usize = 5;
+
+// Detects the encoding of the bytes, and returns it with the length of its BOM.
+//
+// The encoding is detected in the following order:
+// 1. BOM
+// 2. Declaration in the first lines, such as `#KIF version=2.0 encoding=UTF-8` in KIF or `'encoding=SHIFT_JIS` in CSA,
+//    unless the bytes are malformed in it (some applications write the declaration of UTF-8 in Shift_JIS files)
+// 3. UTF-8 if the bytes are valid as UTF-8, otherwise Shift_JIS
+pub(super) fn detect(bytes: &[u8]) -> (&'static Encoding, usize) {
+    if let Some(found) = Encoding::for_bom(bytes) {
+        found
+    } else if let Some(encoding) = declared(bytes) {
+        (encoding, 0)
+    } else if std::str::from_utf8(bytes).is_ok() {
+        (UTF_8, 0)
+    } else {
+        (SHIFT_JIS, 0)
+    }
+}
+
+fn declared(bytes: &[u8]) -> Option<&'static Encoding> {
+    const KEY: &[u8] = b"encoding=";
+    bytes
+        .split(|&b| b == b'\n')
+        .take(DECLARATION_LINES)
+        .filter(|line| line.starts_with(b"#") || line.starts_with(b"'"))
+        .find_map(|line| {
+            let start = line.windows(KEY.len()).position(|w| w == KEY)? + KEY.len();
+            let label = line[start..]
+                .split(|b| b.is_ascii_whitespace() || *b == b',')
+                .next()?;
+            Encoding::for_label(label)
+        })
+        .filter(|encoding| {
+            encoding
+                .decode_without_bom_handling_and_without_replacement(bytes)
+                .is_some()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_bom() {
+        assert_eq!((UTF_8, 3), detect(b"\xEF\xBB\xBF# KIF"));
+    }
+
+    #[test]
+    fn detect_declaration() {
+        let (bytes, _, _) = UTF_8.encode("#KIF version=2.0 encoding=UTF-8\n先手：");
+        assert_eq!((UTF_8, 0), detect(&bytes));
+        let (bytes, _, _) = UTF_8.encode("'encoding=SHIFT_JIS\nN+sente");
+        assert_eq!((SHIFT_JIS, 0), detect(&bytes));
+        // Wrong declaration is ignored
+        let (bytes, _, _) = SHIFT_JIS.encode("#KIF version=2.0 encoding=UTF-8\n先手：");
+        assert_eq!((SHIFT_JIS, 0), detect(&bytes));
+        // Declaration after the first lines is ignored
+        let (bytes, _, _) = UTF_8.encode("\n\n\n\n\n'encoding=SHIFT_JIS\nN+sente");
+        assert_eq!((UTF_8, 0), detect(&bytes));
+    }
+
+    #[test]
+    fn detect_heuristic() {
+        let (bytes, _, _) = SHIFT_JIS.encode("先手：");
+        assert_eq!((SHIFT_JIS, 0), detect(&bytes));
+        let (bytes, _, _) = UTF_8.encode("先手：");
+        assert_eq!((UTF_8, 0), detect(&bytes));
+    }
+}
@@ -0,0 +1,124 @@
+use crate::error::SyntaxError;
+use nom::error::{ContextError, ErrorKind, FromExternalError, ParseError};
+use nom::InputLength;
+
+/// An error type for the nom parsers, which keeps the labels of what was expected at the furthest failed position.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ParserError<I> {
+    input: I,
+    expected: Vec<&'static str>,
+}
+
+impl<I: InputLength> ParseError<I> for ParserError<I> {
+    fn from_error_kind(input: I, _kind: ErrorKind) -> Self {
+        Self {
+            input,
+            expected: Vec::new(),
+        }
+    }
+
+    fn append(_input: I, _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    fn or(mut self, other: Self) -> Self {
+        // Prefer the error which has consumed more input, and merge the labels if they failed at the same position
+        match self.input.input_len().cmp(&other.input.input_len()) {
+            std::cmp::Ordering::Less => self,
+            std::cmp::Ordering::Greater => other,
+            std::cmp::Ordering::Equal => {
+                for label in other.expected {
+                    if !self.expected.contains(&label) {
+                        self.expected.push(label);
+                    }
+                }
+                self
+            }
+        }
+    }
+}
+
+impl<I: InputLength> ContextError<I> for ParserError<I> {
+    fn add_context(input: I, ctx: &'static str, mut other: Self) -> Self {
+        // The label describes the error better if it failed at the beginning of the context
+        if other.expected.is_empty() || input.input_len() == other.input.input_len() {
+            other.expected = vec![ctx];
+        }
+        other
+    }
+}
+
+impl<I: InputLength, E> FromExternalError<I, E> for ParserError<I> {
+    fn from_external_error(input: I, kind: ErrorKind, _e: E) -> Self {
+        Self::from_error_kind(input, kind)
+    }
+}
+
+impl ParserError<&str> {
+    /// Converts to [`SyntaxError`] with the position in `input`, which must contain the failed input as its part.
+    pub(crate) fn into_syntax_error(self, input: &str) -> SyntaxError {
+        let offset = self.input.as_ptr() as usize - input.as_ptr() as usize;
+        let start = input[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let end = input[offset..]
+            .find('\n')
+            .map_or(input.len(), |i| offset + i);
+        SyntaxError {
+            offset,
+            line: input[..offset].matches('\n').count() + 1,
+            column: input[start..offset].chars().count() + 1,
+            line_text: String::from(input[start..end].trim_end_matches('\r')),
+            expected: self.expected.into_iter().map(String::from).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nom::branch::alt;
+    use nom::bytes::complete::tag;
+    use nom::combinator::recognize;
+    use nom::error::context;
+    use nom::sequence::{pair, preceded};
+    use nom::{Finish, IResult};
+
+    fn parse(input: &str) -> IResult<&str, &str, ParserError<&str>> {
+        preceded(
+            tag("a\n"),
+            alt((
+                context("foo", tag("foo")),
+                context("bar", tag("bar")),
+                recognize(pair(tag("b"), context("baz", tag("az")))),
+            )),
+        )(input)
+    }
+
+    #[test]
+    fn into_syntax_error() {
+        let input = "a\nxyz\n";
+        let err = parse(input).finish().expect_err("should fail");
+        assert_eq!(
+            SyntaxError {
+                offset: 2,
+                line: 2,
+                column: 1,
+                line_text: String::from("xyz"),
+                expected: vec![String::from("foo"), String::from("bar")],
+            },
+            err.into_syntax_error(input)
+        );
+        // The furthest error wins
+        let input = "a\nbx";
+        let err = parse(input).finish().expect_err("should fail");
+        assert_eq!(
+            SyntaxError {
+                offset: 3,
+                line: 2,
+                column: 2,
+                line_text: String::from("bx"),
+                expected: vec![String::from("baz")],
+            },
+            err.into_syntax_error(input)
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use super::Format;
+
+// Guesses the format of the kifu string from its contents.
+//
+// The lines are checked from the top, and the first line which is characteristic of a format decides it:
+// - JSON: an object
+// - KIF: the header of the moves `手数----指手----`
+// - KI2: a move line starting with `▲` or `△`
+// - CSA: a version, board, move or side to move line such as `V2.2`, `PI`, `P1`, `+7776FU` or `+`
+// - USI: a `position` command such as `position startpos moves 7g7f`, with or without the keyword
+// - SFEN: a position such as `lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1`
+// If none of them is found, a string with KIF/KI2 style header lines such as `先手：...` is regarded as KIF.
+pub(super) fn detect(s: &str) -> Option<Format> {
+    if s.trim_start().starts_with('{') {
+        return Some(Format::Jkf);
+    }
+    let mut has_header = false;
+    for line in s.lines().map(str::trim) {
+        if line.starts_with("手数") {
+            return Some(Format::Kif);
+        }
+        if line.starts_with('▲') || line.starts_with('△') {
+            return Some(Format::Ki2);
+        }
+        if line.starts_with("position ")
+            || line.starts_with("startpos")
+            || line.starts_with("sfen ")
+        {
+            return Some(Format::Usi);
+        }
+        if is_sfen_line(line) {
+            return Some(Format::Sfen);
+        }
+        if is_csa_line(line) {
+            return Some(Format::Csa);
+        }
+        has_header |= line.contains('：');
+    }
+    has_header.then_some(Format::Kif)
+}
+
+fn is_csa_line(line: &str) -> bool {
+    let bytes = line.as_bytes();
+    match bytes {
+        [b'V', b'2', ..] | [b'P', b'I', ..] | [b'P', b'+' | b'-', ..] | [b'+' | b'-'] => true,
+        [b'P', b'1'..=b'9', ..] => true,
+        [b'+' | b'-', rest @ ..] => {
+            rest.len() >= 6
+                && rest[..4].iter().all(u8::is_ascii_digit)
+                && rest[4..6].iter().all(u8::is_ascii_uppercase)
+        }
+        _ => false,
+    }
+}
+
+fn is_sfen_line(line: &str) -> bool {
+    let mut fields = line.split_ascii_whitespace();
+    matches!(
+        (fields.next(), fields.next()),
+        (Some(board), Some("b" | "w")) if board.matches('/').count() == 8
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_format() {
+        assert_eq!(Some(Format::Jkf), detect(" {\"header\":{}}"));
+        assert_eq!(Some(Format::Csa), detect("'comment\nV2.2\nN+先手\n"));
+        assert_eq!(Some(Format::Csa), detect("N+先手\nN-後手\n+7776FU\n"));
+        assert_eq!(Some(Format::Csa), detect("P1-KY-KE-GI-KI-OU-KI-GI-KE-KY\n"));
+        assert_eq!(
+            Some(Format::Kif),
+            detect("先手：先手\n手数----指手---------消費時間--\n   1 ７六歩(77)\n")
+        );
+        assert_eq!(Some(Format::Kif), detect("先手：先手\n後手：後手\n"));
+        assert_eq!(
+            Some(Format::Ki2),
+            detect("先手：先手\n▲７六歩    △３四歩\n")
+        );
+        assert_eq!(Some(Format::Usi), detect("position startpos moves 7g7f\n"));
+        assert_eq!(
+            Some(Format::Usi),
+            detect("sfen 8k/9/9/9/9/9/9/9/K8 b - 1 moves 9i9h\n")
+        );
+        assert_eq!(Some(Format::Sfen), detect("8k/9/9/9/9/9/9/9/K8 b - 1\n"));
+        assert_eq!(None, detect("foo\nbar\n"));
+    }
+}
@@ -1,13 +1,14 @@
+use super::error::ParserError;
 use crate::jkf::*;
+use indexmap::IndexMap;
 use nom::branch::alt;
 use nom::bytes::complete::{is_not, tag};
-use nom::character::complete::{line_ending, none_of, not_line_ending, one_of};
+use nom::character::complete::{line_ending, none_of, not_line_ending, one_of, space0};
 use nom::combinator::{map, map_res, opt, value};
-use nom::error::VerboseError;
+use nom::error::{context, ContextError, ErrorKind, ParseError};
 use nom::multi::{count, many0, many1};
 use nom::sequence::{delimited, pair, preceded, separated_pair, terminated, tuple};
 use nom::IResult;
-use std::collections::HashMap;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 enum Information {
@@ -21,7 +22,7 @@ enum Information {
 struct InformationData {
     preset: Option<Preset>,
     hands: [Hand; 2],
-    map: HashMap<String, String>,
+    map: IndexMap<String, String>,
 }
 
 impl InformationData {
@@ -51,18 +52,18 @@ impl InformationData {
     }
 }
 
-fn comment_line(input: &str) -> IResult<&str, String, VerboseError<&str>> {
+fn comment_line(input: &str) -> IResult<&str, String, ParserError<&str>> {
     map(
         delimited(tag("#"), not_line_ending, line_ending),
         String::from,
     )(input)
 }
 
-pub(super) fn not_move_line(input: &str) -> IResult<&str, &str, VerboseError<&str>> {
+pub(super) fn not_move_line(input: &str) -> IResult<&str, &str, ParserError<&str>> {
     delimited(none_of(" 0123456789*▲△"), not_line_ending, line_ending)(input)
 }
 
-pub(super) fn move_comment_line(input: &str) -> IResult<&str, String, VerboseError<&str>> {
+pub(super) fn move_comment_line(input: &str) -> IResult<&str, String, ParserError<&str>> {
     alt((
         map(
             delimited(tag("*"), not_line_ending, line_ending),
@@ -74,26 +75,29 @@ pub(super) fn move_comment_line(input: &str) -> IResult<&str, String, VerboseErr
     ))(input)
 }
 
-pub(super) fn piece_kind(input: &str) -> IResult<&str, Kind, VerboseError<&str>> {
-    alt((
-        value(Kind::FU, tag("歩")),
-        value(Kind::KY, tag("香")),
-        value(Kind::KE, tag("桂")),
-        value(Kind::GI, tag("銀")),
-        value(Kind::KI, tag("金")),
-        value(Kind::KA, tag("角")),
-        value(Kind::HI, tag("飛")),
-        value(Kind::OU, alt((tag("玉"), tag("王")))),
-        value(Kind::TO, tag("と")),
-        value(Kind::NY, alt((tag("杏"), tag("成香")))),
-        value(Kind::NK, alt((tag("圭"), tag("成桂")))),
-        value(Kind::NG, alt((tag("全"), tag("成銀")))),
-        value(Kind::UM, tag("馬")),
-        value(Kind::RY, alt((tag("龍"), tag("竜")))),
-    ))(input)
+pub(super) fn piece_kind(input: &str) -> IResult<&str, Kind, ParserError<&str>> {
+    context(
+        "piece",
+        alt((
+            value(Kind::FU, tag("歩")),
+            value(Kind::KY, tag("香")),
+            value(Kind::KE, tag("桂")),
+            value(Kind::GI, tag("銀")),
+            value(Kind::KI, tag("金")),
+            value(Kind::KA, tag("角")),
+            value(Kind::HI, tag("飛")),
+            value(Kind::OU, alt((tag("玉"), tag("王")))),
+            value(Kind::TO, tag("と")),
+            value(Kind::NY, alt((tag("杏"), tag("成香")))),
+            value(Kind::NK, alt((tag("圭"), tag("成桂")))),
+            value(Kind::NG, alt((tag("全"), tag("成銀")))),
+            value(Kind::UM, tag("馬")),
+            value(Kind::RY, alt((tag("龍"), tag("竜")))),
+        )),
+    )(input)
 }
 
-fn kansuji(input: &str) -> IResult<&str, u8, VerboseError<&str>> {
+fn kansuji(input: &str) -> IResult<&str, u8, ParserError<&str>> {
     alt((
         value(18, tag("十八")),
         value(17, tag("十七")),
@@ -116,7 +120,7 @@ fn kansuji(input: &str) -> IResult<&str, u8, VerboseError<&str>> {
     ))(input)
 }
 
-fn information_value_hand(input: &str) -> IResult<&str, Hand, VerboseError<&str>> {
+fn information_value_hand(input: &str) -> IResult<&str, Hand, ParserError<&str>> {
     alt((
         value(Hand::default(), tag("なし")),
         map_res(
@@ -143,7 +147,7 @@ fn information_value_hand(input: &str) -> IResult<&str, Hand, VerboseError<&str>
     ))(input)
 }
 
-fn information_value_preset(input: &str) -> IResult<&str, Information, VerboseError<&str>> {
+fn information_value_preset(input: &str) -> IResult<&str, Information, ParserError<&str>> {
     terminated(
         map(
             alt((
@@ -171,14 +175,14 @@ fn information_value_preset(input: &str) -> IResult<&str, Information, VerboseEr
     )(input)
 }
 
-fn information_line_preset(input: &str) -> IResult<&str, Information, VerboseError<&str>> {
+fn information_line_preset(input: &str) -> IResult<&str, Information, ParserError<&str>> {
     terminated(
         preceded(tag("手合割："), information_value_preset),
         line_ending,
     )(input)
 }
 
-fn information_line_hands(input: &str) -> IResult<&str, Information, VerboseError<&str>> {
+fn information_line_hands(input: &str) -> IResult<&str, Information, ParserError<&str>> {
     terminated(
         map(
             pair(
@@ -202,7 +206,7 @@ fn information_line_hands(input: &str) -> IResult<&str, Information, VerboseErro
     )(input)
 }
 
-fn information_line_keyvalue(input: &str) -> IResult<&str, Information, VerboseError<&str>> {
+fn information_line_keyvalue(input: &str) -> IResult<&str, Information, ParserError<&str>> {
     terminated(
         map(
             separated_pair(
@@ -216,7 +220,7 @@ fn information_line_keyvalue(input: &str) -> IResult<&str, Information, VerboseE
     )(input)
 }
 
-fn informations(input: &str) -> IResult<&str, InformationData, VerboseError<&str>> {
+fn informations(input: &str) -> IResult<&str, InformationData, ParserError<&str>> {
     map(
         many0(preceded(
             many0(comment_line),
@@ -242,11 +246,11 @@ fn informations(input: &str) -> IResult<&str, InformationData, VerboseError<&str
     )(input)
 }
 
-fn board_piece_color(input: &str) -> IResult<&str, Color, VerboseError<&str>> {
+fn board_piece_color(input: &str) -> IResult<&str, Color, ParserError<&str>> {
     alt((value(Color::Black, tag(" ")), value(Color::White, tag("v"))))(input)
 }
 
-fn board_piece(input: &str) -> IResult<&str, Piece, VerboseError<&str>> {
+fn board_piece(input: &str) -> IResult<&str, Piece, ParserError<&str>> {
     alt((
         value(Piece::empty(), tag(" ・")),
         map(pair(board_piece_color, piece_kind), |(c, k)| Piece {
@@ -256,7 +260,7 @@ fn board_piece(input: &str) -> IResult<&str, Piece, VerboseError<&str>> {
     ))(input)
 }
 
-fn board_row(input: &str) -> IResult<&str, Vec<Piece>, VerboseError<&str>> {
+fn board_row(input: &str) -> IResult<&str, Vec<Piece>, ParserError<&str>> {
     terminated(
         delimited(
             tag("|"),
@@ -267,7 +271,7 @@ fn board_row(input: &str) -> IResult<&str, Vec<Piece>, VerboseError<&str>> {
     )(input)
 }
 
-fn board(input: &str) -> IResult<&str, [[Piece; 9]; 9], VerboseError<&str>> {
+fn board(input: &str) -> IResult<&str, [[Piece; 9]; 9], ParserError<&str>> {
     delimited(
         tuple((
             terminated(tag("  ９ ８ ７ ６ ５ ４ ３ ２ １"), line_ending),
@@ -286,44 +290,104 @@ fn board(input: &str) -> IResult<&str, [[Piece; 9]; 9], VerboseError<&str>> {
     )(input)
 }
 
-fn place_x(input: &str) -> IResult<&str, u8, VerboseError<&str>> {
-    alt((
-        value(1, tag("１")),
-        value(2, tag("２")),
-        value(3, tag("３")),
-        value(4, tag("４")),
-        value(5, tag("５")),
-        value(6, tag("６")),
-        value(7, tag("７")),
-        value(8, tag("８")),
-        value(9, tag("９")),
-    ))(input)
+fn place_x(input: &str) -> IResult<&str, u8, ParserError<&str>> {
+    context(
+        "file",
+        alt((
+            value(1, tag("１")),
+            value(2, tag("２")),
+            value(3, tag("３")),
+            value(4, tag("４")),
+            value(5, tag("５")),
+            value(6, tag("６")),
+            value(7, tag("７")),
+            value(8, tag("８")),
+            value(9, tag("９")),
+        )),
+    )(input)
 }
 
-fn place_y(input: &str) -> IResult<&str, u8, VerboseError<&str>> {
-    alt((
-        value(1, tag("一")),
-        value(2, tag("二")),
-        value(3, tag("三")),
-        value(4, tag("四")),
-        value(5, tag("五")),
-        value(6, tag("六")),
-        value(7, tag("七")),
-        value(8, tag("八")),
-        value(9, tag("九")),
-    ))(input)
+fn place_y(input: &str) -> IResult<&str, u8, ParserError<&str>> {
+    context(
+        "rank",
+        alt((
+            value(1, tag("一")),
+            value(2, tag("二")),
+            value(3, tag("三")),
+            value(4, tag("四")),
+            value(5, tag("五")),
+            value(6, tag("六")),
+            value(7, tag("七")),
+            value(8, tag("八")),
+            value(9, tag("九")),
+        )),
+    )(input)
 }
 
-pub(super) fn move_to(input: &str) -> IResult<&str, Option<PlaceFormat>, VerboseError<&str>> {
-    alt((
-        value(None, terminated(tag("同"), opt(tag("　")))),
-        map(pair(place_x, place_y), |(x, y)| Some(PlaceFormat { x, y })),
-    ))(input)
+pub(super) fn move_to(input: &str) -> IResult<&str, Option<PlaceFormat>, ParserError<&str>> {
+    context(
+        "square",
+        alt((
+            value(None, terminated(tag("同"), opt(tag("　")))),
+            map(pair(place_x, place_y), |(x, y)| Some(PlaceFormat { x, y })),
+        )),
+    )(input)
+}
+
+// Lines after the moves, such as "まで64手で先手の勝ち".
+pub(super) fn trailing_lines(input: &str) -> IResult<&str, (), ParserError<&str>> {
+    value(
+        (),
+        tuple((
+            many0(alt((preceded(space0, line_ending), not_move_line))),
+            opt(pair(none_of(" 0123456789*▲△"), not_line_ending)),
+            space0,
+        )),
+    )(input)
+}
+
+// Merges the variations into the main moves.
+// Each variation is placed after the line it branches from, as in the order written by Kifu for Windows.
+// Each variation comes with the input at its `変化` line, which is returned as the error
+// if the ply of the variation is outside the line it branches from.
+pub(super) fn merge_forks<I>(
+    mut moves: Vec<MoveFormat>,
+    mut forks: Vec<(I, usize, Vec<MoveFormat>)>,
+) -> Result<Vec<MoveFormat>, I> {
+    let mut stack = Vec::new();
+    while let Some(fork) = forks.pop() {
+        stack.push(fork);
+        if let Some((_, i, last)) = forks.last_mut() {
+            while stack.last().is_some_and(|(_, j, _)| j >= i) {
+                if let Some((at, j, fork)) = stack.pop() {
+                    let Some(mf) = last.get_mut(j - *i) else {
+                        return Err(at);
+                    };
+                    mf.forks.get_or_insert_with(Vec::new).push(fork);
+                }
+            }
+        }
+    }
+    while let Some((at, i, fork)) = stack.pop() {
+        // `moves[0]` is not a move but the initial position
+        let Some(mf) = moves.get_mut(i).filter(|_| i > 0) else {
+            return Err(at);
+        };
+        mf.forks.get_or_insert_with(Vec::new).push(fork);
+    }
+    Ok(moves)
+}
+
+// The failure at the `変化` line of the variation which cannot be merged by `merge_forks`
+pub(super) fn invalid_fork(input: &str) -> nom::Err<ParserError<&str>> {
+    nom::Err::Failure(ParserError::add_context(
+        input,
+        "ply of the variation",
+        ParserError::from_error_kind(input, ErrorKind::Verify),
+    ))
 }
 
-pub(super) fn parse_without_moves(
-    input: &str,
-) -> IResult<&str, JsonKifuFormat, VerboseError<&str>> {
+pub(super) fn parse_without_moves(input: &str) -> IResult<&str, JsonKifuFormat, ParserError<&str>> {
     map(
         tuple((informations, opt(board), informations)),
         |(info1, opt_board, info2)| {
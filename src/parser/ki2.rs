@@ -1,18 +1,26 @@
-use super::kakinoki::{move_comment_line, move_to, parse_without_moves, piece_kind};
+use super::error::ParserError;
+use super::kakinoki::{
+    invalid_fork, merge_forks, move_comment_line, move_to, not_move_line, parse_without_moves,
+    piece_kind, trailing_lines,
+};
+use super::recover;
 use crate::jkf::*;
 use nom::branch::alt;
 use nom::bytes::complete::tag;
-use nom::character::complete::{line_ending, space0};
-use nom::combinator::{map, opt, value};
-use nom::error::VerboseError;
-use nom::multi::{many0, many1};
-use nom::sequence::{pair, preceded, tuple};
+use nom::character::complete::{digit1, line_ending, space0};
+use nom::combinator::{consumed, eof, map, map_res, not, opt, value};
+use nom::error::context;
+use nom::multi::{many0, many1, many_till};
+use nom::sequence::{delimited, pair, preceded, terminated, tuple};
 use nom::IResult;
 
-fn single_move(input: &str) -> IResult<&str, MoveFormat, VerboseError<&str>> {
+fn single_move(input: &str) -> IResult<&str, MoveFormat, ParserError<&str>> {
     map(
         tuple((
-            alt((value(Color::Black, tag("▲")), value(Color::White, tag("△")))),
+            context(
+                "move",
+                alt((value(Color::Black, tag("▲")), value(Color::White, tag("△")))),
+            ),
             move_to,
             piece_kind,
             opt(alt((value(true, tag("成")), value(false, tag("不成"))))),
@@ -61,7 +69,27 @@ fn single_move(input: &str) -> IResult<&str, MoveFormat, VerboseError<&str>> {
     )(input)
 }
 
-fn moves(input: &str) -> IResult<&str, Vec<MoveFormat>, VerboseError<&str>> {
+fn fork_header(input: &str) -> IResult<&str, usize, ParserError<&str>> {
+    delimited(
+        tag("変化："),
+        map_res(digit1, str::parse),
+        terminated(tag("手"), line_ending),
+    )(input)
+}
+
+fn not_fork_line(input: &str) -> IResult<&str, &str, ParserError<&str>> {
+    preceded(not(fork_header), alt((line_ending, not_move_line)))(input)
+}
+
+// The variation with the input at its `変化` line
+fn fork_moves(input: &str) -> IResult<&str, (&str, usize, Vec<MoveFormat>), ParserError<&str>> {
+    map(
+        pair(consumed(fork_header), many1(single_move)),
+        |((at, i), v)| (at, i, v),
+    )(input)
+}
+
+fn main_moves(input: &str) -> IResult<&str, Vec<MoveFormat>, ParserError<&str>> {
     map(
         pair(
             preceded(many0(line_ending), opt(many1(move_comment_line))),
@@ -80,17 +108,49 @@ fn moves(input: &str) -> IResult<&str, Vec<MoveFormat>, VerboseError<&str>> {
     )(input)
 }
 
-pub(crate) fn parse(input: &str) -> IResult<&str, JsonKifuFormat, VerboseError<&str>> {
-    map(pair(parse_without_moves, moves), |(mut jkf, moves)| {
-        jkf.moves.extend(moves);
-        jkf
-    })(input)
+fn entire_moves(input: &str) -> IResult<&str, Vec<MoveFormat>, ParserError<&str>> {
+    let (rest, (moves, forks)) = pair(
+        main_moves,
+        many0(preceded(many0(not_fork_line), fork_moves)),
+    )(input)?;
+    match merge_forks(moves, forks) {
+        Ok(moves) => Ok((rest, moves)),
+        Err(at) => Err(invalid_fork(at)),
+    }
+}
+
+fn end_of_moves(input: &str) -> IResult<&str, (), ParserError<&str>> {
+    preceded(
+        trailing_lines,
+        alt((
+            value((), eof),
+            // Parse the remaining move to report where it is broken.
+            // If it can be parsed, the move itself is unexpected here.
+            map_res(single_move, |_| Err(())),
+        )),
+    )(input)
+}
+
+pub(crate) fn parse(input: &str) -> IResult<&str, JsonKifuFormat, ParserError<&str>> {
+    map(
+        terminated(pair(parse_without_moves, entire_moves), end_of_moves),
+        |(mut jkf, moves)| {
+            jkf.moves.extend(moves);
+            jkf
+        },
+    )(input)
+}
+
+/// Returns the errors of the lines of moves which cannot be parsed
+pub(crate) fn broken_move_lines(input: &str) -> Vec<ParserError<&str>> {
+    let is_move = |line: &str| line.starts_with(['▲', '△']);
+    recover::broken_move_lines(input, is_move, many_till(single_move, eof))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashMap;
+    use indexmap::IndexMap;
 
     #[test]
     fn parse_empty() {
@@ -98,7 +158,7 @@ mod tests {
             Ok((
                 "",
                 JsonKifuFormat {
-                    header: HashMap::new(),
+                    header: IndexMap::new(),
                     initial: Some(Initial {
                         preset: Preset::PresetHirate,
                         data: None,
@@ -229,7 +289,7 @@ mod tests {
     }
 
     #[test]
-    fn parse_moves() {
+    fn parse_main_moves() {
         assert_eq!(
             Ok((
                 "",
@@ -238,7 +298,7 @@ mod tests {
                     ..Default::default()
                 }]
             )),
-            moves("*comment\n")
+            main_moves("*comment\n")
         );
         assert_eq!(
             Ok((
@@ -286,12 +346,12 @@ mod tests {
                     }
                 ]
             )),
-            moves("▲６八銀 △３四歩 ▲５六歩")
+            main_moves("▲６八銀 △３四歩 ▲５六歩")
         )
     }
 
     #[test]
-    fn parse_moves_with_comments() {
+    fn parse_main_moves_with_comments() {
         assert_eq!(
             Ok((
                 "",
@@ -333,7 +393,7 @@ mod tests {
                     },
                 ]
             )),
-            moves(
+            main_moves(
                 &r#"
 △７四歩
 *-2732
@@ -1,42 +1,59 @@
-use super::kakinoki::{move_comment_line, move_to, not_move_line, parse_without_moves, piece_kind};
+use super::error::ParserError;
+use super::kakinoki::{
+    invalid_fork, merge_forks, move_comment_line, move_to, not_move_line, parse_without_moves,
+    piece_kind, trailing_lines,
+};
+use super::recover;
 use crate::jkf::*;
 use nom::branch::alt;
 use nom::bytes::complete::tag;
 use nom::character::complete::{digit1, line_ending, not_line_ending, space0};
-use nom::combinator::{map, map_res, opt, value};
-use nom::error::VerboseError;
+use nom::combinator::{consumed, eof, map, map_res, opt, value};
+use nom::error::context;
 use nom::multi::{many0, many1};
 use nom::sequence::{delimited, pair, preceded, separated_pair, terminated, tuple};
 use nom::IResult;
 
-fn move_from(input: &str) -> IResult<&str, Option<PlaceFormat>, VerboseError<&str>> {
-    alt((
-        // To disambiguate `Normal` move or `Drop` move, "打" will be parsed as `Some(PlaceFormat { x: 0, y: 0 })`
-        value(Some(PlaceFormat { x: 0, y: 0 }), tag("打")),
-        map(
-            delimited(tag("("), map_res(digit1, str::parse), tag(")")),
-            |d: u8| {
-                Some(PlaceFormat {
-                    x: d / 10,
-                    y: d % 10,
-                })
-            },
-        ),
-    ))(input)
+fn move_from(input: &str) -> IResult<&str, Option<PlaceFormat>, ParserError<&str>> {
+    context(
+        "move origin",
+        alt((
+            // To disambiguate `Normal` move or `Drop` move, "打" will be parsed as `Some(PlaceFormat { x: 0, y: 0 })`
+            value(Some(PlaceFormat { x: 0, y: 0 }), tag("打")),
+            map(
+                delimited(tag("("), map_res(digit1, str::parse), tag(")")),
+                |d: u8| {
+                    Some(PlaceFormat {
+                        x: d / 10,
+                        y: d % 10,
+                    })
+                },
+            ),
+        )),
+    )(input)
 }
 
-fn move_special(input: &str) -> IResult<&str, MoveFormat, VerboseError<&str>> {
+// 反則勝ち is parsed as if Black is to move, and it is fixed by `resolve_specials` with the actual side to move.
+fn move_special(input: &str) -> IResult<&str, MoveFormat, ParserError<&str>> {
     map(
-        alt((
-            value(MoveSpecial::SpecialToryo, tag("投了")),
-            value(MoveSpecial::SpecialChudan, tag("中断")),
-            value(MoveSpecial::SpecialSennichite, tag("千日手")),
-            value(MoveSpecial::SpecialTimeUp, tag("切れ負け")),
-            value(MoveSpecial::SpecialIllegalMove, tag("反則負け")),
-            value(MoveSpecial::SpecialJishogi, tag("持将棋")),
-            value(MoveSpecial::SpecialKachi, tag("入玉勝ち")),
-            value(MoveSpecial::SpecialTsumi, tag("詰み")),
-        )),
+        context(
+            "special move",
+            alt((
+                value(MoveSpecial::SpecialToryo, tag("投了")),
+                value(MoveSpecial::SpecialChudan, tag("中断")),
+                value(MoveSpecial::SpecialChudan, tag("封じ手")),
+                value(MoveSpecial::SpecialSennichite, tag("千日手")),
+                value(MoveSpecial::SpecialTimeUp, tag("切れ負け")),
+                value(MoveSpecial::SpecialIllegalMove, tag("反則負け")),
+                value(MoveSpecial::SpecialIllegalActionWhite, tag("反則勝ち")),
+                value(MoveSpecial::SpecialJishogi, tag("持将棋")),
+                value(MoveSpecial::SpecialKachi, tag("入玉勝ち")),
+                value(MoveSpecial::SpecialKachi, tag("入玉宣言")),
+                value(MoveSpecial::SpecialKachi, tag("宣言勝ち")),
+                value(MoveSpecial::SpecialTsumi, tag("詰み")),
+                value(MoveSpecial::SpecialFuzumi, tag("不詰")),
+            )),
+        ),
         |special| MoveFormat {
             special: Some(special),
             ..Default::default()
@@ -44,9 +61,12 @@ fn move_special(input: &str) -> IResult<&str, MoveFormat, VerboseError<&str>> {
     )(input)
 }
 
-fn move_move(input: &str) -> IResult<&str, MoveFormat, VerboseError<&str>> {
+fn move_move(input: &str) -> IResult<&str, MoveFormat, ParserError<&str>> {
     map(
-        tuple((move_to, piece_kind, opt(tag("成")), move_from)),
+        context(
+            "move",
+            tuple((move_to, piece_kind, opt(tag("成")), move_from)),
+        ),
         |(to, kind, promote, from)| {
             MoveFormat {
                 move_: Some(MoveMoveFormat {
@@ -65,7 +85,7 @@ fn move_move(input: &str) -> IResult<&str, MoveFormat, VerboseError<&str>> {
     )(input)
 }
 
-fn move_time_format(input: &str) -> IResult<&str, TimeFormat, VerboseError<&str>> {
+fn move_time_format(input: &str) -> IResult<&str, TimeFormat, ParserError<&str>> {
     alt((
         map(
             tuple((
@@ -85,7 +105,7 @@ fn move_time_format(input: &str) -> IResult<&str, TimeFormat, VerboseError<&str>
     ))(input)
 }
 
-fn move_time(input: &str) -> IResult<&str, Time, VerboseError<&str>> {
+fn move_time(input: &str) -> IResult<&str, Time, ParserError<&str>> {
     delimited(
         tag("("),
         map(
@@ -100,7 +120,7 @@ fn move_time(input: &str) -> IResult<&str, Time, VerboseError<&str>> {
     )(input)
 }
 
-fn move_line(input: &str) -> IResult<&str, (usize, MoveFormat), VerboseError<&str>> {
+fn move_line(input: &str) -> IResult<&str, (usize, MoveFormat), ParserError<&str>> {
     map(
         delimited(
             space0,
@@ -121,7 +141,7 @@ fn move_line(input: &str) -> IResult<&str, (usize, MoveFormat), VerboseError<&st
     )(input)
 }
 
-fn move_with_comments(input: &str) -> IResult<&str, (usize, MoveFormat), VerboseError<&str>> {
+fn move_with_comments(input: &str) -> IResult<&str, (usize, MoveFormat), ParserError<&str>> {
     map(
         pair(move_line, many0(move_comment_line)),
         |((i, mf), comments)| {
@@ -136,14 +156,14 @@ fn move_with_comments(input: &str) -> IResult<&str, (usize, MoveFormat), Verbose
     )(input)
 }
 
-fn moves_with_index(input: &str) -> IResult<&str, (usize, Vec<MoveFormat>), VerboseError<&str>> {
+fn moves_with_index(input: &str) -> IResult<&str, (usize, Vec<MoveFormat>), ParserError<&str>> {
     map(
         terminated(many1(move_with_comments), opt(not_move_line)),
         |v| (v[0].0, v.into_iter().map(|(_, mf)| mf).collect()),
     )(input)
 }
 
-fn main_moves(input: &str) -> IResult<&str, Vec<MoveFormat>, VerboseError<&str>> {
+fn main_moves(input: &str) -> IResult<&str, Vec<MoveFormat>, ParserError<&str>> {
     map(
         pair(opt(many1(move_comment_line)), opt(moves_with_index)),
         |(comments, o)| {
@@ -159,54 +179,102 @@ fn main_moves(input: &str) -> IResult<&str, Vec<MoveFormat>, VerboseError<&str>>
     )(input)
 }
 
-fn entire_moves(input: &str) -> IResult<&str, Vec<MoveFormat>, VerboseError<&str>> {
-    fn merge_forks(
-        (mut moves, mut forks): (Vec<MoveFormat>, Vec<(usize, Vec<MoveFormat>)>),
-    ) -> Vec<MoveFormat> {
-        let mut stack = Vec::new();
-        while let Some(fork) = forks.pop() {
-            stack.push(fork);
-            if let Some((i, last)) = forks.last_mut() {
-                while stack.last().map_or(false, |(j, _)| j >= i) {
-                    if let Some((j, fork)) = stack.pop() {
-                        if let Some(v) = &mut last[j - *i].forks {
-                            v.push(fork);
-                        } else {
-                            last[j - *i].forks = Some(vec![fork]);
-                        }
-                    }
-                }
-            }
-        }
-        while let Some((i, fork)) = stack.pop() {
-            if let Some(v) = &mut moves[i].forks {
-                v.push(fork);
+// The variation with the input at its first move
+fn fork_moves(input: &str) -> IResult<&str, (&str, usize, Vec<MoveFormat>), ParserError<&str>> {
+    map(
+        preceded(many0(not_move_line), consumed(moves_with_index)),
+        |(first, (i, v))| (first, i, v),
+    )(input)
+}
+
+fn entire_moves(input: &str) -> IResult<&str, Vec<MoveFormat>, ParserError<&str>> {
+    let (rest, (moves, forks)) =
+        pair(preceded(opt(not_move_line), main_moves), many0(fork_moves))(input)?;
+    match merge_forks(moves, forks) {
+        Ok(moves) => Ok((rest, moves)),
+        Err(first) => {
+            // Point at the `変化` line just before the first move of the variation if it exists
+            let offset = first.as_ptr() as usize - input.as_ptr() as usize;
+            let before = input[..offset].trim_end();
+            let start = before.rfind('\n').map_or(0, |i| i + 1);
+            let at = if before[start..].starts_with("変化") {
+                &input[start..]
             } else {
-                moves[i].forks = Some(vec![fork]);
-            }
+                first
+            };
+            Err(invalid_fork(at))
         }
-        moves
     }
+}
 
-    map(
-        pair(
-            preceded(opt(not_move_line), main_moves),
-            many0(preceded(many0(not_move_line), moves_with_index)),
-        ),
-        merge_forks,
+fn end_of_moves(input: &str) -> IResult<&str, (), ParserError<&str>> {
+    preceded(
+        trailing_lines,
+        alt((
+            value((), eof),
+            // Parse the remaining line as a move to report where it is broken.
+            // If it can be parsed, the move itself is unexpected here.
+            map_res(move_line, |_| Err(())),
+        )),
     )(input)
 }
 
-pub(crate) fn parse(input: &str) -> IResult<&str, JsonKifuFormat, VerboseError<&str>> {
+pub(crate) fn parse(input: &str) -> IResult<&str, JsonKifuFormat, ParserError<&str>> {
     map(
-        pair(parse_without_moves, entire_moves),
-        |(mut jkf, moves)| {
+        terminated(pair(parse_without_moves, entire_moves), end_of_moves),
+        |(mut jkf, mut moves)| {
+            resolve_specials(&mut moves, 0, jkf.initial_color());
             jkf.moves.extend(moves);
             jkf
         },
     )(input)
 }
 
+/// Returns the errors of the lines of moves which cannot be parsed
+pub(crate) fn broken_move_lines(input: &str) -> Vec<ParserError<&str>> {
+    let is_move = |line: &str| {
+        line.trim_start_matches([' ', '\t'])
+            .starts_with(|c: char| c.is_ascii_digit())
+    };
+    recover::broken_move_lines(input, is_move, move_line)
+}
+
+// Fixes the colors of 反則勝ち with the side to move, and restores the special moves written as 中断 with `&` lines.
+// `moves[0]` is the move at the ply `start`, and `first` is the color of the side to move at the first move.
+fn resolve_specials(moves: &mut [MoveFormat], start: usize, first: Color) {
+    for (ply, mf) in (start..).zip(moves.iter_mut()) {
+        if let Some(forks) = &mut mf.forks {
+            for fork in forks {
+                resolve_specials(fork, ply, first);
+            }
+        }
+        let Some(special) = &mut mf.special else {
+            continue;
+        };
+        let white_to_move = (first == Color::White) == (ply % 2 == 1);
+        if *special == MoveSpecial::SpecialIllegalActionWhite && white_to_move {
+            *special = MoveSpecial::SpecialIllegalActionBlack;
+        }
+        // Only 中断 is annotated, and the other `&` lines are kept as comments such as bookmarks (しおり)
+        if *special != MoveSpecial::SpecialChudan {
+            continue;
+        }
+        let annotated = mf
+            .comments
+            .as_ref()
+            .and_then(|comments| comments.first())
+            .and_then(|comment| comment.strip_prefix('&'))
+            .and_then(MoveSpecial::from_name);
+        if let Some(annotated) = annotated {
+            *special = annotated;
+            if let Some(comments) = &mut mf.comments {
+                comments.remove(0);
+            }
+            mf.comments = mf.comments.take().filter(|v| !v.is_empty());
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,161 @@
+use super::error::ParserError;
+use super::{Diagnostic, ParseOptions};
+use crate::error::{ParseError, SyntaxError};
+use crate::jkf::{JsonKifuFormat, MoveFormat};
+use nom::IResult;
+use std::borrow::Cow;
+
+// Parses `s` and normalizes the result.
+// With `options.recover`, the lines which cannot be parsed are skipped and the moves from an illegal one are truncated,
+// and they are reported as diagnostics.
+//
+// The broken lines of moves found by `broken` are skipped at once before parsing.
+// The other lines are found by parsing the whole input again after skipping each of them,
+// which is expected to happen only a few times, such as in the header.
+pub(super) fn parse_with_options<F, B>(
+    s: &str,
+    options: &ParseOptions,
+    parse: F,
+    broken: B,
+    into_error: fn(SyntaxError) -> ParseError,
+    skipped: fn(SyntaxError) -> Diagnostic,
+) -> Result<(JsonKifuFormat, Vec<Diagnostic>), ParseError>
+where
+    F: Fn(&str) -> Result<JsonKifuFormat, SyntaxError>,
+    B: Fn(&str) -> Vec<SyntaxError>,
+{
+    let mut errors = Vec::new();
+    let mut input = Cow::Borrowed(s);
+    // The line numbers in `s` of each line in `input`
+    let mut lines = (1..=s.split('\n').count()).collect::<Vec<_>>();
+    if options.recover {
+        errors = broken(s);
+        if !errors.is_empty() {
+            // `errors` are in the order of the lines
+            let is_broken =
+                |line: &usize| errors.binary_search_by_key(line, |err| err.line).is_ok();
+            input = Cow::Owned(
+                (1..)
+                    .zip(s.split_inclusive('\n'))
+                    .filter(|(line, _)| !is_broken(line))
+                    .map(|(_, text)| text)
+                    .collect(),
+            );
+            lines.retain(|line| !is_broken(line));
+        }
+    }
+    let mut jkf = loop {
+        let err = match parse(&input) {
+            Ok(jkf) => break jkf,
+            Err(err) => err,
+        };
+        // Restore the position in `s`
+        let start = input[..err.offset].rfind('\n').map_or(0, |i| i + 1);
+        let end = input[err.offset..]
+            .find('\n')
+            .map_or(input.len(), |i| err.offset + i + 1);
+        let line = lines.remove(err.line - 1);
+        let offset = s
+            .split_inclusive('\n')
+            .take(line - 1)
+            .map(str::len)
+            .sum::<usize>()
+            + (err.offset - start);
+        let err = SyntaxError {
+            offset,
+            line,
+            ..err
+        };
+        if !options.recover || start == end {
+            return Err(into_error(err));
+        }
+        errors.push(err);
+        input.to_mut().replace_range(start..end, "");
+    };
+    errors.sort_by_key(|err| err.line);
+    let mut diagnostics = errors.into_iter().map(skipped).collect::<Vec<_>>();
+    loop {
+        let mut normalized = jkf.clone();
+        match normalized.normalize() {
+            Ok(()) => return Ok((normalized, diagnostics)),
+            // An error with the initial position cannot be recovered
+            Err(err) if options.recover && err.ply > 0 => {
+                truncate_moves(&mut jkf.moves, 0, err.ply, &err.forks);
+                diagnostics.push(Diagnostic::TruncatedMoves(err));
+            }
+            Err(err) => return Err(ParseError::Normalize(err)),
+        }
+    }
+}
+
+// Parses each line of `input` where `is_move` holds by `parse`, and returns the errors of the lines which fail.
+// Each line is parsed by itself, since the moves are parsed in the same way wherever they are.
+pub(super) fn broken_move_lines<'a, O, F>(
+    input: &'a str,
+    is_move: fn(&str) -> bool,
+    mut parse: F,
+) -> Vec<ParserError<&'a str>>
+where
+    F: FnMut(&'a str) -> IResult<&'a str, O, ParserError<&'a str>>,
+{
+    input
+        .split_inclusive('\n')
+        .filter(|line| is_move(line))
+        .filter_map(|line| match parse(line) {
+            Err(nom::Err::Error(err) | nom::Err::Failure(err)) => Some(err),
+            _ => None,
+        })
+        .collect()
+}
+
+// Removes the move where the error occurred and the following moves.
+// `moves[0]` is the move at the ply `start`, and `forks` is the path to the variation from `moves`.
+fn truncate_moves(moves: &mut Vec<MoveFormat>, start: usize, ply: usize, forks: &[(usize, usize)]) {
+    match forks.split_first() {
+        None => moves.truncate(ply - start),
+        Some((&(fork_ply, index), rest)) => {
+            let mf = &mut moves[fork_ply - start];
+            if let Some(v) = &mut mf.forks {
+                truncate_moves(&mut v[index], fork_ply, ply, rest);
+                if v[index].is_empty() {
+                    v.remove(index);
+                }
+                if v.is_empty() {
+                    mf.forks = None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_moves_in_fork() {
+        let mf = |forks| MoveFormat {
+            forks,
+            ..Default::default()
+        };
+        let mut moves = vec![
+            mf(None),
+            mf(None),
+            mf(Some(vec![vec![mf(None), mf(None)], vec![mf(None)]])),
+        ];
+        // The second move of the first variation from move 2
+        truncate_moves(&mut moves, 0, 3, &[(2, 0)]);
+        assert_eq!(
+            vec![
+                mf(None),
+                mf(None),
+                mf(Some(vec![vec![mf(None)], vec![mf(None)]])),
+            ],
+            moves
+        );
+        // The variation itself is removed if its first move is illegal
+        truncate_moves(&mut moves, 0, 2, &[(2, 0)]);
+        truncate_moves(&mut moves, 0, 2, &[(2, 0)]);
+        assert_eq!(vec![mf(None), mf(None), mf(None)], moves);
+    }
+}
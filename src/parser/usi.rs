@@ -0,0 +1,279 @@
+use super::error::ParserError;
+use crate::error::{ConvertError, LocatedNormalizeError, NormalizeError};
+use crate::jkf::JsonKifuFormat;
+use nom::branch::alt;
+use nom::bytes::complete::tag;
+use nom::character::complete::{char, digit1, multispace0, one_of, space0, space1};
+use nom::combinator::{cut, eof, map, map_opt, map_res, opt, value};
+use nom::error::context;
+use nom::multi::{many1, separated_list1};
+use nom::sequence::{delimited, pair, preceded, terminated, tuple};
+use nom::IResult;
+use shogi_core::{Color, Move, PartialPosition, Piece, PieceKind, Position, Square};
+
+// A rank of the board in SFEN, such as `lnsgkgsnl` or `1r5b1`.
+// Empty squares are represented by `None`.
+type Rank = Vec<Option<Piece>>;
+
+fn piece_kind(c: char) -> Option<PieceKind> {
+    match c.to_ascii_uppercase() {
+        'P' => Some(PieceKind::Pawn),
+        'L' => Some(PieceKind::Lance),
+        'N' => Some(PieceKind::Knight),
+        'S' => Some(PieceKind::Silver),
+        'G' => Some(PieceKind::Gold),
+        'B' => Some(PieceKind::Bishop),
+        'R' => Some(PieceKind::Rook),
+        'K' => Some(PieceKind::King),
+        _ => None,
+    }
+}
+
+fn piece(input: &str) -> IResult<&str, Piece, ParserError<&str>> {
+    map_opt(
+        pair(opt(char('+')), one_of("PLNSGBRKplnsgbrk")),
+        |(promoted, c)| {
+            let color = if c.is_ascii_uppercase() {
+                Color::Black
+            } else {
+                Color::White
+            };
+            let piece = Piece::new(piece_kind(c)?, color);
+            match promoted {
+                Some(_) => piece.promote(),
+                None => Some(piece),
+            }
+        },
+    )(input)
+}
+
+fn rank(input: &str) -> IResult<&str, Rank, ParserError<&str>> {
+    map_opt(
+        many1(alt((
+            map(piece, |p| vec![Some(p)]),
+            map(one_of("123456789"), |c| {
+                vec![None; c.to_digit(10).unwrap_or_default() as usize]
+            }),
+        ))),
+        |v| Some(v.concat()).filter(|rank| rank.len() == 9),
+    )(input)
+}
+
+fn board(input: &str) -> IResult<&str, Vec<Rank>, ParserError<&str>> {
+    context(
+        "board",
+        map_opt(separated_list1(char('/'), rank), |ranks| {
+            Some(ranks).filter(|ranks| ranks.len() == 9)
+        }),
+    )(input)
+}
+
+fn side_to_move(input: &str) -> IResult<&str, Color, ParserError<&str>> {
+    context(
+        "side to move",
+        alt((
+            value(Color::Black, char('b')),
+            value(Color::White, char('w')),
+        )),
+    )(input)
+}
+
+fn hands(input: &str) -> IResult<&str, Vec<(u8, Piece)>, ParserError<&str>> {
+    context(
+        "hands",
+        alt((
+            value(Vec::new(), char('-')),
+            many1(pair(
+                map(opt(map_res(digit1, str::parse)), |n| n.unwrap_or(1)),
+                map_opt(one_of("PLNSGBRplnsgbr"), |c| {
+                    let color = if c.is_ascii_uppercase() {
+                        Color::Black
+                    } else {
+                        Color::White
+                    };
+                    Some(Piece::new(piece_kind(c)?, color))
+                }),
+            )),
+        )),
+    )(input)
+}
+
+pub(super) fn sfen(input: &str) -> IResult<&str, PartialPosition, ParserError<&str>> {
+    map_opt(
+        tuple((
+            board,
+            preceded(space1, side_to_move),
+            preceded(space1, hands),
+            opt(preceded(space1, map_res(digit1, str::parse::<u16>))),
+        )),
+        |(board, color, hands, ply)| {
+            let mut pos = PartialPosition::empty();
+            for (i, rank) in board.into_iter().enumerate() {
+                for (j, p) in rank.into_iter().enumerate() {
+                    pos.piece_set(Square::new(9 - j as u8, i as u8 + 1)?, p);
+                }
+            }
+            pos.side_to_move_set(color);
+            for (n, p) in hands {
+                let hand = pos.hand_of_a_player_mut(p.color());
+                for _ in 0..n {
+                    *hand = hand.added(p.piece_kind())?;
+                }
+            }
+            if let Some(ply) = ply {
+                pos.ply_set(ply).then_some(())?;
+            }
+            Some(pos)
+        },
+    )(input)
+}
+
+fn square(input: &str) -> IResult<&str, Square, ParserError<&str>> {
+    map_opt(
+        pair(one_of("123456789"), one_of("abcdefghi")),
+        |(file, rank)| Square::new(file as u8 - b'0', rank as u8 - b'a' + 1),
+    )(input)
+}
+
+fn usi_move(input: &str) -> IResult<&str, Move, ParserError<&str>> {
+    context(
+        "move",
+        alt((
+            map(
+                tuple((square, square, opt(char('+')))),
+                |(from, to, promote)| Move::Normal {
+                    from,
+                    to,
+                    promote: promote.is_some(),
+                },
+            ),
+            map_opt(
+                tuple((one_of("PLNSGBR"), char('*'), square)),
+                |(c, _, to)| {
+                    Some(Move::Drop {
+                        piece: Piece::new(piece_kind(c)?, Color::Black),
+                        to,
+                    })
+                },
+            ),
+        )),
+    )(input)
+}
+
+// `position` command of USI, the `position` keyword can be omitted.
+// The colors of the dropped pieces are not decided here since USI doesn't distinguish them.
+pub(super) fn position(
+    input: &str,
+) -> IResult<&str, (PartialPosition, Vec<Move>), ParserError<&str>> {
+    delimited(
+        pair(space0, opt(pair(tag("position"), space1))),
+        pair(
+            context(
+                "position",
+                alt((
+                    value(PartialPosition::startpos(), tag("startpos")),
+                    preceded(pair(tag("sfen"), space1), cut(sfen)),
+                )),
+            ),
+            map(
+                opt(preceded(
+                    tuple((space1, tag("moves"), space1)),
+                    cut(separated_list1(space1, usi_move)),
+                )),
+                Option::unwrap_or_default,
+            ),
+        ),
+        terminated(multispace0, eof),
+    )(input)
+}
+
+// A standalone SFEN string, surrounded by whitespace optionally.
+pub(super) fn standalone_sfen(input: &str) -> IResult<&str, PartialPosition, ParserError<&str>> {
+    delimited(multispace0, sfen, terminated(multispace0, eof))(input)
+}
+
+// Makes the moves from the position and converts them to `JsonKifuFormat`.
+// The colors of the dropped pieces are replaced with the side to move.
+pub(super) fn into_jkf(
+    pos: PartialPosition,
+    moves: Vec<Move>,
+) -> Result<JsonKifuFormat, LocatedNormalizeError> {
+    let mut pos = Position::arbitrary_position(pos);
+    for (ply, mv) in (1..).zip(moves) {
+        let mv = match mv {
+            Move::Drop { piece, to } => Move::Drop {
+                piece: Piece::new(piece.piece_kind(), pos.side_to_move()),
+                to,
+            },
+            mv => mv,
+        };
+        pos.make_move(mv)
+            .ok_or_else(|| NormalizeError::MakeMoveFailed(mv).at(ply))?;
+    }
+    JsonKifuFormat::try_from(&pos).map_err(|err| match err {
+        ConvertError::Normalize(err) => err,
+        // Only the errors of the moves can occur in the conversion from `Position`
+        err => unreachable!("{err}"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sfen() {
+        let startpos = PartialPosition::startpos();
+        assert_eq!(Ok(("", startpos.clone())), sfen(&startpos.to_sfen_owned()));
+
+        let (rest, pos) = sfen("8k/9/9/9/9/9/9/9/K+R7 w 2Pb10p 123").expect("failed to parse");
+        assert_eq!("", rest);
+        assert_eq!(Some(Piece::W_K), pos.piece_at(Square::SQ_1A));
+        assert_eq!(Some(Piece::B_PR), pos.piece_at(Square::SQ_8I));
+        assert_eq!(Color::White, pos.side_to_move());
+        assert_eq!(Some(2), pos.hand(Piece::B_P));
+        assert_eq!(Some(1), pos.hand(Piece::W_B));
+        assert_eq!(Some(10), pos.hand(Piece::W_P));
+        assert_eq!(123, pos.ply());
+
+        // Too many squares in a rank
+        assert!(sfen("9k/9/9/9/9/9/9/9/K8 b -").is_err());
+        // Too few ranks
+        assert!(sfen("8k/9/9/9/9/9/9/K8 b -").is_err());
+    }
+
+    #[test]
+    fn parse_position() {
+        assert_eq!(
+            Ok((
+                "",
+                (
+                    PartialPosition::startpos(),
+                    vec![
+                        Move::Normal {
+                            from: Square::SQ_7G,
+                            to: Square::SQ_7F,
+                            promote: false,
+                        },
+                        Move::Normal {
+                            from: Square::SQ_8H,
+                            to: Square::SQ_2B,
+                            promote: true,
+                        },
+                        Move::Drop {
+                            piece: Piece::B_B,
+                            to: Square::SQ_4E,
+                        },
+                    ]
+                )
+            )),
+            position("position startpos moves 7g7f 8h2b+ B*4e\n")
+        );
+        assert_eq!(
+            Ok(("", (PartialPosition::startpos(), Vec::new()))),
+            position("startpos")
+        );
+        assert!(position("position startpos moves 7g7f 7z").is_err());
+        assert!(position("position foo").is_err());
+    }
+}
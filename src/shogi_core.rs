@@ -1,6 +1,51 @@
+use crate::jkf::{JsonKifuFormat, MoveFormat};
+use shogi_core::{LegalityChecker, Move, PartialPosition};
+use shogi_legality_lite::prelegality::will_king_be_captured;
+use shogi_legality_lite::LiteLegalityChecker;
+
 mod from;
 mod into;
 
+// Whether the side to move is in check
+pub(crate) fn is_in_check(pos: &PartialPosition) -> bool {
+    let mut pos = pos.clone();
+    pos.side_to_move_set(pos.side_to_move().flip());
+    will_king_be_captured(&pos).unwrap_or_default()
+}
+
+// Whether the side to move is checkmated
+pub(crate) fn is_mate(pos: &PartialPosition) -> bool {
+    is_in_check(pos) && LiteLegalityChecker.all_legal_moves_partial(pos).is_empty()
+}
+
+// Replays the moves of a line to find the checks and the checkmate,
+// which is shared by the normalizer and the writers annotating `王手` and `詰み`.
+// The position is `None` after a special move or a move which cannot be made.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CheckReplay(Option<PartialPosition>);
+
+impl CheckReplay {
+    // Starts from the initial position of the kifu, or never finds checks if it cannot be converted
+    pub(crate) fn new(jkf: &JsonKifuFormat) -> Self {
+        CheckReplay(jkf.initial_position().ok())
+    }
+
+    // Makes the move, and returns whether it gives check
+    pub(crate) fn make_move(&mut self, mf: &MoveFormat) -> bool {
+        self.0 = self.0.take().and_then(|mut pos| {
+            let mv = Move::try_from(mf.move_.as_ref()?).ok()?;
+            pos.make_move(mv)?;
+            Some(pos)
+        });
+        self.0.as_ref().is_some_and(is_in_check)
+    }
+
+    // Whether the last move checkmates the opponent
+    pub(crate) fn is_mate(&self) -> bool {
+        self.0.as_ref().is_some_and(is_mate)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::jkf::JsonKifuFormat;
@@ -1,4 +1,4 @@
-use crate::error::{ConvertError, NormalizeError};
+use crate::error::ConvertError;
 use crate::jkf;
 use crate::jkf::{Color::*, Kind::*, Preset::*};
 use shogi_core::{Color, Move, PartialPosition, Piece, PieceKind, Position, Square};
@@ -116,12 +116,16 @@ impl TryFrom<&jkf::Initial> for PartialPosition {
                     PresetHI   => vec![Square::SQ_8B],
                     PresetHIKY => vec![Square::SQ_8B, Square::SQ_1A],
                     Preset2    => vec![Square::SQ_8B, Square::SQ_2B],
+                    Preset3    => vec![Square::SQ_8B, Square::SQ_2B, Square::SQ_1A],
                     Preset4    => vec![Square::SQ_8B, Square::SQ_2B, Square::SQ_9A, Square::SQ_1A],
+                    Preset5    => vec![Square::SQ_8B, Square::SQ_2B, Square::SQ_9A, Square::SQ_1A, Square::SQ_8A],
+                    Preset5L   => vec![Square::SQ_8B, Square::SQ_2B, Square::SQ_9A, Square::SQ_1A, Square::SQ_2A],
                     Preset6    => vec![Square::SQ_8B, Square::SQ_2B, Square::SQ_9A, Square::SQ_1A, Square::SQ_8A, Square::SQ_2A],
+                    Preset7L   => vec![Square::SQ_8B, Square::SQ_2B, Square::SQ_9A, Square::SQ_1A, Square::SQ_8A, Square::SQ_2A, Square::SQ_3A],
+                    Preset7R   => vec![Square::SQ_8B, Square::SQ_2B, Square::SQ_9A, Square::SQ_1A, Square::SQ_8A, Square::SQ_2A, Square::SQ_7A],
                     Preset8    => vec![Square::SQ_8B, Square::SQ_2B, Square::SQ_9A, Square::SQ_1A, Square::SQ_8A, Square::SQ_2A, Square::SQ_7A, Square::SQ_3A],
                     Preset10   => vec![Square::SQ_8B, Square::SQ_2B, Square::SQ_9A, Square::SQ_1A, Square::SQ_8A, Square::SQ_2A, Square::SQ_7A, Square::SQ_3A, Square::SQ_6A, Square::SQ_4A],
-                    // Preset3, Preset5, Preset7...?
-                    _ => unimplemented!(),
+                    PresetHirate | PresetOther => unreachable!(),
                 };
                 for sq in drops {
                     pos.piece_set(sq, None);
@@ -136,20 +140,15 @@ impl TryFrom<&jkf::JsonKifuFormat> for Position {
     type Error = ConvertError;
 
     fn try_from(jkf: &jkf::JsonKifuFormat) -> Result<Self, Self::Error> {
-        let mut pos = if let Some(initial) = &jkf.initial {
-            Position::arbitrary_position(initial.try_into()?)
-        } else {
-            Position::startpos()
-        };
-        for mf in jkf.moves.iter() {
-            if let Some(mv) = &mf.move_ {
-                let mv = mv.try_into()?;
-                pos.make_move(mv).ok_or_else(|| {
-                    ConvertError::Normalize(NormalizeError::MakeMoveFailed(mv).to_string())
-                })?;
-            }
+        let mut positions = jkf.positions();
+        for item in positions.by_ref() {
+            item?;
         }
-        Ok(pos)
+        // The position is kept after the last item unless an error was returned above
+        positions
+            .position()
+            .cloned()
+            .ok_or_else(|| ConvertError::InvalidForkPath(Vec::new()))
     }
 }
 
@@ -1,7 +1,7 @@
 use crate::error::{ConvertError, NormalizeError};
 use crate::jkf;
+use indexmap::IndexMap;
 use shogi_core::{Color, Hand, Move, PartialPosition, Piece, PieceKind, Position, Square};
-use std::collections::HashMap;
 
 impl From<Color> for jkf::Color {
     fn from(c: Color) -> Self {
@@ -94,11 +94,11 @@ impl TryFrom<&Position> for jkf::JsonKifuFormat {
     fn try_from(pos: &Position) -> Result<Self, Self::Error> {
         let mut moves = vec![jkf::MoveFormat::default()];
         let mut pp = pos.initial_position().clone();
-        for &mv in pos.moves() {
+        for (ply, &mv) in (1..).zip(pos.moves()) {
             let mmf = match mv {
                 Move::Normal { from, to, promote } => {
                     let piece = pp.piece_at(from).ok_or_else(|| {
-                        ConvertError::Normalize(NormalizeError::NoPieceAt(from).to_string())
+                        ConvertError::Normalize(NormalizeError::NoPieceAt(from).at(ply))
                     })?;
                     jkf::MoveMoveFormat {
                         color: pp.side_to_move().into(),
@@ -128,17 +128,17 @@ impl TryFrom<&Position> for jkf::JsonKifuFormat {
                 ..Default::default()
             });
             pp.make_move(mv).ok_or_else(|| {
-                ConvertError::Normalize(NormalizeError::MakeMoveFailed(mv).to_string())
+                ConvertError::Normalize(NormalizeError::MakeMoveFailed(mv).at(ply))
             })?;
         }
         let mut ret = jkf::JsonKifuFormat {
-            header: HashMap::new(),
+            header: IndexMap::new(),
             initial: Some(pos.initial_position().into()),
             moves,
         };
         match ret.normalize() {
             Ok(()) => Ok(ret),
-            Err(err) => Err(ConvertError::Normalize(err.to_string())),
+            Err(err) => Err(ConvertError::Normalize(err)),
         }
     }
 }
@@ -176,7 +176,7 @@ mod tests {
         .expect("failed to make move");
         assert_eq!(
             Ok(jkf::JsonKifuFormat {
-                header: HashMap::new(),
+                header: IndexMap::new(),
                 initial: Some(jkf::Initial {
                     preset: jkf::Preset::PresetKY,
                     data: None,
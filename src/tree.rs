@@ -0,0 +1,415 @@
+//! Navigation over the moves and the variations of [`JsonKifuFormat`]
+//!
+//! The variations in [`MoveFormat::forks`] are nested inside the moves.
+//! [`KifuTree`] flattens them into nodes, so that the kifu can be walked without the recursion.
+//!
+//! The nodes are also used to edit the moves of [`JsonKifuFormat`] (e.g. [`JsonKifuFormat::add_move()`]),
+//! which leave the kifu unchanged if they fail,
+//! and this module provides merging and comparing kifus ([`JsonKifuFormat::merge()`], [`JsonKifuFormat::diff()`]).
+
+use crate::error::{ConvertError, LocatedNormalizeError, NormalizeError};
+use crate::jkf::{JsonKifuFormat, MoveFormat};
+use crate::shogi_core::{is_in_check, is_mate};
+use shogi_core::{Move, PartialPosition, Position};
+
+mod declaration;
+mod diff;
+mod edit;
+mod merge;
+mod repetition;
+
+pub use self::declaration::{Declaration, DeclarationRule};
+pub use self::diff::{CommentChange, HeaderChange, KifuDiff, Location, VariationChange};
+pub use self::repetition::Repetition;
+
+/// An identifier of a node in [`KifuTree`]
+///
+/// The nodes are numbered in depth-first order from the initial position,
+/// so the ids are the same as long as the moves of [`JsonKifuFormat`] are not changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(usize);
+
+impl NodeId {
+    /// The index of the node in the depth-first order
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// Whether the side to move is in check or checkmated,
+/// returned by [`KifuTree::check_state()`] and [`KifuTree::check_states()`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CheckState {
+    /// The move gives check (王手)
+    pub check: bool,
+    /// The move checkmates the opponent (詰み)
+    pub mate: bool,
+}
+
+#[derive(Debug)]
+struct Node<'a> {
+    mf: &'a MoveFormat,
+    ply: usize,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    forks: Vec<(usize, usize)>,
+}
+
+/// A tree of the moves of [`JsonKifuFormat`]
+///
+/// The root is the initial position (`moves[0]`), and each of the other nodes is a move or a special move.
+/// The children of a node are the next move of the same line followed by the first moves of its variations.
+#[derive(Debug)]
+pub struct KifuTree<'a> {
+    jkf: &'a JsonKifuFormat,
+    nodes: Vec<Node<'a>>,
+}
+
+impl<'a> KifuTree<'a> {
+    /// Builds the tree of `jkf`
+    pub fn new(jkf: &'a JsonKifuFormat) -> Self {
+        let mut tree = KifuTree {
+            jkf,
+            nodes: Vec::new(),
+        };
+        if let Some((root, moves)) = jkf.moves.split_first() {
+            let id = tree.push(root, 0, None, Vec::new());
+            tree.add_line(moves, 1, id, &[]);
+        }
+        tree
+    }
+
+    fn push(
+        &mut self,
+        mf: &'a MoveFormat,
+        ply: usize,
+        parent: Option<NodeId>,
+        forks: Vec<(usize, usize)>,
+    ) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        self.nodes.push(Node {
+            mf,
+            ply,
+            parent,
+            children: Vec::new(),
+            forks,
+        });
+        if let Some(parent) = parent {
+            self.nodes[parent.0].children.push(id);
+        }
+        id
+    }
+
+    // Adds the nodes of `moves`, whose first move is at the ply `start` after the node `parent`.
+    // `forks` is the path to the variation of `moves`.
+    fn add_line(
+        &mut self,
+        moves: &'a [MoveFormat],
+        start: usize,
+        mut parent: NodeId,
+        forks: &[(usize, usize)],
+    ) {
+        for (ply, mf) in (start..).zip(moves) {
+            let id = self.push(mf, ply, Some(parent), forks.to_vec());
+            for (i, fork) in mf.forks.iter().flatten().enumerate() {
+                let path = [forks, &[(ply, i)]].concat();
+                self.add_line(fork, ply, parent, &path);
+            }
+            parent = id;
+        }
+    }
+
+    /// Returns the id of the root node, which is the initial position
+    ///
+    /// # Panics
+    ///
+    /// Panics if [`JsonKifuFormat::moves`] is empty.
+    pub fn root(&self) -> NodeId {
+        assert!(!self.nodes.is_empty(), "no initial position");
+        NodeId(0)
+    }
+
+    /// Returns the number of the nodes
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns `true` if the tree has no nodes
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Returns the ids of all nodes in depth-first order
+    pub fn ids(&self) -> impl Iterator<Item = NodeId> {
+        (0..self.nodes.len()).map(NodeId)
+    }
+
+    /// Returns the move of the node, or `None` if the id is not of this tree
+    pub fn get(&self, id: NodeId) -> Option<&'a MoveFormat> {
+        self.nodes.get(id.0).map(|node| node.mf)
+    }
+
+    /// Returns the ply number of the node, which is 0 for the root
+    ///
+    /// # Panics
+    ///
+    /// Panics if the id is not of this tree. Use [`KifuTree::get()`] to check it.
+    pub fn ply(&self, id: NodeId) -> usize {
+        self.nodes[id.0].ply
+    }
+
+    /// Returns the parent of the node, or `None` for the root
+    ///
+    /// # Panics
+    ///
+    /// Panics if the id is not of this tree. Use [`KifuTree::get()`] to check it.
+    pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+        self.nodes[id.0].parent
+    }
+
+    /// Returns the children of the node
+    ///
+    /// The first child is the next move of the same line, and the others are the first moves of the variations.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the id is not of this tree. Use [`KifuTree::get()`] to check it.
+    pub fn children(&self, id: NodeId) -> &[NodeId] {
+        &self.nodes[id.0].children
+    }
+
+    /// Returns the path to the variation which contains the node, as used by [`JsonKifuFormat::line()`]
+    ///
+    /// # Panics
+    ///
+    /// Panics if the id is not of this tree. Use [`KifuTree::get()`] to check it.
+    pub fn forks(&self, id: NodeId) -> &[(usize, usize)] {
+        &self.nodes[id.0].forks
+    }
+
+    /// Returns the node at the ply in the variation of the path `forks`, or `None` if there is no such node
+    ///
+    /// This function scans the nodes from the root, so it takes the time proportional to the number of the nodes.
+    pub fn find(&self, forks: &[(usize, usize)], ply: usize) -> Option<NodeId> {
+        self.ids()
+            .find(|&id| self.ply(id) == ply && self.forks(id) == forks)
+    }
+
+    /// Returns the nodes of the main line, starting from the root
+    pub fn main_line(&self) -> Vec<NodeId> {
+        let mut line = Vec::new();
+        let mut next = (!self.nodes.is_empty()).then_some(NodeId(0));
+        while let Some(id) = next {
+            line.push(id);
+            next = self.children(id).first().copied();
+        }
+        line
+    }
+
+    /// Returns the nodes from the root to the node, inclusive
+    ///
+    /// # Panics
+    ///
+    /// Panics if the id is not of this tree. Use [`KifuTree::get()`] to check it.
+    pub fn path_to(&self, id: NodeId) -> Vec<NodeId> {
+        let mut path = vec![id];
+        while let Some(parent) = self.parent(path[path.len() - 1]) {
+            path.push(parent);
+        }
+        path.reverse();
+        path
+    }
+
+    /// Returns the position after the move of the node
+    ///
+    /// The moves are replayed from the initial position on each call.
+    /// To get the positions of a whole line, [`JsonKifuFormat::line_positions()`] is faster.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`ConvertError`] if the initial position or a move to the node cannot be converted,
+    /// or a move cannot be made.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the id is not of this tree. Use [`KifuTree::get()`] to check it.
+    pub fn position(&self, id: NodeId) -> Result<Position, ConvertError> {
+        let forks = self.forks(id);
+        let invalid = || ConvertError::InvalidForkPath(forks.to_vec());
+        let mut positions = self.jkf.line_positions(forks).ok_or_else(invalid)?;
+        for item in positions.by_ref().take(self.ply(id) + 1) {
+            item?;
+        }
+        positions.position().cloned().ok_or_else(invalid)
+    }
+
+    /// Returns whether the move of the node gives check, and whether it checkmates the opponent
+    ///
+    /// For the root and the special moves, the state of the position at the node is returned.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`ConvertError`] in the same cases as [`KifuTree::position()`].
+    ///
+    /// # Panics
+    ///
+    /// Panics in the same cases as [`KifuTree::position()`].
+    pub fn check_state(&self, id: NodeId) -> Result<CheckState, ConvertError> {
+        let pos = self.position(id)?;
+        Ok(CheckState {
+            check: is_in_check(pos.inner()),
+            mate: is_mate(pos.inner()),
+        })
+    }
+
+    /// Returns the states of all nodes as [`KifuTree::check_state()`], indexed by [`NodeId::index()`]
+    ///
+    /// The moves are replayed only once for the whole tree,
+    /// so this is faster than calling [`KifuTree::check_state()`] for each node.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`ConvertError`] if the initial position or a move cannot be converted,
+    /// or a move cannot be made.
+    pub fn check_states(&self) -> Result<Vec<CheckState>, ConvertError> {
+        let initial = self.jkf.initial_position()?;
+        // The parent of a node always precedes it in depth-first order
+        let mut positions = Vec::<PartialPosition>::with_capacity(self.len());
+        for id in self.ids() {
+            let mut pos = match self.parent(id) {
+                Some(parent) => positions[parent.0].clone(),
+                None => initial.clone(),
+            };
+            let node = &self.nodes[id.0];
+            if let Some(mmf) = &node.mf.move_ {
+                let mv = Move::try_from(mmf)?;
+                pos.make_move(mv).ok_or_else(|| {
+                    ConvertError::Normalize(LocatedNormalizeError {
+                        error: NormalizeError::MakeMoveFailed(mv),
+                        ply: node.ply,
+                        forks: node.forks.clone(),
+                    })
+                })?;
+            }
+            positions.push(pos);
+        }
+        Ok(positions
+            .iter()
+            .map(|pos| CheckState {
+                check: is_in_check(pos),
+                mate: is_mate(pos),
+            })
+            .collect())
+    }
+}
+
+impl JsonKifuFormat {
+    /// Returns the tree of the moves and the variations
+    pub fn tree(&self) -> KifuTree<'_> {
+        KifuTree::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_kif_file;
+    use shogi_core::{Color, Square};
+
+    #[test]
+    fn navigate() {
+        let jkf = parse_kif_file("data/tests/kif/forks.kif").expect("failed to parse kif");
+        let tree = jkf.tree();
+        let root = tree.root();
+        assert_eq!(None, tree.parent(root));
+        assert_eq!(Some(&jkf.moves[0]), tree.get(root));
+
+        let main_line = tree.main_line();
+        assert_eq!(jkf.moves.len(), main_line.len());
+        for (ply, &id) in main_line.iter().enumerate() {
+            assert_eq!(ply, tree.ply(id));
+            assert_eq!(Some(&jkf.moves[ply]), tree.get(id));
+            assert!(tree.forks(id).is_empty());
+        }
+
+        // The variation from the 10th move
+        let children = tree.children(main_line[9]);
+        assert_eq!(2, children.len());
+        assert_eq!(main_line[10], children[0]);
+        let fork = children[1];
+        assert_eq!(10, tree.ply(fork));
+        assert_eq!(&[(10, 0)], tree.forks(fork));
+        assert_eq!(main_line[..10], tree.path_to(fork)[..10]);
+        assert_eq!(
+            jkf.line(&[(10, 0)]).expect("line should exist")[10],
+            tree.get(fork).expect("node should exist")
+        );
+
+        // Every node is reachable from the root
+        for id in tree.ids() {
+            assert_eq!(root, tree.path_to(id)[0]);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn foreign_id() {
+        let jkf = parse_kif_file("data/tests/kif/forks.kif").expect("failed to parse kif");
+        let tree = jkf.tree();
+        let id = NodeId(tree.len());
+        assert_eq!(None, tree.get(id));
+        tree.children(id);
+    }
+
+    #[test]
+    fn check_state() {
+        let jkf = crate::parser::parse_usi_str(
+            "position sfen 4k4/9/p3P4/9/9/9/9/9/4K4 b G 1 moves 5i4i 9c9d G*5b",
+        )
+        .expect("failed to parse usi");
+        let tree = jkf.tree();
+        let states = tree
+            .main_line()
+            .into_iter()
+            .map(|id| tree.check_state(id).expect("failed to get check state"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                CheckState::default(),
+                CheckState::default(),
+                CheckState::default(),
+                CheckState {
+                    check: true,
+                    mate: true,
+                },
+            ],
+            states
+        );
+        assert_eq!(Ok(states), tree.check_states());
+
+        // The same as each node in the variations
+        let jkf = parse_kif_file("data/tests/kif/forks.kif").expect("failed to parse kif");
+        let tree = jkf.tree();
+        assert_eq!(
+            tree.ids()
+                .map(|id| tree.check_state(id))
+                .collect::<Result<Vec<_>, _>>(),
+            tree.check_states()
+        );
+    }
+
+    #[test]
+    fn position() {
+        let jkf = parse_kif_file("data/tests/kif/forks.kif").expect("failed to parse kif");
+        let tree = jkf.tree();
+        let pos = tree.position(tree.root()).expect("failed to get position");
+        assert_eq!(&PartialPosition::startpos(), pos.initial_position());
+        assert!(pos.moves().is_empty());
+
+        let fork = tree.children(tree.main_line()[9])[1];
+        let pos = tree.position(fork).expect("failed to get position");
+        assert_eq!(10, pos.moves().len());
+        assert_eq!(Color::Black, pos.side_to_move());
+        assert_eq!(Some(shogi_core::Piece::W_B), pos.piece_at(Square::SQ_3C));
+    }
+}
@@ -0,0 +1,149 @@
+use crate::jkf::{Color, Outcome};
+use crate::shogi_core::is_in_check;
+use shogi_core::{Hand, PieceKind, Position, Square};
+
+/// The rule of the declaration by the entering king (入玉宣言法)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DeclarationRule {
+    /// 27点法, which is used in the CSA protocol.
+    /// The declaring side wins with 28 points or more for Black, and 27 points or more for White.
+    #[default]
+    Points27,
+    /// 24点法.
+    /// The declaring side wins with 31 points or more, and the game is drawn (持将棋) with 24 to 30 points.
+    Points24,
+}
+
+/// The conditions of the declaration in a position, returned by [`Declaration::evaluate()`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Declaration {
+    /// The rule to evaluate the declaration
+    pub rule: DeclarationRule,
+    /// The declaring side, which is the side to move
+    pub color: Color,
+    /// Whether the king of the declaring side is in the promotion zone
+    pub king_in_zone: bool,
+    /// The number of the pieces of the declaring side in the promotion zone, except for the king
+    pub pieces_in_zone: u8,
+    /// The points of the pieces in the promotion zone and in hand, except for the king.
+    /// 飛 and 角 (including the promoted ones) count 5 points, and the others count 1 point.
+    pub points: u8,
+    /// Whether the king of the declaring side is in check
+    pub in_check: bool,
+}
+
+// The points of the piece kind in the declaration
+fn points(piece_kind: PieceKind) -> u8 {
+    match piece_kind {
+        PieceKind::Bishop | PieceKind::Rook | PieceKind::ProBishop | PieceKind::ProRook => 5,
+        PieceKind::King => 0,
+        _ => 1,
+    }
+}
+
+impl Declaration {
+    /// Evaluates the declaration by the side to move of the position
+    pub fn evaluate(pos: &Position, rule: DeclarationRule) -> Self {
+        let pos = pos.inner();
+        let color = pos.side_to_move();
+        let mut king_in_zone = false;
+        let mut pieces_in_zone = 0;
+        let mut total = 0;
+        for piece_kind in Square::all()
+            .filter(|sq| sq.relative_rank(color) <= 3)
+            .filter_map(|sq| pos.piece_at(sq))
+            .filter(|piece| piece.color() == color)
+            .map(|piece| piece.piece_kind())
+        {
+            if piece_kind == PieceKind::King {
+                king_in_zone = true;
+            } else {
+                pieces_in_zone += 1;
+                total += points(piece_kind);
+            }
+        }
+        let hand = pos.hand_of_a_player(color);
+        total += Hand::all_hand_pieces()
+            .map(|piece_kind| hand.count(piece_kind).unwrap_or_default() * points(piece_kind))
+            .sum::<u8>();
+        Declaration {
+            rule,
+            color: color.into(),
+            king_in_zone,
+            pieces_in_zone,
+            points: total,
+            in_check: is_in_check(pos),
+        }
+    }
+
+    /// Whether the conditions other than the points are satisfied:
+    /// the king is in the promotion zone, 10 pieces or more are in the zone, and the king is not in check
+    pub fn is_eligible(&self) -> bool {
+        self.king_in_zone && self.pieces_in_zone >= 10 && !self.in_check
+    }
+
+    /// Returns the outcome of the game by the declaration
+    ///
+    /// The declaring side loses if the conditions are not satisfied.
+    pub fn outcome(&self) -> Outcome {
+        let (win, draw) = match (self.rule, self.color) {
+            (DeclarationRule::Points27, Color::Black) => (28, None),
+            (DeclarationRule::Points27, Color::White) => (27, None),
+            (DeclarationRule::Points24, _) => (31, Some(24)),
+        };
+        match self.color {
+            _ if self.is_eligible() && self.points >= win => Outcome::Win(self.color),
+            _ if self.is_eligible() && draw.is_some_and(|draw| self.points >= draw) => {
+                Outcome::Draw
+            }
+            Color::Black => Outcome::Win(Color::White),
+            Color::White => Outcome::Win(Color::Black),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_usi_str;
+
+    fn position(sfen: &str) -> Position {
+        let jkf = parse_usi_str(&format!("position sfen {sfen}")).expect("failed to parse usi");
+        Position::try_from(&jkf).expect("failed to convert")
+    }
+
+    #[test]
+    fn evaluate() {
+        // 2 rooks, 2 bishops and 8 others in the zone, and 8 pawns in hand
+        let pos = position("RBRB1GGSS/1K1+P1+P3/9/9/9/9/9/9/4k4 b 8P 1");
+        let declaration = Declaration::evaluate(&pos, DeclarationRule::Points27);
+        assert_eq!(
+            Declaration {
+                rule: DeclarationRule::Points27,
+                color: Color::Black,
+                king_in_zone: true,
+                pieces_in_zone: 10,
+                points: 34,
+                in_check: false,
+            },
+            declaration
+        );
+        assert_eq!(Outcome::Win(Color::Black), declaration.outcome());
+
+        // 9 pieces in the zone
+        let pos = position("RBRB1GGS1/1K1+P1+P3/9/9/9/9/9/9/4k4 b S8P 1");
+        let declaration = Declaration::evaluate(&pos, DeclarationRule::Points27);
+        assert_eq!(9, declaration.pieces_in_zone);
+        assert_eq!(Outcome::Win(Color::White), declaration.outcome());
+
+        // 25 points of White is a draw by the 24-point rule
+        let pos = position("4K4/9/9/9/9/9/9/1+p1+p1gg2/rbsb1ssk1 w 3p 1");
+        let declaration = Declaration::evaluate(&pos, DeclarationRule::Points24);
+        assert!(declaration.is_eligible());
+        assert_eq!(25, declaration.points);
+        assert_eq!(Outcome::Draw, declaration.outcome());
+        // White needs 27 points by the 27-point rule
+        let declaration = Declaration::evaluate(&pos, DeclarationRule::Points27);
+        assert_eq!(Outcome::Win(Color::Black), declaration.outcome());
+    }
+}
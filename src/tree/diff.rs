@@ -0,0 +1,432 @@
+use super::merge::same_move;
+use super::{KifuTree, NodeId};
+use crate::converter::{write_initial, write_move_line};
+use crate::jkf::{Color, Initial, JsonKifuFormat, MoveFormat, Preset};
+use std::fmt::{self, Write};
+
+/// A change of an entry of [`JsonKifuFormat::header`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HeaderChange {
+    /// The entry exists only in the new kifu
+    Added {
+        /// The key of the entry
+        key: String,
+        /// The value in the new kifu
+        value: String,
+    },
+    /// The entry exists only in the old kifu
+    Removed {
+        /// The key of the entry
+        key: String,
+        /// The value in the old kifu
+        value: String,
+    },
+    /// The value of the entry is changed
+    Changed {
+        /// The key of the entry
+        key: String,
+        /// The value in the old kifu
+        old: String,
+        /// The value in the new kifu
+        new: String,
+    },
+}
+
+/// The location of a move in a kifu
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    /// The ply number of the move
+    pub ply: usize,
+    /// The path to the variation which contains the move, as used by [`JsonKifuFormat::line()`]
+    pub forks: Vec<(usize, usize)>,
+}
+
+/// A line of moves which exists only in one of the kifus
+///
+/// If the main lines diverge, the rest of the main line of each kifu is also reported as a variation with the empty `forks`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VariationChange {
+    /// The line exists only in the new kifu
+    Added {
+        /// The location of the first move in the new kifu
+        location: Location,
+        /// The moves of the line, without their comments and variations
+        moves: Vec<MoveFormat>,
+    },
+    /// The line exists only in the old kifu
+    Removed {
+        /// The location of the first move in the old kifu
+        location: Location,
+        /// The moves of the line, without their comments and variations
+        moves: Vec<MoveFormat>,
+    },
+}
+
+/// A change of the comments of a move which exists in both kifus
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommentChange {
+    /// The location of the move in the new kifu
+    pub location: Location,
+    /// The move, without its comments and variations
+    pub mf: MoveFormat,
+    /// The comments in the old kifu
+    pub old: Vec<String>,
+    /// The comments in the new kifu
+    pub new: Vec<String>,
+}
+
+/// The structural differences between two kifus, returned by [`JsonKifuFormat::diff()`]
+///
+/// The moves are matched by the moves themselves regardless of the order of the variations,
+/// so promoting a variation to the main line changes only [`KifuDiff::diverging_ply`].
+///
+/// The [`Display`](fmt::Display) implementation writes a human-readable report,
+/// where the changed lines are written in KIF format with the prefix `-` or `+`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KifuDiff {
+    /// The changes of the header entries
+    pub header: Vec<HeaderChange>,
+    /// The old and the new initial positions if they are different
+    pub initial: Option<(Initial, Initial)>,
+    /// The first ply where the main lines are different, or `None` if they are the same
+    pub diverging_ply: Option<usize>,
+    /// The lines which exist only in one of the kifus
+    pub variations: Vec<VariationChange>,
+    /// The changes of the comments
+    pub comments: Vec<CommentChange>,
+    // The colors of the side to move at the first move of the old and the new kifus
+    first: (Color, Color),
+}
+
+impl KifuDiff {
+    /// Returns `true` if there are no differences
+    pub fn is_empty(&self) -> bool {
+        self.header.is_empty()
+            && self.initial.is_none()
+            && self.diverging_ply.is_none()
+            && self.variations.is_empty()
+            && self.comments.is_empty()
+    }
+}
+
+// Writes each line of the string with the prefix.
+fn write_prefixed(f: &mut fmt::Formatter<'_>, prefix: char, s: &str) -> fmt::Result {
+    s.lines().try_for_each(|line| writeln!(f, "{prefix}{line}"))
+}
+
+// Writes the label of the variation which contains the move, and the line of the move.
+fn write_location(
+    f: &mut fmt::Formatter<'_>,
+    location: &Location,
+    mf: &MoveFormat,
+    first: Color,
+) -> fmt::Result {
+    if let Some((ply, _)) = location.forks.last() {
+        writeln!(f, "変化：{ply}手")?;
+    }
+    if location.ply == 0 {
+        // The comments of the initial position are written after this line in KIF
+        writeln!(f, "手数----指手---------消費時間--")
+    } else {
+        let mut s = String::new();
+        write_move_line(mf, location.ply, first, &mut s)?;
+        f.write_str(&s)
+    }
+}
+
+fn write_comments(f: &mut fmt::Formatter<'_>, prefix: char, comments: &[String]) -> fmt::Result {
+    for comment in comments {
+        let star = if comment.starts_with('&') { "" } else { "*" };
+        writeln!(f, "{prefix}{star}{comment}")?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for KifuDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.header.is_empty() {
+            writeln!(f, "# header")?;
+            for change in &self.header {
+                match change {
+                    HeaderChange::Added { key, value } => writeln!(f, "+{key}：{value}")?,
+                    HeaderChange::Removed { key, value } => writeln!(f, "-{key}：{value}")?,
+                    HeaderChange::Changed { key, old, new } => {
+                        writeln!(f, "-{key}：{old}")?;
+                        writeln!(f, "+{key}：{new}")?;
+                    }
+                }
+            }
+        }
+        if let Some((old, new)) = &self.initial {
+            writeln!(f, "# initial position")?;
+            for (prefix, initial) in [('-', old), ('+', new)] {
+                let mut s = String::new();
+                write_initial(&Some(*initial), false, &mut s)?;
+                write_prefixed(f, prefix, &s)?;
+            }
+        }
+        if let Some(ply) = self.diverging_ply {
+            writeln!(f, "# main line diverges at move {ply}")?;
+        }
+        if !self.variations.is_empty() {
+            writeln!(f, "# variations")?;
+            for change in &self.variations {
+                let (prefix, location, moves, first) = match change {
+                    VariationChange::Added { location, moves } => {
+                        ('+', location, moves, self.first.1)
+                    }
+                    VariationChange::Removed { location, moves } => {
+                        ('-', location, moves, self.first.0)
+                    }
+                };
+                let mut s = String::new();
+                match location.forks.last() {
+                    Some((ply, _)) => writeln!(s, "変化：{ply}手")?,
+                    None => writeln!(s, "本譜：{}手", location.ply)?,
+                }
+                for (ply, mf) in (location.ply..).zip(moves) {
+                    write_move_line(mf, ply, first, &mut s)?;
+                }
+                write_prefixed(f, prefix, &s)?;
+            }
+        }
+        if !self.comments.is_empty() {
+            writeln!(f, "# comments")?;
+            for change in &self.comments {
+                write_location(f, &change.location, &change.mf, self.first.1)?;
+                write_comments(f, '-', &change.old)?;
+                write_comments(f, '+', &change.new)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The move without its comments and variations
+fn stripped(mf: &MoveFormat) -> MoveFormat {
+    MoveFormat {
+        comments: None,
+        forks: None,
+        ..mf.clone()
+    }
+}
+
+fn location(tree: &KifuTree, id: NodeId) -> Location {
+    Location {
+        ply: tree.ply(id),
+        forks: tree.forks(id).to_vec(),
+    }
+}
+
+// The moves of the line from the node to the end
+fn line_from(tree: &KifuTree, mut id: NodeId) -> Vec<MoveFormat> {
+    let mut moves = Vec::new();
+    loop {
+        moves.extend(tree.get(id).map(stripped));
+        match tree.children(id).first() {
+            Some(&next) => id = next,
+            None => return moves,
+        }
+    }
+}
+
+// Compares the matched nodes and their descendants.
+fn diff_nodes(old: &KifuTree, new: &KifuTree, a: NodeId, b: NodeId, diff: &mut KifuDiff) {
+    let (Some(ma), Some(mb)) = (old.get(a), new.get(b)) else {
+        return;
+    };
+    if ma.comments != mb.comments {
+        diff.comments.push(CommentChange {
+            location: location(new, b),
+            mf: stripped(mb),
+            old: ma.comments.clone().unwrap_or_default(),
+            new: mb.comments.clone().unwrap_or_default(),
+        });
+    }
+    let mut unmatched = old.children(a).to_vec();
+    for &c in new.children(b) {
+        let Some(mc) = new.get(c) else {
+            continue;
+        };
+        match unmatched
+            .iter()
+            .position(|&o| old.get(o).is_some_and(|mo| same_move(mo, mc)))
+        {
+            Some(i) => {
+                let o = unmatched.remove(i);
+                diff_nodes(old, new, o, c, diff);
+            }
+            None => diff.variations.push(VariationChange::Added {
+                location: location(new, c),
+                moves: line_from(new, c),
+            }),
+        }
+    }
+    for o in unmatched {
+        diff.variations.push(VariationChange::Removed {
+            location: location(old, o),
+            moves: line_from(old, o),
+        });
+    }
+}
+
+impl JsonKifuFormat {
+    /// Returns the structural differences from `self` (the old kifu) to `other` (the new kifu)
+    ///
+    /// The differences of the times of the moves are ignored.
+    pub fn diff(&self, other: &JsonKifuFormat) -> KifuDiff {
+        let mut header = Vec::new();
+        for (key, value) in &self.header {
+            match other.header.get(key) {
+                None => header.push(HeaderChange::Removed {
+                    key: key.clone(),
+                    value: value.clone(),
+                }),
+                Some(new) if new != value => header.push(HeaderChange::Changed {
+                    key: key.clone(),
+                    old: value.clone(),
+                    new: new.clone(),
+                }),
+                Some(_) => {}
+            }
+        }
+        for (key, value) in &other.header {
+            if !self.header.contains_key(key) {
+                header.push(HeaderChange::Added {
+                    key: key.clone(),
+                    value: value.clone(),
+                });
+            }
+        }
+
+        // `None` is the same as the hirate preset
+        let initial = |jkf: &JsonKifuFormat| {
+            jkf.initial.unwrap_or(Initial {
+                preset: Preset::PresetHirate,
+                data: None,
+            })
+        };
+        let (old_initial, new_initial) = (initial(self), initial(other));
+
+        let diverging_ply = (1..self.moves.len().max(other.moves.len())).find(|&ply| {
+            match (self.moves.get(ply), other.moves.get(ply)) {
+                (Some(a), Some(b)) => !same_move(a, b),
+                _ => true,
+            }
+        });
+
+        let mut diff = KifuDiff {
+            header,
+            initial: (old_initial != new_initial).then_some((old_initial, new_initial)),
+            diverging_ply,
+            variations: Vec::new(),
+            comments: Vec::new(),
+            first: (self.initial_color(), other.initial_color()),
+        };
+        let (old, new) = (self.tree(), other.tree());
+        if !old.is_empty() && !new.is_empty() {
+            diff_nodes(&old, &new, old.root(), new.root(), &mut diff);
+        }
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_csa_str, parse_kif_file};
+
+    #[test]
+    fn diff() {
+        let jkf = parse_kif_file("data/tests/kif/forks.kif").expect("failed to parse kif");
+        assert!(jkf.diff(&jkf).is_empty());
+        assert_eq!("", jkf.diff(&jkf).to_string());
+
+        let old = parse_csa_str("N+Alice\nN-Bob\nPI\n+\n+7776FU\n-3334FU\n+2726FU\n")
+            .expect("failed to parse csa");
+        let new = parse_csa_str(
+            "N+Alice\nN-Carol\n$EVENT:Test\nPI\n+\n+7776FU\n'good\n-3334FU\n+8822UM\n-3122GI\n",
+        )
+        .expect("failed to parse csa");
+        let diff = old.diff(&new);
+        assert_eq!(
+            vec![
+                HeaderChange::Changed {
+                    key: String::from("後手"),
+                    old: String::from("Bob"),
+                    new: String::from("Carol"),
+                },
+                HeaderChange::Added {
+                    key: String::from("棋戦"),
+                    value: String::from("Test"),
+                },
+            ],
+            diff.header
+        );
+        assert_eq!(None, diff.initial);
+        assert_eq!(Some(3), diff.diverging_ply);
+        assert_eq!(2, diff.variations.len());
+        assert!(matches!(
+            &diff.variations[0],
+            VariationChange::Added { location, moves } if location.ply == 3 && location.forks.is_empty() && moves.len() == 2
+        ));
+        assert!(matches!(
+            &diff.variations[1],
+            VariationChange::Removed { location, moves } if location.ply == 3 && moves.len() == 1
+        ));
+        assert_eq!(1, diff.comments.len());
+        assert_eq!(1, diff.comments[0].location.ply);
+        assert_eq!(vec![String::from("good")], diff.comments[0].new);
+        assert_eq!(
+            "\
+# header
+-後手：Bob
++後手：Carol
++棋戦：Test
+# main line diverges at move 3
+# variations
++本譜：3手
++   3 ２二角成(88)
++   4 同　銀(31)
+-本譜：3手
+-   3 ２六歩(27)
+# comments
+   1 ７六歩(77)
++*good
+",
+            diff.to_string()
+        );
+
+        // Promoting a variation changes only the main line
+        let mut promoted = jkf.clone();
+        let tree = promoted.tree();
+        let fork = tree.children(tree.main_line()[9])[1];
+        promoted.promote_variation(fork).expect("failed to promote");
+        let diff = jkf.diff(&promoted);
+        assert_eq!(Some(10), diff.diverging_ply);
+        assert!(diff.variations.is_empty());
+
+        // Deleting a variation
+        let mut deleted = jkf.clone();
+        let tree = deleted.tree();
+        let fork = tree.children(tree.main_line()[9])[1];
+        deleted.delete_variation(fork).expect("failed to delete");
+        let diff = jkf.diff(&deleted);
+        assert_eq!(None, diff.diverging_ply);
+        assert!(matches!(
+            &diff.variations[..],
+            [VariationChange::Removed { location, .. }] if location == &Location { ply: 10, forks: vec![(10, 0)] }
+        ));
+
+        let mut handicap = jkf.clone();
+        handicap.initial = Some(Initial {
+            preset: Preset::PresetKY,
+            data: None,
+        });
+        let diff = jkf.diff(&handicap);
+        assert!(diff.initial.is_some());
+        assert!(diff
+            .to_string()
+            .contains("-手合割：平手\n+手合割：香落ち\n"));
+    }
+}
@@ -0,0 +1,392 @@
+use super::NodeId;
+use crate::error::EditError;
+use crate::jkf::{JsonKifuFormat, MoveFormat, MoveMoveFormat, PlaceFormat};
+use shogi_core::{LegalityChecker, Move, PartialPosition};
+use shogi_legality_lite::LiteLegalityChecker;
+
+// Converts the move into `MoveMoveFormat`, which will be completed by the normalizer.
+fn move_format(pos: &PartialPosition, mv: Move) -> Option<MoveMoveFormat> {
+    Some(match mv {
+        Move::Normal { from, to, promote } => MoveMoveFormat {
+            color: pos.side_to_move().into(),
+            from: Some((&from).into()),
+            to: (&to).into(),
+            piece: pos.piece_at(from)?.piece_kind().into(),
+            same: None,
+            promote: Some(promote),
+            capture: None,
+            relative: None,
+        },
+        // `from` of `(0, 0)` is the marker of a drop, as in the conversion from `Position`
+        Move::Drop { piece, to } => MoveMoveFormat {
+            color: pos.side_to_move().into(),
+            from: Some(PlaceFormat { x: 0, y: 0 }),
+            to: (&to).into(),
+            piece: piece.piece_kind().into(),
+            same: None,
+            promote: None,
+            capture: None,
+            relative: None,
+        },
+    })
+}
+
+impl JsonKifuFormat {
+    // Returns the path to the variation which contains the node and the ply number of the node.
+    fn locate(&self, id: NodeId) -> Result<(Vec<(usize, usize)>, usize), EditError> {
+        let tree = self.tree();
+        tree.get(id).ok_or(EditError::NodeNotFound(id))?;
+        Ok((tree.forks(id).to_vec(), tree.ply(id)))
+    }
+
+    // Returns the moves of the variation at the path `forks` and the ply number of its first move.
+    fn line_mut(&mut self, forks: &[(usize, usize)]) -> Option<(&mut Vec<MoveFormat>, usize)> {
+        let (mut moves, mut start) = (&mut self.moves, 0);
+        for &(ply, index) in forks {
+            moves = ply
+                .checked_sub(start)
+                .and_then(|i| moves.get_mut(i))?
+                .forks
+                .as_mut()?
+                .get_mut(index)?;
+            start = ply;
+        }
+        Some((moves, start))
+    }
+
+    /// Adds the move after the node, and returns the id of the node of the move
+    ///
+    /// If the node is the last one of its line, the move is appended to the line.
+    /// Otherwise the move is added as a new variation, unless it is the same as the next move or the first move of a variation.
+    /// In that case nothing is changed and the id of the existing node is returned.
+    ///
+    /// The ids of [`KifuTree`](super::KifuTree) are changed by editing, so the returned id is of the new tree.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`EditError`] if the node doesn't exist or is a special move,
+    /// or the move is not legal in the position.
+    pub fn add_move(&mut self, parent: NodeId, mv: Move) -> Result<NodeId, EditError> {
+        let tree = self.tree();
+        let mf = tree.get(parent).ok_or(EditError::NodeNotFound(parent))?;
+        if mf.special.is_some() {
+            return Err(EditError::AfterSpecialMove);
+        }
+        let pos = tree.position(parent)?;
+        LiteLegalityChecker
+            .is_legal_partial(pos.inner(), mv)
+            .map_err(|kind| EditError::IllegalMove(mv, kind))?;
+        let children = tree.children(parent);
+        if let Some(&id) = children.iter().find(|&&id| {
+            tree.get(id)
+                .and_then(|mf| mf.move_.as_ref())
+                .and_then(|mmf| Move::try_from(mmf).ok())
+                == Some(mv)
+        }) {
+            return Ok(id);
+        }
+        let ply = tree.ply(parent) + 1;
+        let mut forks = tree.forks(parent).to_vec();
+        let has_next = !children.is_empty();
+        let mf = MoveFormat {
+            move_: Some(move_format(pos.inner(), mv).ok_or(EditError::InvalidNode(parent))?),
+            ..Default::default()
+        };
+
+        // The edit is applied to a clone, so that `self` is unchanged if normalizing fails
+        let mut edited = self.clone();
+        let (line, start) = edited
+            .line_mut(&forks)
+            .ok_or(EditError::NodeNotFound(parent))?;
+        if has_next {
+            let variations = line[ply - start].forks.get_or_insert_with(Vec::new);
+            forks.push((ply, variations.len()));
+            variations.push(vec![mf]);
+        } else {
+            line.push(mf);
+        }
+        edited.normalize()?;
+        let id = edited
+            .tree()
+            .find(&forks, ply)
+            .ok_or(EditError::InvalidNode(parent))?;
+        *self = edited;
+        Ok(id)
+    }
+
+    /// Deletes the innermost variation which contains the node
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`EditError`] if the node doesn't exist or is in the main line.
+    pub fn delete_variation(&mut self, id: NodeId) -> Result<(), EditError> {
+        let (forks, _) = self.locate(id)?;
+        let Some((&(ply, index), parent)) = forks.split_last() else {
+            return Err(EditError::InvalidNode(id));
+        };
+        let mut edited = self.clone();
+        let (line, start) = edited.line_mut(parent).ok_or(EditError::NodeNotFound(id))?;
+        let mf = &mut line[ply - start];
+        if let Some(variations) = &mut mf.forks {
+            variations.remove(index);
+            if variations.is_empty() {
+                mf.forks = None;
+            }
+        }
+        edited.normalize()?;
+        *self = edited;
+        Ok(())
+    }
+
+    /// Removes the move of the node and all the following moves of its line
+    ///
+    /// If the node is the first move of a variation, the whole variation is deleted.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`EditError`] if the node doesn't exist or is the root.
+    pub fn truncate(&mut self, id: NodeId) -> Result<(), EditError> {
+        let (forks, ply) = self.locate(id)?;
+        if ply == 0 {
+            return Err(EditError::InvalidNode(id));
+        }
+        if forks.last().is_some_and(|&(p, _)| p == ply) {
+            return self.delete_variation(id);
+        }
+        let mut edited = self.clone();
+        let (line, start) = edited.line_mut(&forks).ok_or(EditError::NodeNotFound(id))?;
+        line.truncate(ply - start);
+        edited.normalize()?;
+        *self = edited;
+        Ok(())
+    }
+
+    /// Promotes the innermost variation which contains the node to its parent line,
+    /// and returns the new id of the node
+    ///
+    /// The moves of the parent line from the branching point become a variation in place of the promoted one.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`EditError`] if the node doesn't exist or is in the main line.
+    pub fn promote_variation(&mut self, id: NodeId) -> Result<NodeId, EditError> {
+        let (forks, node_ply) = self.locate(id)?;
+        let Some((&(ply, index), parent)) = forks.split_last() else {
+            return Err(EditError::InvalidNode(id));
+        };
+        let mut edited = self.clone();
+        let (line, start) = edited.line_mut(parent).ok_or(EditError::NodeNotFound(id))?;
+        let mut tail = line.split_off(ply - start);
+        let mut variations = tail[0].forks.take().unwrap_or_default();
+        let mut promoted = std::mem::take(&mut variations[index]);
+        // The variations of the first move of the promoted one are alternatives at the same ply
+        variations[index] = tail;
+        variations.extend(promoted[0].forks.take().into_iter().flatten());
+        promoted[0].forks = Some(variations);
+        line.append(&mut promoted);
+        edited.normalize()?;
+        let promoted = edited
+            .tree()
+            .find(parent, node_ply)
+            .ok_or(EditError::InvalidNode(id))?;
+        *self = edited;
+        Ok(promoted)
+    }
+
+    /// Promotes the variations which contain the node until it is in the main line,
+    /// and returns the new id of the node
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`EditError`] if the node doesn't exist.
+    pub fn promote_to_main_line(&mut self, mut id: NodeId) -> Result<NodeId, EditError> {
+        let mut edited = self.clone();
+        while !edited.locate(id)?.0.is_empty() {
+            id = edited.promote_variation(id)?;
+        }
+        *self = edited;
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_kif_file;
+    use shogi_core::{IllegalMoveKind, Piece, Square};
+
+    fn forks_jkf() -> JsonKifuFormat {
+        parse_kif_file("data/tests/kif/forks.kif").expect("failed to parse kif")
+    }
+
+    #[test]
+    fn add_move() {
+        let mut jkf = forks_jkf();
+        let tree = jkf.tree();
+        let main_line = tree.main_line();
+        let (parent, next) = (main_line[2], main_line[3]);
+        let next_move = Move::try_from(tree.get(next).and_then(|mf| mf.move_.as_ref()).unwrap())
+            .expect("failed to convert move");
+        let len = main_line.len();
+
+        // The same move as the existing one
+        assert_eq!(Ok(next), jkf.add_move(parent, next_move));
+
+        // A new variation
+        let mv = Move::Normal {
+            from: Square::SQ_2G,
+            to: Square::SQ_2F,
+            promote: false,
+        };
+        let id = jkf.add_move(parent, mv).expect("failed to add move");
+        let tree = jkf.tree();
+        assert_eq!(&[(3, 0)], tree.forks(id));
+        assert_eq!(Some(parent), tree.parent(id));
+        let mmf = tree.get(id).and_then(|mf| mf.move_.as_ref()).unwrap();
+        assert_eq!(Ok(mv), Move::try_from(mmf));
+        assert_eq!(Ok(id), jkf.add_move(parent, mv));
+        assert_eq!(len, jkf.tree().main_line().len());
+
+        // Append to the end of the main line, the ids have been changed by the edit
+        let last = *jkf.tree().main_line().last().unwrap();
+        let pos = jkf.tree().position(last).expect("failed to get position");
+        let mv = LiteLegalityChecker.all_legal_moves_partial(pos.inner())[0];
+        let id = jkf.add_move(last, mv).expect("failed to add move");
+        let tree = jkf.tree();
+        assert_eq!(len + 1, tree.main_line().len());
+        assert!(tree.forks(id).is_empty());
+
+        // Illegal moves
+        let mv = Move::Normal {
+            from: Square::SQ_5I,
+            to: Square::SQ_5G,
+            promote: false,
+        };
+        assert!(matches!(
+            jkf.add_move(parent, mv),
+            Err(EditError::IllegalMove(_, _))
+        ));
+        let mv = Move::Drop {
+            piece: Piece::B_B,
+            to: Square::SQ_5E,
+        };
+        assert_eq!(
+            Err(EditError::IllegalMove(mv, IllegalMoveKind::IncorrectMove)),
+            jkf.add_move(parent, mv)
+        );
+    }
+
+    #[test]
+    fn unchanged_on_error() {
+        // A broken move in the variation fails normalizing the edited kifu
+        let mut jkf = forks_jkf();
+        let (line, start) = jkf.line_mut(&[(10, 0)]).expect("line should exist");
+        if let Some(mmf) = &mut line[11 - start].move_ {
+            mmf.from = Some(PlaceFormat { x: 5, y: 5 });
+        }
+        let original = jkf.clone();
+        let tree = jkf.tree();
+        let last = *tree.main_line().last().unwrap();
+        let pos = tree.position(last).expect("failed to get position");
+        let mv = LiteLegalityChecker.all_legal_moves_partial(pos.inner())[0];
+        let main_line = tree.main_line();
+        assert!(matches!(
+            jkf.add_move(last, mv),
+            Err(EditError::Normalize(_))
+        ));
+        assert_eq!(original, jkf);
+        assert!(matches!(
+            jkf.truncate(main_line[12]),
+            Err(EditError::Normalize(_))
+        ));
+        assert_eq!(original, jkf);
+    }
+
+    #[test]
+    fn delete_and_truncate() {
+        let mut jkf = forks_jkf();
+        let tree = jkf.tree();
+        let main_line = tree.main_line();
+        let fork = tree.children(main_line[9])[1];
+        let inner = tree.children(fork)[0];
+        let variations = jkf.moves[10].forks.as_ref().map_or(0, Vec::len);
+
+        assert_eq!(
+            Err(EditError::InvalidNode(main_line[3])),
+            jkf.delete_variation(main_line[3])
+        );
+        jkf.delete_variation(inner)
+            .expect("failed to delete variation");
+        assert_eq!(
+            variations - 1,
+            jkf.moves[10].forks.as_ref().map_or(0, Vec::len)
+        );
+
+        let mut jkf = forks_jkf();
+        let tree = jkf.tree();
+        let (root, node) = (tree.root(), tree.main_line()[5]);
+        assert_eq!(Err(EditError::InvalidNode(root)), jkf.truncate(root));
+        jkf.truncate(node).expect("failed to truncate");
+        assert_eq!(5, jkf.moves.len());
+        assert_eq!(
+            Err(EditError::NodeNotFound(NodeId(1000))),
+            jkf.truncate(NodeId(1000))
+        );
+
+        // Truncating from the first move deletes the variation
+        let mut jkf = forks_jkf();
+        let tree = jkf.tree();
+        let fork = tree.children(tree.main_line()[9])[1];
+        jkf.truncate(fork).expect("failed to truncate");
+        assert_eq!(
+            variations - 1,
+            jkf.moves[10].forks.as_ref().map_or(0, Vec::len)
+        );
+    }
+
+    #[test]
+    fn promote() {
+        let original = forks_jkf();
+        let moves = |line: Vec<&MoveFormat>| -> Vec<Option<MoveMoveFormat>> {
+            line.into_iter().map(|mf| mf.move_).collect()
+        };
+
+        let mut jkf = original.clone();
+        let tree = jkf.tree();
+        let fork = tree.children(tree.main_line()[9])[1];
+        let id = jkf.promote_variation(fork).expect("failed to promote");
+        let tree = jkf.tree();
+        assert!(tree.forks(id).is_empty());
+        assert_eq!(10, tree.ply(id));
+        assert_eq!(
+            moves(original.line(&[(10, 0)]).unwrap()),
+            moves(jkf.line(&[]).unwrap())
+        );
+        assert_eq!(
+            moves(original.line(&[]).unwrap()),
+            moves(jkf.line(&[(10, 0)]).unwrap())
+        );
+        assert_eq!(Err(EditError::InvalidNode(id)), jkf.promote_variation(id));
+
+        // Promoting the demoted line restores the original
+        let demoted = jkf.tree().find(&[(10, 0)], 10).unwrap();
+        jkf.promote_variation(demoted).expect("failed to promote");
+        assert_eq!(original, jkf);
+
+        // The deepest variation
+        let mut jkf = original.clone();
+        let tree = jkf.tree();
+        let deepest = tree.ids().max_by_key(|&id| tree.forks(id).len()).unwrap();
+        let (forks, ply) = (tree.forks(deepest).to_vec(), tree.ply(deepest));
+        let id = jkf
+            .promote_to_main_line(deepest)
+            .expect("failed to promote");
+        let tree = jkf.tree();
+        assert!(tree.forks(id).is_empty());
+        assert_eq!(ply, tree.ply(id));
+        assert_eq!(
+            moves(original.line(&forks).unwrap())[..=ply],
+            moves(jkf.line(&[]).unwrap())[..=ply]
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use crate::error::EditError;
+use crate::jkf::{IndexMap, JsonKifuFormat, MoveFormat};
+
+// Whether the two moves are the same, regardless of their comments, times and variations.
+// The moves are compared after normalization, so the derived fields such as `relative` are ignored.
+pub(super) fn same_move(a: &MoveFormat, b: &MoveFormat) -> bool {
+    let key = |mf: &MoveFormat| {
+        (
+            mf.move_
+                .map(|mmf| (mmf.color, mmf.from, mmf.to, mmf.piece, mmf.promote)),
+            mf.special,
+        )
+    };
+    key(a) == key(b)
+}
+
+// Appends the comments of `src` which `dst` doesn't have yet.
+fn merge_comments(dst: &mut MoveFormat, src: &MoveFormat) {
+    for comment in src.comments.iter().flatten() {
+        let comments = dst.comments.get_or_insert_with(Vec::new);
+        if !comments.contains(comment) {
+            comments.push(comment.clone());
+        }
+    }
+}
+
+// Adds the note to the beginning of the comments of the move.
+fn annotate(mf: &mut MoveFormat, note: Vec<String>) {
+    let comments = mf.comments.take().unwrap_or_default();
+    mf.comments = Some([note, comments].concat()).filter(|v| !v.is_empty());
+}
+
+// Merges `line`, which branches from the ply of `mf`, into the variations of `mf`.
+fn merge_fork(mf: &mut MoveFormat, mut line: Vec<MoveFormat>, note: &mut Option<Vec<String>>) {
+    let Some(first) = line.first_mut() else {
+        return;
+    };
+    // The variations of the first move are the alternatives of `mf` itself
+    for fork in first.forks.take().into_iter().flatten() {
+        merge_fork(mf, fork, &mut None);
+    }
+    let forks = mf.forks.get_or_insert_with(Vec::new);
+    match forks
+        .iter_mut()
+        .find(|fork| fork.first().is_some_and(|m| same_move(m, &line[0])))
+    {
+        Some(fork) => merge_line(fork, line, note),
+        None => {
+            if let Some(note) = note.take() {
+                annotate(&mut line[0], note);
+            }
+            forks.push(line);
+        }
+    }
+}
+
+// Merges `src` into `dst`, both of which start from the same ply.
+// The note is added to the first move of `src` which is not in `dst`, or to the last move if all of them are.
+fn merge_line(dst: &mut Vec<MoveFormat>, mut src: Vec<MoveFormat>, note: &mut Option<Vec<String>>) {
+    for i in 0..src.len() {
+        if i == dst.len() {
+            if let Some(note) = note.take() {
+                annotate(&mut src[i], note);
+            }
+            dst.extend(src.drain(i..));
+            return;
+        }
+        for fork in src[i].forks.take().into_iter().flatten() {
+            merge_fork(&mut dst[i], fork, &mut None);
+        }
+        if !same_move(&dst[i], &src[i]) {
+            merge_fork(&mut dst[i], src.split_off(i), note);
+            return;
+        }
+        merge_comments(&mut dst[i], &src[i]);
+    }
+    if let Some(last) = src.len().checked_sub(1).and_then(|i| dst.get_mut(i)) {
+        if let Some(note) = note.take() {
+            annotate(last, note);
+        }
+    }
+}
+
+impl JsonKifuFormat {
+    /// Merges the kifus which start from the same position into one
+    ///
+    /// The moves in common are merged, and the variations are created where the kifus diverge.
+    /// The first kifu becomes the main line.
+    /// The variations and the comments of the kifus are kept, and the same comments of a move are merged.
+    ///
+    /// The header of the merged kifu consists of the entries which all the kifus have in common.
+    /// The first move where each kifu diverges from the previous ones (or its last move if it doesn't diverge)
+    /// is annotated with the comments naming the kifu, such as `&1 先手名 vs 後手名`,
+    /// followed by the other header entries of the kifu in the form of `key：value`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`EditError`] if the initial positions are different or cannot be converted,
+    /// or the merged kifu cannot be normalized.
+    pub fn merge<'a, I>(kifus: I) -> Result<JsonKifuFormat, EditError>
+    where
+        I: IntoIterator<Item = &'a JsonKifuFormat>,
+    {
+        let kifus = kifus.into_iter().collect::<Vec<_>>();
+        let Some(&first) = kifus.first() else {
+            return Ok(JsonKifuFormat::default());
+        };
+        let pos = first.initial_position()?;
+        for (i, jkf) in kifus.iter().enumerate().skip(1) {
+            if jkf.initial_position()? != pos {
+                return Err(EditError::InitialMismatch(i));
+            }
+        }
+
+        let header = first
+            .header
+            .iter()
+            .filter(|&(key, value)| kifus.iter().all(|jkf| jkf.header.get(key) == Some(value)))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<IndexMap<_, _>>();
+        let mut ret = JsonKifuFormat {
+            header,
+            initial: first.initial,
+            moves: vec![MoveFormat::default()],
+        };
+        for (i, jkf) in kifus.iter().enumerate() {
+            let game_info = jkf.game_info();
+            let mut name = format!("&{}", i + 1);
+            if let (Some(black), Some(white)) = (game_info.black, game_info.white) {
+                name += &format!(" {black} vs {white}");
+            }
+            let note = std::iter::once(name)
+                .chain(
+                    jkf.header
+                        .iter()
+                        .filter(|(key, _)| !ret.header.contains_key(*key))
+                        .map(|(key, value)| format!("{key}：{value}")),
+                )
+                .collect();
+            merge_line(&mut ret.moves, jkf.moves.clone(), &mut Some(note));
+        }
+        ret.normalize()?;
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_csa_str, parse_kif_file};
+
+    #[test]
+    fn merge() {
+        let jkf0 = parse_csa_str(
+            "N+Alice\nN-Bob\n$EVENT:Test\nPI\n+\n+7776FU\n-3334FU\n+2726FU\n-8384FU\n%TORYO\n",
+        )
+        .expect("failed to parse csa");
+        let jkf1 = parse_csa_str(
+            "N+Carol\nN-Dave\n$EVENT:Test\nPI\n+\n+7776FU\n-3334FU\n+8822UM\n-3122GI\n",
+        )
+        .expect("failed to parse csa");
+        let jkf2 =
+            parse_csa_str("$EVENT:Test\nPI\n+\n+7776FU\n-3334FU\n").expect("failed to parse csa");
+
+        let merged = JsonKifuFormat::merge([&jkf0, &jkf1, &jkf2]).expect("failed to merge");
+        assert_eq!(
+            IndexMap::from([(String::from("棋戦"), String::from("Test"))]),
+            merged.header
+        );
+        assert_eq!(
+            jkf0.moves
+                .iter()
+                .map(|mf| (mf.move_, mf.special))
+                .collect::<Vec<_>>(),
+            merged
+                .moves
+                .iter()
+                .map(|mf| (mf.move_, mf.special))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some(vec![
+                String::from("&1 Alice vs Bob"),
+                String::from("先手：Alice"),
+                String::from("後手：Bob"),
+            ]),
+            merged.moves[1].comments
+        );
+        // Diverges at the 3rd move
+        let forks = merged.moves[3].forks.as_ref().expect("no forks");
+        assert_eq!(1, forks.len());
+        assert_eq!(2, forks[0].len());
+        assert_eq!(jkf1.moves[3].move_, forks[0][0].move_);
+        assert_eq!(
+            Some(vec![
+                String::from("&2 Carol vs Dave"),
+                String::from("先手：Carol"),
+                String::from("後手：Dave"),
+            ]),
+            forks[0][0].comments
+        );
+        // A prefix of the others
+        assert_eq!(Some(vec![String::from("&3")]), merged.moves[2].comments);
+
+        // Merging a kifu with its variations keeps them
+        let jkf = parse_kif_file("data/tests/kif/forks.kif").expect("failed to parse kif");
+        let merged = JsonKifuFormat::merge([&jkf]).expect("failed to merge");
+        assert_eq!(jkf.tree().len(), merged.tree().len());
+        let merged = JsonKifuFormat::merge([&jkf, &jkf]).expect("failed to merge");
+        assert_eq!(jkf.tree().len(), merged.tree().len());
+
+        let mut handicap = jkf0.clone();
+        handicap.initial = Some(crate::jkf::Initial {
+            preset: crate::jkf::Preset::PresetKY,
+            data: None,
+        });
+        assert_eq!(
+            Err(EditError::InitialMismatch(1)),
+            JsonKifuFormat::merge([&jkf0, &handicap])
+        );
+    }
+}
@@ -0,0 +1,231 @@
+use crate::error::{ConvertError, LocatedNormalizeError, NormalizeError};
+use crate::jkf::{Color, JsonKifuFormat, MoveFormat};
+use crate::shogi_core::is_in_check;
+use shogi_core::{Move, PartialPosition};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// A repetition of the same position for the fourth time (千日手), returned by [`JsonKifuFormat::repetitions()`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Repetition {
+    /// The ply number of the move which makes the position appear for the fourth time
+    pub ply: usize,
+    /// The path to the variation which contains the move, empty if it is in the main line.
+    /// See [`LocatedNormalizeError::forks`]
+    pub forks: Vec<(usize, usize)>,
+    /// The side which has checked with all its moves during the repetition (連続王手の千日手), and loses the game.
+    /// `None` if it is a draw.
+    pub perpetual_check: Option<Color>,
+}
+
+// The hash of the board, the hands and the side to move, regardless of the ply number
+fn position_hash(pos: &PartialPosition) -> u64 {
+    let sfen = pos.to_sfen_owned();
+    let mut hasher = DefaultHasher::new();
+    sfen.rsplit_once(' ')
+        .map_or(sfen.as_str(), |(s, _)| s)
+        .hash(&mut hasher);
+    hasher.finish()
+}
+
+// The positions from the initial position, with whether the side to move is in check
+#[derive(Clone)]
+struct History(Vec<(u64, bool)>);
+
+impl History {
+    fn new(initial: &PartialPosition) -> Self {
+        History(vec![(position_hash(initial), is_in_check(initial))])
+    }
+
+    // Adds the position after the move of `color` at the ply,
+    // and returns the repetition if the position appears for the fourth time.
+    fn push(
+        &mut self,
+        ply: usize,
+        color: Color,
+        pos: &PartialPosition,
+        forks: &[(usize, usize)],
+    ) -> Option<Repetition> {
+        let history = &mut self.0;
+        let hash = position_hash(pos);
+        history.push((hash, is_in_check(pos)));
+        let occurrences = history
+            .iter()
+            .enumerate()
+            .filter(|(_, (h, _))| *h == hash)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        if occurrences.len() < 4 {
+            return None;
+        }
+        // The moves from the first occurrence, where `history[i]` is the position after the move at the ply `i`
+        let checks = |parity| {
+            (occurrences[0] + 1..history.len())
+                .filter(|i| i % 2 == parity)
+                .all(|i| history[i].1)
+        };
+        let perpetual_check = if checks(ply % 2) {
+            Some(color)
+        } else if checks((ply + 1) % 2) {
+            Some(match color {
+                Color::Black => Color::White,
+                Color::White => Color::Black,
+            })
+        } else {
+            None
+        };
+        Some(Repetition {
+            ply,
+            forks: forks.to_vec(),
+            perpetual_check,
+        })
+    }
+}
+
+// Finds the repetition in the line and its variations, and stops the line at the repetition.
+fn find_repetitions(
+    moves: &[MoveFormat],
+    start: usize,
+    mut pos: PartialPosition,
+    mut history: History,
+    forks: &[(usize, usize)],
+    repetitions: &mut Vec<Repetition>,
+) -> Result<(), ConvertError> {
+    let mut fork_lines = Vec::new();
+    for (ply, mf) in (start..).zip(moves) {
+        for (i, fork) in mf.forks.iter().flatten().enumerate() {
+            fork_lines.push((ply, i, fork, pos.clone(), history.clone()));
+        }
+        let Some(mmf) = &mf.move_ else {
+            break;
+        };
+        let mv = Move::try_from(mmf)?;
+        pos.make_move(mv).ok_or_else(|| {
+            ConvertError::Normalize(LocatedNormalizeError {
+                error: NormalizeError::MakeMoveFailed(mv),
+                ply,
+                forks: forks.to_vec(),
+            })
+        })?;
+        if let Some(repetition) = history.push(ply, mmf.color, &pos, forks) {
+            repetitions.push(repetition);
+            break;
+        }
+    }
+    for (ply, i, fork, pos, history) in fork_lines {
+        let path = [forks, &[(ply, i)]].concat();
+        find_repetitions(fork, ply, pos, history, &path, repetitions)?;
+    }
+    Ok(())
+}
+
+impl JsonKifuFormat {
+    /// Finds the repetitions of the same position for the fourth time (千日手) in the main line and the variations
+    ///
+    /// The positions are compared by their boards, hands and sides to move.
+    /// Only the first repetition of each line is reported, since the game ends there.
+    ///
+    /// # Errors
+    ///
+    /// This function returns [`ConvertError`] if the initial position or a move cannot be converted,
+    /// or a move cannot be made.
+    pub fn repetitions(&self) -> Result<Vec<Repetition>, ConvertError> {
+        let pos = self.initial_position()?;
+        let history = History::new(&pos);
+        let mut repetitions = Vec::new();
+        if let Some(moves) = self.moves.get(1..) {
+            find_repetitions(moves, 1, pos, history, &[], &mut repetitions)?;
+        }
+        Ok(repetitions)
+    }
+
+    // Finds the repetition in the main line, replaying only the moves of the main line
+    pub(crate) fn main_line_repetition(&self) -> Result<Option<Repetition>, ConvertError> {
+        let mut positions = self.positions();
+        let mut history = match positions.next().transpose()? {
+            Some((_, _, pos)) => History::new(&pos),
+            None => return Ok(None),
+        };
+        for item in positions {
+            let (ply, mf, pos) = item?;
+            let Some(mmf) = &mf.move_ else {
+                break;
+            };
+            if let Some(repetition) = history.push(ply, mmf.color, &pos, &[]) {
+                return Ok(Some(repetition));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_usi_str;
+
+    #[test]
+    fn sennichite() {
+        let mut jkf = parse_usi_str(&format!(
+            "position startpos moves {}",
+            "5i5h 5a5b 5h5i 5b5a ".repeat(4)
+        ))
+        .expect("failed to parse usi");
+        assert_eq!(
+            Ok(vec![Repetition {
+                ply: 12,
+                forks: Vec::new(),
+                perpetual_check: None,
+            }]),
+            jkf.repetitions()
+        );
+
+        // In a variation
+        let root = jkf.tree().root();
+        let id = jkf
+            .add_move(
+                root,
+                Move::Normal {
+                    from: shogi_core::Square::SQ_7G,
+                    to: shogi_core::Square::SQ_7F,
+                    promote: false,
+                },
+            )
+            .expect("failed to add move");
+        jkf.promote_variation(id).expect("failed to promote");
+        assert_eq!(
+            Ok(vec![Repetition {
+                ply: 12,
+                forks: vec![(1, 0)],
+                perpetual_check: None,
+            }]),
+            jkf.repetitions()
+        );
+
+        // Not yet for the fourth time
+        let jkf = parse_usi_str(&format!(
+            "position startpos moves {}",
+            "5i5h 5a5b 5h5i 5b5a ".repeat(2)
+        ))
+        .expect("failed to parse usi");
+        assert_eq!(Ok(Vec::new()), jkf.repetitions());
+    }
+
+    #[test]
+    fn perpetual_check() {
+        // The rook of Black checks the king of White repeatedly
+        let jkf = parse_usi_str(&format!(
+            "position sfen 8k/9/9/9/9/9/9/9/K7R w - 1 moves {}",
+            "1a2a 1i2i 2a1a 2i1i ".repeat(3)
+        ))
+        .expect("failed to parse usi");
+        assert_eq!(
+            Ok(vec![Repetition {
+                ply: 12,
+                forks: Vec::new(),
+                perpetual_check: Some(Color::Black),
+            }]),
+            jkf.repetitions()
+        );
+    }
+}
@@ -0,0 +1,332 @@
+use crate::error::{LocatedValidationError, NormalizeError, ValidationError};
+use crate::jkf::*;
+use crate::normalizer::normalize_move;
+use crate::tree::{Declaration, DeclarationRule};
+use shogi_core::{LegalityChecker, PartialPosition, Position};
+use shogi_legality_lite::LiteLegalityChecker;
+
+// The kind of the piece before promotion
+fn base_kind(kind: Kind) -> Kind {
+    match kind {
+        Kind::TO => Kind::FU,
+        Kind::NY => Kind::KY,
+        Kind::NK => Kind::KE,
+        Kind::NG => Kind::GI,
+        Kind::UM => Kind::KA,
+        Kind::RY => Kind::HI,
+        kind => kind,
+    }
+}
+
+/// Options for [`JsonKifuFormat::validate_with_options()`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ValidateOptions {
+    /// The rule to check the declarations by `KACHI`
+    pub declaration_rule: DeclarationRule,
+}
+
+// Checks the numbers of the pieces on the board and in the hands.
+fn validate_piece_counts(data: &StateFormat) -> Vec<ValidationError> {
+    const MAX_COUNTS: [(Kind, usize); 7] = [
+        (Kind::FU, 18),
+        (Kind::KY, 4),
+        (Kind::KE, 4),
+        (Kind::GI, 4),
+        (Kind::KI, 4),
+        (Kind::KA, 2),
+        (Kind::HI, 2),
+    ];
+    let in_hand = |hand: &Hand, kind| match kind {
+        Kind::FU => hand.FU,
+        Kind::KY => hand.KY,
+        Kind::KE => hand.KE,
+        Kind::GI => hand.GI,
+        Kind::KI => hand.KI,
+        Kind::KA => hand.KA,
+        Kind::HI => hand.HI,
+        _ => 0,
+    };
+    let pieces = data.board.iter().flatten();
+    let mut errors = MAX_COUNTS
+        .into_iter()
+        .filter_map(|(kind, max)| {
+            let count = pieces
+                .clone()
+                .filter(|p| p.kind.map(base_kind) == Some(kind))
+                .count()
+                + data
+                    .hands
+                    .iter()
+                    .map(|hand| in_hand(hand, kind) as usize)
+                    .sum::<usize>();
+            (count > max).then_some(ValidationError::InvalidPieceCount(kind, count))
+        })
+        .collect::<Vec<_>>();
+    for color in [Color::Black, Color::White] {
+        let count = pieces
+            .clone()
+            .filter(|p| p.color == Some(color) && p.kind == Some(Kind::OU))
+            .count();
+        if count > 1 {
+            errors.push(ValidationError::InvalidPieceCount(Kind::OU, count));
+        }
+    }
+    errors
+}
+
+// Checks the moves from the position, including the variations.
+// Unlike the normalizer, the moves after a special move are also checked.
+fn validate_moves(
+    moves: &[MoveFormat],
+    start: usize,
+    mut pos: PartialPosition,
+    forks: &[(usize, usize)],
+    options: &ValidateOptions,
+    errors: &mut Vec<LocatedValidationError>,
+) {
+    let mut push = |error, ply| {
+        errors.push(LocatedValidationError {
+            error,
+            ply,
+            forks: forks.to_vec(),
+        })
+    };
+    let mut end = None;
+    let mut fork_lines = Vec::new();
+    for (ply, mf) in (start..).zip(moves) {
+        for (i, fork) in mf.forks.iter().flatten().enumerate() {
+            fork_lines.push((ply, i, fork, pos.clone()));
+        }
+        if let Some(special) = end {
+            push(ValidationError::MoveAfterSpecial(special), ply);
+        }
+        if let Some(special) = mf.special {
+            if special == MoveSpecial::SpecialKachi && end.is_none() {
+                let declaration = Declaration::evaluate(
+                    &Position::arbitrary_position(pos.clone()),
+                    options.declaration_rule,
+                );
+                if declaration.outcome() != Outcome::Win(declaration.color) {
+                    push(ValidationError::InvalidDeclaration(declaration), ply);
+                }
+            }
+            end = end.or(Some(special));
+        }
+        let Some(mmf) = mf.move_ else {
+            continue;
+        };
+        if mmf.color != pos.side_to_move().into() {
+            push(ValidationError::WrongSideToMove(mmf.color), ply);
+            break;
+        }
+        let mut mmf = mmf;
+        let mv = match normalize_move(&mut mmf, &pos).and_then(|()| {
+            shogi_core::Move::try_from(&mmf).map_err(|err| NormalizeError::Convert(err.to_string()))
+        }) {
+            Ok(mv) => mv,
+            Err(err) => {
+                push(ValidationError::InvalidMove(err), ply);
+                break;
+            }
+        };
+        if let Err(kind) = LiteLegalityChecker.is_legal_partial(&pos, mv) {
+            push(ValidationError::IllegalMove(mv, kind), ply);
+        }
+        if pos.make_move(mv).is_none() {
+            break;
+        }
+    }
+    for (ply, i, fork, pos) in fork_lines {
+        let path = [forks, &[(ply, i)]].concat();
+        validate_moves(fork, ply, pos, &path, options, errors);
+    }
+}
+
+impl JsonKifuFormat {
+    /// Checks the initial position and all the moves including the variations, and returns the problems found
+    ///
+    /// Unlike [`JsonKifuFormat::normalize()`], this function doesn't stop at the first problem and doesn't change `self`.
+    /// The following problems are reported:
+    ///
+    /// - impossible numbers of the pieces in the initial position
+    /// - moves of the wrong side
+    /// - moves which cannot be made, or are illegal (e.g. 二歩, 打ち歩詰め, leaving the king in check, or dead pieces)
+    /// - moves after a special move which ends the game
+    /// - `KACHI` in the position which doesn't satisfy [`ValidateOptions::declaration_rule`], 27点法 by default
+    ///
+    /// The rest of a line is not checked after a move which cannot be made.
+    pub fn validate(&self) -> Vec<LocatedValidationError> {
+        self.validate_with_options(&ValidateOptions::default())
+    }
+
+    /// Checks `self` as [`JsonKifuFormat::validate()`] with the given options
+    pub fn validate_with_options(&self, options: &ValidateOptions) -> Vec<LocatedValidationError> {
+        let mut errors = Vec::new();
+        let at_initial = |error| LocatedValidationError {
+            error,
+            ply: 0,
+            forks: Vec::new(),
+        };
+        if let Some(data) = self
+            .initial
+            .as_ref()
+            .and_then(|initial| initial.data.as_ref())
+        {
+            errors.extend(validate_piece_counts(data).into_iter().map(at_initial));
+        }
+        let pos = match self.initial_position() {
+            Ok(pos) => pos,
+            Err(err) => {
+                errors.push(at_initial(ValidationError::InvalidInitial(err)));
+                return errors;
+            }
+        };
+        if let Some(moves) = self.moves.get(1..) {
+            validate_moves(moves, 1, pos, &[], options, &mut errors);
+        }
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_kif_file, parse_usi_str};
+    use shogi_core::{IllegalMoveKind, Move, Square};
+
+    #[test]
+    fn validate_valid() {
+        let jkf = parse_kif_file("data/tests/kif/forks.kif").expect("failed to parse kif");
+        assert_eq!(Vec::<LocatedValidationError>::new(), jkf.validate());
+    }
+
+    #[test]
+    fn validate_moves() {
+        // A pawn of White is in the hand of Black
+        let mut jkf = parse_usi_str(
+            "position sfen lnsgkgsnl/1r5b1/pppp1pppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b P 1 moves 7g7f 3c3d",
+        )
+        .expect("failed to parse usi");
+        let nifu = MoveFormat {
+            move_: Some(MoveMoveFormat {
+                color: Color::Black,
+                from: None,
+                to: PlaceFormat { x: 5, y: 5 },
+                piece: Kind::FU,
+                same: None,
+                promote: None,
+                capture: None,
+                relative: None,
+            }),
+            ..Default::default()
+        };
+        jkf.moves[1].forks = Some(vec![vec![nifu]]);
+        jkf.moves.push(MoveFormat {
+            special: Some(MoveSpecial::SpecialToryo),
+            ..Default::default()
+        });
+        jkf.moves.push(jkf.moves[2].clone());
+        assert_eq!(
+            vec![
+                LocatedValidationError {
+                    error: ValidationError::MoveAfterSpecial(MoveSpecial::SpecialToryo),
+                    ply: 4,
+                    forks: Vec::new(),
+                },
+                LocatedValidationError {
+                    error: ValidationError::WrongSideToMove(Color::White),
+                    ply: 4,
+                    forks: Vec::new(),
+                },
+                LocatedValidationError {
+                    error: ValidationError::IllegalMove(
+                        Move::Drop {
+                            piece: shogi_core::Piece::B_P,
+                            to: Square::SQ_5E,
+                        },
+                        IllegalMoveKind::TwoPawns
+                    ),
+                    ply: 1,
+                    forks: vec![(1, 0)],
+                },
+            ],
+            jkf.validate()
+        );
+    }
+
+    #[test]
+    fn validate_initial() {
+        let mut jkf = JsonKifuFormat {
+            initial: Some(Initial {
+                preset: Preset::PresetOther,
+                data: Some(StateFormat {
+                    color: Color::Black,
+                    board: crate::normalizer::HIRATE_BOARD,
+                    hands: Default::default(),
+                }),
+            }),
+            ..Default::default()
+        };
+        assert!(jkf.validate().is_empty());
+        if let Some(data) = &mut jkf.initial.as_mut().unwrap().data {
+            data.hands[0].FU = 1;
+            data.hands[1].KA = 2;
+            data.board[0][0] = Piece {
+                color: Some(Color::Black),
+                kind: Some(Kind::OU),
+            };
+        }
+        assert_eq!(
+            vec![
+                LocatedValidationError {
+                    error: ValidationError::InvalidPieceCount(Kind::FU, 19),
+                    ply: 0,
+                    forks: Vec::new(),
+                },
+                LocatedValidationError {
+                    error: ValidationError::InvalidPieceCount(Kind::KA, 4),
+                    ply: 0,
+                    forks: Vec::new(),
+                },
+                LocatedValidationError {
+                    error: ValidationError::InvalidPieceCount(Kind::OU, 2),
+                    ply: 0,
+                    forks: Vec::new(),
+                },
+            ],
+            jkf.validate()
+        );
+    }
+
+    #[test]
+    fn validate_declaration() {
+        let mut jkf = parse_usi_str(
+            "position sfen RBRB1GGSS/1K1+P1+P3/9/9/9/9/9/9/4k4 b 3P 1 moves 1a2b 5i5h",
+        )
+        .expect("failed to parse usi");
+        jkf.moves.push(MoveFormat {
+            special: Some(MoveSpecial::SpecialKachi),
+            ..Default::default()
+        });
+        assert_eq!(Vec::<LocatedValidationError>::new(), jkf.validate());
+        // 29 points is a draw by the 24-point rule
+        let options = ValidateOptions {
+            declaration_rule: DeclarationRule::Points24,
+        };
+        assert_eq!(
+            vec![LocatedValidationError {
+                error: ValidationError::InvalidDeclaration(Declaration {
+                    rule: DeclarationRule::Points24,
+                    color: Color::Black,
+                    king_in_zone: true,
+                    pieces_in_zone: 10,
+                    points: 29,
+                    in_check: false,
+                }),
+                ply: 3,
+                forks: Vec::new(),
+            }],
+            jkf.validate_with_options(&options)
+        );
+    }
+}